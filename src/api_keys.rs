@@ -0,0 +1,185 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{error::RequestError, image_processor::ProcessingParams, policy::TransformPolicy};
+
+/// 多租户 API key（api_keys）：请求通过 `X-API-Key` 头携带，每个 key 对应一个租户及其限制
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ApiKeysConfig {
+    // 为 true 时图片请求必须携带有效的 API key，否则不带 key 的请求照常处理
+    pub required: bool,
+    // API key → 租户配置
+    pub keys: HashMap<String, ApiKeyConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    // 租户名，用量统计（usage）按它归属
+    pub tenant: String,
+    // 每秒请求数上限（令牌桶），未配置时不限速
+    #[serde(default)]
+    pub requests_per_sec: Option<f64>,
+    // 令牌桶容量，允许的突发请求数；未配置时等于 requests_per_sec（至少 1）
+    #[serde(default)]
+    pub burst: Option<f64>,
+    // 允许访问的 bucket，为空时不限制
+    #[serde(default)]
+    pub buckets: Vec<String>,
+    // 变换限制，字段与签名策略令牌相同（prefix、max_width、max_height、max_quality、formats）
+    #[serde(default)]
+    pub limits: TransformPolicy,
+}
+
+impl ApiKeyConfig {
+    fn capacity(&self) -> f64 {
+        self.burst.or(self.requests_per_sec).unwrap_or(1.0).max(1.0)
+    }
+}
+
+// 每个 key 的令牌桶：tokens 为当前可用的请求数，按 requests_per_sec 随时间补充
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    config: Arc<ApiKeysConfig>,
+    // key 只来自配置，表的大小有上限
+    buckets: Arc<Mutex<HashMap<String, TokenBucket>>>,
+}
+
+impl ApiKeys {
+    pub fn new(config: ApiKeysConfig) -> Self {
+        if !config.keys.is_empty() {
+            println!("API keys enabled for {} keys (required: {})", config.keys.len(), config.required);
+        }
+        Self { config: Arc::new(config), buckets: Arc::default() }
+    }
+
+    // 校验 key、bucket 和限速，返回 key 对应的租户配置；未配置 key 或请求未携带 key（且非必需）时返回 None
+    pub fn admit(&self, api_key: Option<&str>, image_key: &str) -> Result<Option<ApiKeyConfig>> {
+        let api_key = api_key.map(str::trim).filter(|key| !key.is_empty());
+        let Some(api_key) = api_key.filter(|_| !self.config.keys.is_empty()) else {
+            if self.config.required {
+                return Err(RequestError::unauthorized("An API key is required (X-API-Key header)").into());
+            }
+            return Ok(None);
+        };
+        let Some(tenant) = self.config.keys.get(api_key) else {
+            return Err(RequestError::unauthorized("Invalid API key").into());
+        };
+        let bucket = image_key.split('/').next().unwrap_or_default();
+        if !tenant.buckets.is_empty() && !tenant.buckets.iter().any(|b| b == bucket) {
+            return Err(RequestError::forbidden(format!("Bucket '{}' is not allowed for this API key", bucket)).into());
+        }
+        if let Some(rate) = tenant.requests_per_sec {
+            let mut buckets = self.buckets.lock().unwrap();
+            let now = Instant::now();
+            let limiter = buckets.entry(api_key.to_string()).or_insert_with(|| TokenBucket {
+                tokens: tenant.capacity(),
+                updated: now,
+            });
+            let elapsed = now.duration_since(limiter.updated).as_secs_f64();
+            limiter.tokens = (limiter.tokens + elapsed * rate).min(tenant.capacity());
+            limiter.updated = now;
+            if limiter.tokens < 1.0 {
+                eprintln!("Rate limit exceeded for tenant '{}'", tenant.tenant);
+                return Err(RequestError::too_many_requests(format!("Rate limit of {} requests/s exceeded", rate)).into());
+            }
+            limiter.tokens -= 1.0;
+        }
+        Ok(Some(tenant.clone()))
+    }
+
+    // 用量统计用：key 有效时返回其租户名，不做校验和限速
+    pub fn tenant_of(&self, api_key: Option<&str>) -> Option<String> {
+        let api_key = api_key.map(str::trim)?;
+        self.config.keys.get(api_key).map(|tenant| tenant.tenant.clone())
+    }
+
+    // admit 之后再检查处理参数是否在租户的变换限制之内
+    pub fn authorize(&self, api_key: Option<&str>, image_key: &str, params: &ProcessingParams) -> Result<()> {
+        match self.admit(api_key, image_key)? {
+            Some(tenant) => tenant.limits.check(image_key, params),
+            None => Ok(()),
+        }
+    }
+
+    // 只读取原图信息的路由（没有处理参数）：admit 之后只检查租户限制中的 prefix
+    pub fn authorize_key(&self, api_key: Option<&str>, image_key: &str) -> Result<()> {
+        match self.admit(api_key, image_key)? {
+            Some(tenant) => tenant.limits.check_key(image_key),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(required: bool) -> ApiKeys {
+        let tenant = ApiKeyConfig {
+            tenant: "acme".to_string(),
+            requests_per_sec: Some(1.0),
+            burst: Some(2.0),
+            buckets: vec!["acme-images".to_string()],
+            limits: TransformPolicy { prefix: Some("acme-images/public/".to_string()), max_width: Some(500), ..Default::default() },
+        };
+        ApiKeys::new(ApiKeysConfig { required, keys: HashMap::from([("key-1".to_string(), tenant)]) })
+    }
+
+    fn status(result: Result<impl std::fmt::Debug>) -> u16 {
+        result.unwrap_err().downcast_ref::<RequestError>().unwrap().status
+    }
+
+    #[test]
+    fn valid_key_is_admitted_as_its_tenant() {
+        let tenant = keys(false).admit(Some("key-1"), "acme-images/public/a.jpg").unwrap();
+        assert_eq!(tenant.unwrap().tenant, "acme");
+    }
+
+    #[test]
+    fn missing_key_is_only_rejected_when_required() {
+        assert!(keys(false).admit(None, "acme-images/public/a.jpg").unwrap().is_none());
+        assert_eq!(status(keys(true).admit(None, "acme-images/public/a.jpg")), 401);
+        assert_eq!(status(keys(true).authorize_key(Some(" "), "acme-images/public/a.jpg")), 401);
+    }
+
+    #[test]
+    fn unknown_key_is_unauthorized() {
+        assert_eq!(status(keys(false).admit(Some("key-2"), "acme-images/public/a.jpg")), 401);
+    }
+
+    #[test]
+    fn other_buckets_are_forbidden() {
+        assert_eq!(status(keys(false).admit(Some("key-1"), "other/a.jpg")), 403);
+        assert_eq!(status(keys(false).authorize_key(Some("key-1"), "other/a.jpg")), 403);
+    }
+
+    #[test]
+    fn requests_over_the_rate_are_throttled() {
+        let keys = keys(false);
+        // burst 为 2：前两个请求放行，第三个超出令牌桶
+        assert!(keys.admit(Some("key-1"), "acme-images/public/a.jpg").is_ok());
+        assert!(keys.admit(Some("key-1"), "acme-images/public/a.jpg").is_ok());
+        assert_eq!(status(keys.admit(Some("key-1"), "acme-images/public/a.jpg")), 429);
+    }
+
+    #[test]
+    fn tenant_limits_apply_to_transforms_and_keys() {
+        let keys = keys(false);
+        let wide = ProcessingParams { width: Some(800), ..Default::default() };
+        assert_eq!(status(keys.authorize(Some("key-1"), "acme-images/public/a.jpg", &wide)), 403);
+        assert_eq!(status(keys.authorize_key(Some("key-1"), "acme-images/private/a.jpg")), 403);
+        assert!(keys.authorize_key(Some("key-1"), "acme-images/public/a.jpg").is_ok());
+    }
+}
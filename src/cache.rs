@@ -1,82 +1,447 @@
-use moka::future::Cache;
-use serde::Deserialize;
-use std::sync::Arc;
-use std::time::Duration;
+use moka::{
+    future::{Cache, ConcurrentCacheExt},
+    Expiry,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::image_processor::ProcessedImage;
+use crate::logging::redact_key;
+use crate::redis_cache::{RedisCache, RedisConfig};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CacheConfig {
     pub max_capacity_mb: u64,
     pub time_to_live_sec: u64,
     pub time_to_idle_sec: u64,
+    // 分片数量，>1 时按缓存键哈希分散到多个 moka 缓存以减少高并发下的争用
+    #[serde(default = "default_shards")]
+    pub shards: usize,
+    // 为 true 时在后台任务中写入缓存，响应不等待写入完成；写入完成前的同键请求从待写入表中读取
+    #[serde(default)]
+    pub lazy_insert: bool,
+    // 对未压缩的格式（BMP/TIFF）用 zstd 压缩后再存入缓存，只在确实变小时生效
+    #[serde(default)]
+    pub compress: bool,
+    // 可选的 Redis 共享缓存层，查找顺序为 内存 → Redis → 回源处理
+    #[serde(default)]
+    pub redis: Option<RedisConfig>,
+    // 条目写入后的绝对存活上限，超过后读取按未命中处理并重新生成；不受 time_to_idle 续命影响
+    #[serde(default)]
+    pub max_entry_age_sec: Option<u64>,
+}
+
+// 本身未压缩、值得再压缩的内容类型；JPEG/PNG/WebP 等再压缩几乎没有收益
+const COMPRESSIBLE_TYPES: &[&str] = &["image/bmp", "image/tiff"];
+const ZSTD_LEVEL: i32 = 3;
+
+fn default_shards() -> usize {
+    1
+}
+
+// 按条目大小划分的区间，用于观察缓存构成
+const SIZE_BUCKETS: &[(&str, usize)] = &[("lt_50kb", 50 * 1024), ("lt_200kb", 200 * 1024)];
+const SIZE_BUCKET_LARGEST: &str = "gte_200kb";
+
+fn size_bucket(bytes: usize) -> &'static str {
+    SIZE_BUCKETS
+        .iter()
+        .find(|(_, limit)| bytes < *limit)
+        .map(|(name, _)| *name)
+        .unwrap_or(SIZE_BUCKET_LARGEST)
+}
+
+// 输出格式取内容类型的子类型，如 image/webp → webp
+fn format_label(value: &ProcessedImage) -> String {
+    value.content_type.rsplit('/').next().unwrap_or_default().to_string()
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BreakdownStats {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+// 按格式、按大小区间统计的条目数和字节数；插入时增加，淘汰/替换/失效时通过 eviction listener 减少
+#[derive(Debug, Default)]
+struct Breakdown {
+    by_format: BTreeMap<String, BreakdownStats>,
+    by_size: BTreeMap<&'static str, BreakdownStats>,
+}
+
+impl Breakdown {
+    fn add(&mut self, value: &ProcessedImage) {
+        let bytes = value.data.len() as u64;
+        for stats in [
+            self.by_format.entry(format_label(value)).or_default(),
+            self.by_size.entry(size_bucket(value.data.len())).or_default(),
+        ] {
+            stats.entries += 1;
+            stats.bytes += bytes;
+        }
+    }
+
+    fn subtract(&mut self, value: &ProcessedImage) {
+        let bytes = value.data.len() as u64;
+        for stats in [
+            self.by_format.entry(format_label(value)).or_default(),
+            self.by_size.entry(size_bucket(value.data.len())).or_default(),
+        ] {
+            stats.entries = stats.entries.saturating_sub(1);
+            stats.bytes = stats.bytes.saturating_sub(bytes);
+        }
+    }
+}
+
+// 每个条目的存活时间：优先使用条目自带的 ttl（来自原图元数据），否则使用全局 TTL；
+// 原图有过期时间时再截断到该时间，保证派生图不会比原图活得更久
+struct EntryExpiry {
+    default_ttl: Duration,
+}
+
+impl EntryExpiry {
+    fn ttl_for(&self, value: &ProcessedImage) -> Duration {
+        let ttl = value.ttl.unwrap_or(self.default_ttl);
+        match value.expires_at {
+            Some(expires_at) => ttl.min(expires_at.duration_since(SystemTime::now()).unwrap_or_default()),
+            None => ttl,
+        }
+    }
+}
+
+impl Expiry<String, ProcessedImage> for EntryExpiry {
+    fn expire_after_create(&self, _key: &String, value: &ProcessedImage, _current_time: Instant) -> Option<Duration> {
+        Some(self.ttl_for(value))
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &ProcessedImage,
+        _current_time: Instant,
+        _current_duration: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.ttl_for(value))
+    }
 }
 
 #[derive(Clone)]
 pub struct ImageCache {
-    cache: Arc<Cache<String, Vec<u8>>>,
+    // 每个分片是独立的 moka 缓存，按缓存键哈希选择；未配置分片时只有一个
+    shards: Arc<Vec<Cache<String, ProcessedImage>>>,
     config: CacheConfig,
+    breakdown: Arc<Mutex<Breakdown>>,
+    // lazy_insert 模式下已返回给客户端、尚未写入缓存的条目，连同该次写入的代号；
+    // 后台写入任务只在代号仍然一致时落盘，remove/clear 删除待写条目即作废对应的写入
+    pending: Arc<Mutex<HashMap<String, (u64, ProcessedImage)>>>,
+    generation: Arc<AtomicU64>,
+    redis: Option<RedisCache>,
 }
 
 impl ImageCache {
     pub fn new(config: CacheConfig) -> Self {
         let max_capacity = config.max_capacity_mb * 1024 * 1024; // 转换为字节
-        
-        let cache = Cache::builder()
-            .max_capacity(max_capacity)
-            .weigher(|_key, value: &Vec<u8>| -> u32 {
-                // 使用字节数作为权重，限制为u32::MAX
-                value.len().min(u32::MAX as usize) as u32
+        let breakdown = Arc::new(Mutex::new(Breakdown::default()));
+        let shard_count = config.shards.max(1);
+        // 总容量在各分片间平分
+        let shard_capacity = max_capacity / shard_count as u64;
+
+        let shards = (0..shard_count)
+            .map(|_| {
+                Cache::builder()
+                    .max_capacity(shard_capacity)
+                    .weigher(|_key, value: &ProcessedImage| -> u32 {
+                        // 使用字节数作为权重，限制为u32::MAX
+                        value.data.len().min(u32::MAX as usize) as u32
+                    })
+                    .expire_after(EntryExpiry {
+                        default_ttl: Duration::from_secs(config.time_to_live_sec),
+                    })
+                    .time_to_idle(Duration::from_secs(config.time_to_idle_sec))
+                    // 任何原因移除的条目（包括被同 key 替换的旧值、未被准入的条目）都从分类统计中扣除
+                    .eviction_listener_with_queued_delivery_mode({
+                        let breakdown = breakdown.clone();
+                        move |_key, value: ProcessedImage, _cause| {
+                            breakdown.lock().unwrap().subtract(&value);
+                        }
+                    })
+                    .build()
             })
-            .time_to_live(Duration::from_secs(config.time_to_live_sec))
-            .time_to_idle(Duration::from_secs(config.time_to_idle_sec))
-            .build();
-            
+            .collect::<Vec<_>>();
+
+        if shard_count > 1 {
+            println!("Cache split into {} shards of {} bytes each", shard_count, shard_capacity);
+        }
+
+        // Redis 配置有误时只使用内存缓存，不影响启动
+        let redis = config.redis.as_ref().and_then(|redis| {
+            RedisCache::new(redis)
+                .map_err(|e| eprintln!("Warning: failed to set up the Redis cache, using the in-memory cache only: {}", e))
+                .ok()
+        });
+
         Self {
-            cache: Arc::new(cache),
+            shards: Arc::new(shards),
             config,
+            breakdown,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            generation: Arc::new(AtomicU64::new(0)),
+            redis,
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
-        self.cache.get(key)
+    // 同一个键总是落到同一个分片
+    fn shard(&self, key: &str) -> &Cache<String, ProcessedImage> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let index = (hasher.finish() % self.shards.len() as u64) as usize;
+        &self.shards[index]
     }
 
-    pub async fn insert(&self, key: String, value: Vec<u8>) {
-        self.cache.insert(key, value).await;
+    pub async fn get(&self, key: &str) -> Option<ProcessedImage> {
+        if let Some(value) = self.shard(key).get(key) {
+            if self.is_too_old(&value) {
+                println!("Cache entry {} exceeded max_entry_age_sec, regenerating", redact_key(key));
+                self.shard(key).invalidate(key).await;
+                return None;
+            }
+            return decompress(value);
+        }
+        if self.config.lazy_insert {
+            if let Some((_, value)) = self.pending.lock().unwrap().get(key).cloned() {
+                return Some(value);
+            }
+        }
+        // 其他实例处理过的结果：从 Redis 读出后放入本机内存缓存；写入时间沿用原条目，一样受 max_entry_age_sec 限制
+        let value = self.redis.as_ref()?.get(key).await?;
+        if self.is_too_old(&value) {
+            return None;
+        }
+        self.store(key.to_string(), value.clone()).await;
+        Some(value)
+    }
+
+    fn max_entry_age(&self) -> Option<Duration> {
+        self.config.max_entry_age_sec.map(Duration::from_secs)
+    }
+
+    fn is_too_old(&self, value: &ProcessedImage) -> bool {
+        match (self.max_entry_age(), value.cached_at) {
+            (Some(max_age), Some(cached_at)) => cached_at.elapsed().unwrap_or_default() >= max_age,
+            _ => false,
+        }
+    }
+
+    pub async fn insert(&self, key: String, mut value: ProcessedImage) {
+        value.cached_at = Some(SystemTime::now());
+        // Redis 写入在后台进行，响应不等待
+        if let (Some(redis), Some(ttl)) = (self.redis.clone(), self.expires_in(&value)) {
+            let (key, value) = (key.clone(), value.clone());
+            tokio::spawn(async move { redis.insert(&key, &value, ttl).await });
+        }
+        if !self.config.lazy_insert {
+            self.store(key, value).await;
+            return;
+        }
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(key.clone(), (generation, value.clone()));
+        let cache = self.clone();
+        tokio::spawn(async move { cache.store_pending(key, generation, value).await });
+    }
+
+    fn is_pending(&self, key: &str, generation: u64) -> bool {
+        matches!(self.pending.lock().unwrap().get(key), Some((current, _)) if *current == generation)
+    }
+
+    // 后台写入：已被 remove/clear 作废、或被同键的更新写入取代时不再写入；
+    // 写入期间被作废的，写完后撤销，避免刚淘汰的条目又被写回
+    async fn store_pending(&self, key: String, generation: u64, value: ProcessedImage) {
+        if !self.is_pending(&key, generation) {
+            return;
+        }
+        self.store(key.clone(), value).await;
+        let voided = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some((current, _)) if *current == generation => {
+                    pending.remove(&key);
+                    false
+                }
+                // 更新的写入仍在进行，由它覆盖本次写入的值
+                Some(_) => false,
+                None => true,
+            }
+        };
+        if voided {
+            self.shard(&key).invalidate(&key).await;
+        }
     }
 
-    pub async fn remove(&self, key: &str) {
-        self.cache.invalidate(key).await;
+    async fn store(&self, key: String, value: ProcessedImage) {
+        let value = self.compress(value);
+        self.breakdown.lock().unwrap().add(&value);
+        self.shard(&key).insert(key, value).await;
     }
 
+    fn compress(&self, value: ProcessedImage) -> ProcessedImage {
+        if !self.config.compress || value.compressed || !COMPRESSIBLE_TYPES.contains(&value.content_type.as_str()) {
+            return value;
+        }
+        match zstd::bulk::compress(&value.data, ZSTD_LEVEL) {
+            Ok(data) if data.len() < value.data.len() => ProcessedImage {
+                data,
+                compressed: true,
+                ..value
+            },
+            Ok(_) => value,
+            Err(e) => {
+                eprintln!("Warning: cache entry compression failed: {}", e);
+                value
+            }
+        }
+    }
+
+    // 条目距离按 TTL 过期还剩的时间（不考虑 time_to_idle）；未写入过缓存的结果返回 None
+    pub fn expires_in(&self, value: &ProcessedImage) -> Option<Duration> {
+        let age = value.cached_at?.elapsed().unwrap_or_default();
+        let mut ttl = value.ttl.unwrap_or(Duration::from_secs(self.config.time_to_live_sec));
+        if let Some(max_age) = self.max_entry_age() {
+            ttl = ttl.min(max_age);
+        }
+        let ttl = ttl.saturating_sub(age);
+        Some(match value.expires_at {
+            Some(expires_at) => ttl.min(expires_at.duration_since(SystemTime::now()).unwrap_or_default()),
+            None => ttl,
+        })
+    }
+
+    // 本机内存缓存中条目记录的原图最后修改时间，不解压也不查询 Redis；未缓存时为 None
+    pub fn last_modified(&self, key: &str) -> Option<SystemTime> {
+        if let Some((_, value)) = self.pending.lock().unwrap().get(key) {
+            return value.last_modified;
+        }
+        self.shard(key).get(key).filter(|value| !self.is_too_old(value))?.last_modified
+    }
+
+    // 本机内存缓存（含尚未写入的待写条目）中是否存在该键，不查询 Redis
+    pub fn contains(&self, key: &str) -> bool {
+        self.pending.lock().unwrap().contains_key(key) || self.shard(key).contains_key(key)
+    }
+
+    // 移除单个条目，返回条目是否存在
+    pub async fn remove(&self, key: &str) -> bool {
+        let pending = self.pending.lock().unwrap().remove(key).is_some();
+        let shared = match self.redis {
+            Some(ref redis) => redis.remove(key).await,
+            None => false,
+        };
+        self.shard(key).remove(key).await.is_some() || pending || shared
+    }
+
+    // 只清空本机的内存缓存，Redis 中共享的条目按 TTL 过期
     pub async fn clear(&self) {
-        self.cache.invalidate_all();
+        self.pending.lock().unwrap().clear();
+        for shard in self.shards.iter() {
+            shard.invalidate_all();
+        }
     }
 
     pub fn entry_count(&self) -> u64 {
-        self.cache.entry_count()
+        self.shards.iter().map(|shard| shard.entry_count()).sum()
     }
 
     pub fn weighted_size(&self) -> u64 {
-        self.cache.weighted_size()
+        self.shards.iter().map(|shard| shard.weighted_size()).sum()
     }
 
     pub fn get_stats(&self) -> CacheStats {
+        // 让 moka 先处理完挂起的淘汰，使 eviction listener 的扣减尽量及时
+        for shard in self.shards.iter() {
+            shard.sync();
+        }
+        let breakdown = self.breakdown.lock().unwrap();
         // 某些 moka 版本上没有公开 stats()，这里暂时返回基本信息并将 hit_rate 置为 0.0
         CacheStats {
             entry_count: self.entry_count(),
             weighted_size: self.weighted_size(),
             max_capacity: self.config.max_capacity_mb * 1024 * 1024,
             hit_rate: 0.0,
+            by_format: non_empty(&breakdown.by_format),
+            by_size: non_empty(&breakdown.by_size),
+        }
+    }
+}
+
+// 解压失败的条目按未命中处理
+fn decompress(value: ProcessedImage) -> Option<ProcessedImage> {
+    if !value.compressed {
+        return Some(value);
+    }
+    match zstd::stream::decode_all(value.data.as_slice()) {
+        Ok(data) => Some(ProcessedImage {
+            data,
+            compressed: false,
+            ..value
+        }),
+        Err(e) => {
+            eprintln!("Warning: failed to decompress cache entry: {}", e);
+            None
         }
     }
 }
 
-#[derive(Debug, Clone)]
+fn non_empty<K: Clone + Ord>(map: &BTreeMap<K, BreakdownStats>) -> BTreeMap<K, BreakdownStats> {
+    map.iter()
+        .filter(|(_, stats)| stats.entries > 0)
+        .map(|(k, stats)| (k.clone(), stats.clone()))
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct CacheStats {
     pub entry_count: u64,
     pub weighted_size: u64,
     pub max_capacity: u64,
     pub hit_rate: f64,
+    // 按输出格式（jpeg/png/webp…）分类
+    pub by_format: BTreeMap<String, BreakdownStats>,
+    // 按条目大小区间（lt_50kb/lt_200kb/gte_200kb）分类
+    pub by_size: BTreeMap<&'static str, BreakdownStats>,
+}
+
+impl CacheStats {
+    // Prometheus 文本格式
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE image_cache_entries gauge\n");
+        out.push_str(&format!("image_cache_entries {}\n", self.entry_count));
+        out.push_str("# TYPE image_cache_bytes gauge\n");
+        out.push_str(&format!("image_cache_bytes {}\n", self.weighted_size));
+        out.push_str("# TYPE image_cache_capacity_bytes gauge\n");
+        out.push_str(&format!("image_cache_capacity_bytes {}\n", self.max_capacity));
+        out.push_str("# TYPE image_cache_format_entries gauge\n");
+        for (format, stats) in &self.by_format {
+            out.push_str(&format!("image_cache_format_entries{{format=\"{}\"}} {}\n", format, stats.entries));
+        }
+        out.push_str("# TYPE image_cache_format_bytes gauge\n");
+        for (format, stats) in &self.by_format {
+            out.push_str(&format!("image_cache_format_bytes{{format=\"{}\"}} {}\n", format, stats.bytes));
+        }
+        out.push_str("# TYPE image_cache_size_bucket_entries gauge\n");
+        for (bucket, stats) in &self.by_size {
+            out.push_str(&format!("image_cache_size_bucket_entries{{bucket=\"{}\"}} {}\n", bucket, stats.entries));
+        }
+        out.push_str("# TYPE image_cache_size_bucket_bytes gauge\n");
+        for (bucket, stats) in &self.by_size {
+            out.push_str(&format!("image_cache_size_bucket_bytes{{bucket=\"{}\"}} {}\n", bucket, stats.bytes));
+        }
+        out
+    }
 }
 
 impl std::fmt::Display for CacheStats {
@@ -97,7 +462,14 @@ impl std::fmt::Display for CacheStats {
             max_mb,
             usage_percent,
             self.hit_rate * 100.0
-        )
+        )?;
+        for (format, stats) in &self.by_format {
+            write!(f, "\n  format {}: entries={}, size={:.2}MB", format, stats.entries, stats.bytes as f64 / 1024.0 / 1024.0)?;
+        }
+        for (bucket, stats) in &self.by_size {
+            write!(f, "\n  size {}: entries={}, size={:.2}MB", bucket, stats.entries, stats.bytes as f64 / 1024.0 / 1024.0)?;
+        }
+        Ok(())
     }
 }
 
@@ -108,4 +480,300 @@ impl std::fmt::Debug for ImageCache {
             .field("config", &self.config)
             .finish()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(config: serde_json::Value) -> ImageCache {
+        let mut base = serde_json::json!({
+            "max_capacity_mb": 16,
+            "time_to_live_sec": 60,
+            "time_to_idle_sec": 60,
+        });
+        base.as_object_mut().unwrap().extend(config.as_object().unwrap().clone());
+        ImageCache::new(serde_json::from_value(base).unwrap())
+    }
+
+    fn image(content_type: &str, len: usize) -> ProcessedImage {
+        ProcessedImage {
+            data: vec![7; len],
+            content_type: content_type.to_string(),
+            width: None,
+            height: None,
+            ttl: None,
+            expires_at: None,
+            blurhash: None,
+            compressed: false,
+            cached_at: None,
+            degraded: None,
+            last_modified: None,
+        }
+    }
+
+    // 让单线程运行时上的后台写入任务跑完
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn lazy_insert_returns_before_the_entry_is_stored() {
+        let cache = cache(serde_json::json!({ "lazy_insert": true }));
+        cache.insert("a".to_string(), image("image/jpeg", 1024)).await;
+        // 单线程运行时中后台任务尚未运行：insert 已返回，但条目还没写入分片
+        assert!(!cache.shard("a").contains_key("a"));
+        assert_eq!(cache.get("a").await.unwrap().data.len(), 1024);
+
+        settle().await;
+        assert!(cache.shard("a").contains_key("a"));
+        assert!(cache.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove_voids_an_in_flight_lazy_insert() {
+        let cache = cache(serde_json::json!({ "lazy_insert": true }));
+        cache.insert("a".to_string(), image("image/jpeg", 16)).await;
+        assert!(cache.remove("a").await);
+
+        settle().await;
+        assert!(!cache.contains("a"));
+        assert!(cache.get("a").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clear_voids_in_flight_lazy_inserts() {
+        let cache = cache(serde_json::json!({ "lazy_insert": true }));
+        cache.insert("a".to_string(), image("image/jpeg", 16)).await;
+        cache.insert("b".to_string(), image("image/png", 16)).await;
+        cache.clear().await;
+
+        settle().await;
+        assert!(!cache.contains("a"));
+        assert!(!cache.contains("b"));
+    }
+
+    #[tokio::test]
+    async fn a_newer_lazy_insert_is_not_dropped_by_an_older_one() {
+        let cache = cache(serde_json::json!({ "lazy_insert": true }));
+        cache.insert("a".to_string(), image("image/jpeg", 16)).await;
+        cache.insert("a".to_string(), image("image/jpeg", 32)).await;
+
+        settle().await;
+        assert_eq!(cache.get("a").await.unwrap().data.len(), 32);
+        assert!(cache.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn eager_insert_is_stored_before_returning() {
+        let cache = cache(serde_json::json!({}));
+        cache.insert("a".to_string(), image("image/jpeg", 16)).await;
+        assert!(cache.shard("a").contains_key("a"));
+    }
+
+    #[tokio::test]
+    async fn shard_routing_is_deterministic_and_stats_cover_every_shard() {
+        let cache = cache(serde_json::json!({ "shards": 4 }));
+        assert_eq!(cache.shards.len(), 4);
+        let keys: Vec<String> = (0..64).map(|i| format!("bucket/image-{}.jpg", i)).collect();
+        for key in &keys {
+            let shard = cache.shard(key) as *const _;
+            assert_eq!(shard, cache.shard(key) as *const _, "{}", key);
+            assert_eq!(shard, cache.clone().shard(key) as *const _, "{}", key);
+        }
+        // 64 个键不会都落到同一个分片
+        let used: std::collections::HashSet<_> = keys.iter().map(|key| cache.shard(key) as *const _).collect();
+        assert!(used.len() > 1);
+
+        for key in &keys {
+            cache.insert(key.clone(), image("image/jpeg", 100)).await;
+        }
+        for key in &keys {
+            assert!(cache.shard(key).contains_key(key));
+            assert!(cache.get(key).await.is_some());
+        }
+        let stats = cache.get_stats();
+        assert_eq!(stats.entry_count, 64);
+        assert_eq!(stats.weighted_size, 6400);
+        assert_eq!(stats.max_capacity, 16 * 1024 * 1024);
+        assert_eq!(stats.by_format["jpeg"].entries, 64);
+    }
+
+    // 64x64 的 24 位 BMP：文件头 + 信息头 + 纯色像素，压缩率很高
+    fn bmp() -> Vec<u8> {
+        let (width, height) = (64u32, 64u32);
+        let pixels = (width * height * 3) as usize;
+        let mut data = Vec::with_capacity(54 + pixels);
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&(54 + pixels as u32).to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        data.extend(std::iter::repeat_n([0x20, 0x80, 0xF0], pixels / 3).flatten());
+        data
+    }
+
+    #[tokio::test]
+    async fn compressed_bmp_round_trips_through_the_cache() {
+        let cache = cache(serde_json::json!({ "compress": true }));
+        let original = bmp();
+        cache.insert("a.bmp".to_string(), ProcessedImage { data: original.clone(), ..image("image/bmp", 0) }).await;
+
+        let stored = cache.shard("a.bmp").get("a.bmp").unwrap();
+        assert!(stored.compressed);
+        assert!(stored.data.len() < original.len() / 10, "{} bytes", stored.data.len());
+        let served = cache.get("a.bmp").await.unwrap();
+        assert!(!served.compressed);
+        assert_eq!(served.data, original);
+        assert_eq!(served.content_type, "image/bmp");
+    }
+
+    #[tokio::test]
+    async fn only_uncompressed_formats_that_shrink_are_compressed() {
+        let plain = cache(serde_json::json!({}));
+        let cache = cache(serde_json::json!({ "compress": true }));
+        // JPEG 已经压缩过，不再处理
+        cache.insert("a.jpg".to_string(), image("image/jpeg", 4096)).await;
+        assert!(!cache.shard("a.jpg").get("a.jpg").unwrap().compressed);
+        // 近似随机的数据压缩后不会变小，保持原样
+        let mut state = 0x2545F491u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        cache.insert("noise.bmp".to_string(), ProcessedImage { data: noise.clone(), ..image("image/bmp", 0) }).await;
+        let stored = cache.shard("noise.bmp").get("noise.bmp").unwrap();
+        assert!(!stored.compressed);
+        assert_eq!(stored.data, noise);
+        // 未开启 compress 时 BMP 也原样存放
+        plain.insert("a.bmp".to_string(), ProcessedImage { data: bmp(), ..image("image/bmp", 0) }).await;
+        assert!(!plain.shard("a.bmp").get("a.bmp").unwrap().compressed);
+    }
+
+    #[test]
+    fn breakdown_adds_and_subtracts_by_format_and_size() {
+        let mut breakdown = Breakdown::default();
+        let small_jpeg = image("image/jpeg", 10 * 1024);
+        let large_webp = image("image/webp", 300 * 1024);
+        breakdown.add(&small_jpeg);
+        breakdown.add(&image("image/jpeg", 60 * 1024));
+        breakdown.add(&large_webp);
+        assert_eq!((breakdown.by_format["jpeg"].entries, breakdown.by_format["jpeg"].bytes), (2, 70 * 1024));
+        assert_eq!(breakdown.by_size["lt_50kb"].entries, 1);
+        assert_eq!(breakdown.by_size["lt_200kb"].entries, 1);
+        assert_eq!(breakdown.by_size["gte_200kb"].bytes, 300 * 1024);
+
+        breakdown.subtract(&small_jpeg);
+        breakdown.subtract(&large_webp);
+        assert_eq!((breakdown.by_format["jpeg"].entries, breakdown.by_format["jpeg"].bytes), (1, 60 * 1024));
+        assert_eq!(breakdown.by_format["webp"].entries, 0);
+        assert_eq!(breakdown.by_size["lt_50kb"].entries, 0);
+        // 多扣不会下溢
+        breakdown.subtract(&large_webp);
+        assert_eq!(breakdown.by_size["gte_200kb"].bytes, 0);
+    }
+
+    // eviction listener 由 moka 在后台线程池中调用，分类统计的扣减稍后才可见
+    async fn stats_when(cache: &ImageCache, ready: impl Fn(&CacheStats) -> bool) -> CacheStats {
+        for _ in 0..200 {
+            let stats = cache.get_stats();
+            if ready(&stats) {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        cache.get_stats()
+    }
+
+    #[tokio::test]
+    async fn stats_breakdown_follows_inserts_replacements_and_evictions() {
+        let cache = cache(serde_json::json!({}));
+        cache.insert("a".to_string(), image("image/jpeg", 10 * 1024)).await;
+        cache.insert("b".to_string(), image("image/png", 100 * 1024)).await;
+        cache.insert("c".to_string(), image("image/webp", 300 * 1024)).await;
+        let stats = cache.get_stats();
+        assert_eq!(stats.by_format.keys().collect::<Vec<_>>(), ["jpeg", "png", "webp"]);
+        assert_eq!(stats.by_size.keys().copied().collect::<Vec<_>>(), ["gte_200kb", "lt_200kb", "lt_50kb"]);
+        assert_eq!(stats.by_format["png"].bytes, 100 * 1024);
+
+        // 淘汰后从分类中扣除，空的分类不再出现
+        cache.remove("b").await;
+        let stats = stats_when(&cache, |stats| !stats.by_format.contains_key("png")).await;
+        assert!(!stats.by_format.contains_key("png"));
+        assert!(!stats.by_size.contains_key("lt_200kb"));
+
+        // 同键替换时扣除旧值
+        cache.insert("a".to_string(), image("image/webp", 20 * 1024)).await;
+        let stats = stats_when(&cache, |stats| !stats.by_format.contains_key("jpeg")).await;
+        assert!(!stats.by_format.contains_key("jpeg"));
+        assert_eq!((stats.by_format["webp"].entries, stats.by_format["webp"].bytes), (2, 320 * 1024));
+        assert_eq!(stats.by_size["lt_50kb"].bytes, 20 * 1024);
+    }
+
+    #[test]
+    fn an_earlier_object_expiry_shortens_the_entry_ttl() {
+        let expiry = EntryExpiry { default_ttl: Duration::from_secs(3600) };
+        assert_eq!(expiry.ttl_for(&image("image/jpeg", 1)), Duration::from_secs(3600));
+
+        let expiring = ProcessedImage { expires_at: Some(SystemTime::now() + Duration::from_secs(60)), ..image("image/jpeg", 1) };
+        let ttl = expiry.ttl_for(&expiring);
+        assert!(ttl <= Duration::from_secs(60) && ttl > Duration::from_secs(55), "{:?}", ttl);
+
+        // 原图较晚过期时不延长 TTL；已经过期的立即过期
+        let later = ProcessedImage { expires_at: Some(SystemTime::now() + Duration::from_secs(7200)), ..image("image/jpeg", 1) };
+        assert_eq!(expiry.ttl_for(&later), Duration::from_secs(3600));
+        let expired = ProcessedImage { expires_at: Some(SystemTime::now() - Duration::from_secs(1)), ..image("image/jpeg", 1) };
+        assert_eq!(expiry.ttl_for(&expired), Duration::ZERO);
+        // 条目自带的 ttl 同样被截断
+        let own_ttl = ProcessedImage { ttl: Some(Duration::from_secs(600)), ..expiring };
+        assert!(expiry.ttl_for(&own_ttl) <= Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn entries_expire_with_their_original() {
+        let cache = cache(serde_json::json!({}));
+        let expiring = ProcessedImage { expires_at: Some(SystemTime::now() + Duration::from_millis(100)), ..image("image/jpeg", 1) };
+        cache.insert("a".to_string(), expiring).await;
+        cache.insert("b".to_string(), image("image/jpeg", 1)).await;
+        assert!(cache.get("a").await.is_some());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(cache.get("a").await.is_none());
+        assert!(cache.get("b").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn entries_past_the_max_age_are_regenerated_despite_recent_access() {
+        let cache = cache(serde_json::json!({ "max_entry_age_sec": 1 }));
+        cache.insert("a".to_string(), image("image/jpeg", 1)).await;
+        // 持续访问只会刷新 time_to_idle，不影响绝对存活上限
+        for _ in 0..3 {
+            assert!(cache.get("a").await.is_some());
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(cache.get("a").await.is_none());
+        assert!(!cache.shard("a").contains_key("a"));
+    }
+
+    #[test]
+    fn is_too_old_compares_the_write_time_with_the_max_age() {
+        let unlimited = cache(serde_json::json!({}));
+        let cache = cache(serde_json::json!({ "max_entry_age_sec": 60 }));
+        let written = |ago: u64| ProcessedImage { cached_at: Some(SystemTime::now() - Duration::from_secs(ago)), ..image("image/jpeg", 1) };
+        assert!(!cache.is_too_old(&written(10)));
+        assert!(cache.is_too_old(&written(61)));
+        // 未写入过缓存的结果、未配置上限时都不算过期
+        assert!(!cache.is_too_old(&image("image/jpeg", 1)));
+        assert!(!unlimited.is_too_old(&written(86_400)));
+    }
+}
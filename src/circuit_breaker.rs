@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CircuitBreakerConfig {
+    // 连续失败多少次后断开
+    pub failure_threshold: u32,
+    // 断开后多久进入半开状态，放行一个探测请求
+    pub cooldown_sec: u64,
+}
+
+#[derive(Debug)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+/// 简单的熔断器：closed → (连续失败) → open → (冷却结束) → half-open → (探测成功) → closed
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    // 是否允许本次请求访问后端
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } => {
+                if Instant::now() >= until {
+                    *state = BreakerState::HalfOpen { probing: true };
+                    true
+                } else {
+                    false
+                }
+            }
+            // 半开状态同一时间只放行一个探测请求
+            BreakerState::HalfOpen { probing } => {
+                if probing {
+                    false
+                } else {
+                    *state = BreakerState::HalfOpen { probing: true };
+                    true
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, BreakerState::Closed { failures: 0 }) {
+            println!("S3 circuit breaker closed");
+        }
+        *state = BreakerState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let open = match *state {
+            BreakerState::Closed { failures } => {
+                let failures = failures + 1;
+                if failures >= self.config.failure_threshold {
+                    true
+                } else {
+                    *state = BreakerState::Closed { failures };
+                    false
+                }
+            }
+            BreakerState::HalfOpen { .. } => true,
+            BreakerState::Open { .. } => false,
+        };
+        if open {
+            eprintln!("S3 circuit breaker opened for {}s", self.config.cooldown_sec);
+            *state = BreakerState::Open {
+                until: Instant::now() + Duration::from_secs(self.config.cooldown_sec),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cooldown_sec: u64) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig { failure_threshold: 3, cooldown_sec })
+    }
+
+    #[test]
+    fn consecutive_failures_open_the_breaker() {
+        let breaker = breaker(60);
+        for _ in 0..2 {
+            breaker.record_failure();
+            assert!(breaker.allow());
+        }
+        breaker.record_failure();
+        assert!(!breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = breaker(60);
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn half_open_admits_one_probe() {
+        // 冷却时间为 0：断开后下一个请求即进入半开状态
+        let breaker = breaker(0);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+
+        // 探测失败重新断开，探测成功则恢复
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use std::{panic::AssertUnwindSafe, sync::Arc};
+use tokio::sync::oneshot;
+
+use crate::error::RequestError;
+
+/// 专用于 OpenCV 解码/处理/编码的线程池（image_processing.cpu_threads）
+///
+/// 异步侧提交任务后通过 oneshot 等待结果，CPU 密集的工作不会占用 Tokio 的工作线程。
+/// 未配置时任务在调用方所在的线程上直接执行。
+#[derive(Debug, Clone, Default)]
+pub struct CpuPool {
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl CpuPool {
+    pub fn new(threads: Option<usize>) -> Result<Self> {
+        let Some(threads) = threads else {
+            return Ok(Self::default());
+        };
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.max(1))
+            .thread_name(|index| format!("opencv-worker-{}", index))
+            // 任务内的 panic 已由 catch_panic 转换为错误，这里兜底记录其余情况
+            .panic_handler(|_| eprintln!("OpenCV worker thread panicked while processing an image"))
+            .build()?;
+        println!("OpenCV work runs on a dedicated pool of {} threads", pool.current_num_threads());
+        Ok(Self { pool: Some(Arc::new(pool)) })
+    }
+
+    pub async fn run<F, R>(&self, job: F) -> Result<R>
+    where
+        F: FnOnce() -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let Some(ref pool) = self.pool else {
+            return catch_panic(job);
+        };
+        let (tx, rx) = oneshot::channel();
+        pool.spawn(move || {
+            let _ = tx.send(catch_panic(job));
+        });
+        rx.await.map_err(|_| anyhow::anyhow!("Image processing job was aborted by a worker panic"))?
+    }
+}
+
+// OpenCV 处理畸形输入时可能 panic；转换为 422，避免在 Tokio 工作线程上展开到整个连接任务。
+// 任务只持有自己的 Mat 和内存额度，展开时随 drop 释放，不会留下不一致的共享状态
+fn catch_panic<R>(job: impl FnOnce() -> Result<R>) -> Result<R> {
+    std::panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        eprintln!("Image processing panicked: {}", message);
+        Err(RequestError::new(422, "The source image could not be processed").into())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(e: &anyhow::Error) -> Option<u16> {
+        e.downcast_ref::<RequestError>().map(|re| re.status)
+    }
+
+    #[tokio::test]
+    async fn a_panicking_job_becomes_a_422_instead_of_crashing() {
+        for pool in [CpuPool::default(), CpuPool::new(Some(1)).unwrap()] {
+            let err = pool.run(|| -> Result<()> { panic!("cv::Exception: corrupt header") }).await.unwrap_err();
+            assert_eq!(status(&err), Some(422));
+            assert_eq!(err.downcast_ref::<RequestError>().unwrap().message, "The source image could not be processed");
+
+            // 同一个池之后的任务照常执行
+            assert_eq!(pool.run(|| Ok(7)).await.unwrap(), 7);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_job_error_is_passed_through_unchanged() {
+        let pool = CpuPool::new(Some(1)).unwrap();
+        let err = pool.run(|| -> Result<()> { Err(RequestError::new(400, "bad crop").into()) }).await.unwrap_err();
+        assert_eq!(status(&err), Some(400));
+    }
+
+    #[tokio::test]
+    async fn jobs_run_on_the_dedicated_pool_and_leave_the_runtime_free() {
+        let pool = CpuPool::new(Some(2)).unwrap();
+        let job = pool.run(|| {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            Ok(std::thread::current().name().map(str::to_string))
+        });
+        tokio::pin!(job);
+
+        // 单线程运行时上：任务执行期间其他异步任务照常推进
+        let ticked = tokio::select! {
+            _ = &mut job => false,
+            _ = tokio::time::sleep(std::time::Duration::from_millis(20)) => true,
+        };
+        assert!(ticked, "the runtime was blocked by the CPU job");
+
+        let thread = job.await.unwrap().unwrap();
+        assert!(thread.starts_with("opencv-worker-"), "{}", thread);
+        assert_ne!(std::thread::current().name(), Some(thread.as_str()));
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+
+/// 携带 HTTP 状态码的请求错误。
+///
+/// 处理流程内部仍然使用 `anyhow::Result`，需要返回特定状态码时构造该错误，
+/// 路由层通过 `downcast_ref::<RequestError>()` 取回状态码生成响应。
+#[derive(Debug, Clone)]
+pub struct RequestError {
+    pub status: u16,
+    pub message: String,
+    // 响应的 Retry-After（秒），用于 429/503
+    pub retry_after: Option<u64>,
+}
+
+impl RequestError {
+    pub fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(400, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(401, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(403, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(404, message)
+    }
+
+    pub fn unsupported_media_type(message: impl Into<String>) -> Self {
+        Self::new(415, message)
+    }
+
+    pub fn too_many_requests(message: impl Into<String>) -> Self {
+        Self::new(429, message)
+    }
+
+    pub fn service_unavailable(message: impl Into<String>) -> Self {
+        Self::new(503, message)
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (status {})", self.message, self.status)
+    }
+}
+
+impl std::error::Error for RequestError {}
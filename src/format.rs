@@ -0,0 +1,163 @@
+/// 通过文件头识别出的图片格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+    Avif,
+    Tiff,
+    Bmp,
+    Svg,
+    // 相机 RAW，按扩展名（见 `raw::is_raw_key`）或 CR2 魔数识别
+    Raw,
+}
+
+impl ImageFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::WebP => "image/webp",
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Tiff => "image/tiff",
+            ImageFormat::Bmp => "image/bmp",
+            ImageFormat::Svg => "image/svg+xml",
+            ImageFormat::Raw => "image/x-dcraw",
+        }
+    }
+
+    /// 对应的输出格式名（见 `OUTPUT_FORMATS`），不支持作为输出的格式返回 None
+    pub fn output_name(&self) -> Option<&'static str> {
+        match self {
+            ImageFormat::Jpeg => Some("jpg"),
+            ImageFormat::Png => Some("png"),
+            ImageFormat::WebP => Some("webp"),
+            _ => None,
+        }
+    }
+}
+
+/// 根据魔数识别图片格式，非图片或无法识别时返回 None
+pub fn detect_format(bytes: &[u8]) -> Option<ImageFormat> {
+    let Some(kind) = infer::get(bytes) else {
+        return crate::svg::is_svg(bytes).then_some(ImageFormat::Svg);
+    };
+    match kind.mime_type() {
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/png" => Some(ImageFormat::Png),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        "image/avif" => Some(ImageFormat::Avif),
+        "image/tiff" => Some(ImageFormat::Tiff),
+        "image/bmp" => Some(ImageFormat::Bmp),
+        "image/x-canon-cr2" => Some(ImageFormat::Raw),
+        _ => None,
+    }
+}
+
+/// JPEG 的帧类型是否为渐进式（SOF2/SOF6/SOF10/SOF14），按标记段逐个跳过直到遇到 SOF；
+/// 数据不完整或不是 JPEG 时返回 false
+pub fn is_progressive_jpeg(bytes: &[u8]) -> bool {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+    let mut pos = 2;
+    while pos + 1 < bytes.len() {
+        if bytes[pos] != 0xFF {
+            return false;
+        }
+        let marker = bytes[pos + 1];
+        match marker {
+            // 填充字节
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            0xC2 | 0xC6 | 0xCA | 0xCE => return true,
+            // 其余 SOF，以及在 SOF 之前就出现的 SOS/EOI
+            0xC0 | 0xC1 | 0xC3 | 0xC5 | 0xC7 | 0xC9 | 0xCB | 0xCD | 0xDA | 0xD9 => return false,
+            // 没有长度字段的独立标记
+            0x01 | 0xD0..=0xD7 => pos += 2,
+            _ => {
+                let Some(length) = bytes.get(pos + 2..pos + 4) else {
+                    return false;
+                };
+                pos += 2 + u16::from_be_bytes([length[0], length[1]]) as usize;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 各格式的最小文件头，足以被魔数识别
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00];
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+    const GIF: &[u8] = b"GIF89a\x40\x00\x30\x00\x00\x00\x00";
+    const WEBP: &[u8] = b"RIFF\x24\x00\x00\x00WEBPVP8 \x18\x00\x00\x00";
+    const AVIF: &[u8] = b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00avifmif1miaf";
+    const TIFF_LE: &[u8] = b"II\x2a\x00\x08\x00\x00\x00\x10\x00\x00\x01";
+    const TIFF_BE: &[u8] = b"MM\x00\x2a\x00\x00\x00\x08\x00\x10\x01\x00";
+    const BMP: &[u8] = b"BM\x46\x00\x00\x00\x00\x00\x00\x00\x36\x00\x00\x00\x28\x00\x00\x00";
+
+    #[test]
+    fn image_formats_are_detected_from_magic_bytes() {
+        for (bytes, format) in [
+            (JPEG, ImageFormat::Jpeg),
+            (PNG, ImageFormat::Png),
+            (GIF, ImageFormat::Gif),
+            (WEBP, ImageFormat::WebP),
+            (AVIF, ImageFormat::Avif),
+            (TIFF_LE, ImageFormat::Tiff),
+            (TIFF_BE, ImageFormat::Tiff),
+            (BMP, ImageFormat::Bmp),
+        ] {
+            assert_eq!(detect_format(bytes), Some(format), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn svg_is_detected_from_its_markup() {
+        assert_eq!(detect_format(br#"<svg xmlns="http://www.w3.org/2000/svg"/>"#), Some(ImageFormat::Svg));
+    }
+
+    #[test]
+    fn non_image_blobs_are_not_detected() {
+        for bytes in [
+            &b""[..],
+            b"plain text, not an image",
+            b"%PDF-1.7\n%\xe2\xe3\xcf\xd3",
+            b"PK\x03\x04\x14\x00\x00\x00",
+            &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05],
+        ] {
+            assert_eq!(detect_format(bytes), None, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn content_type_and_output_name_follow_the_format() {
+        assert_eq!(ImageFormat::WebP.content_type(), "image/webp");
+        assert_eq!(ImageFormat::Jpeg.output_name(), Some("jpg"));
+        assert_eq!(ImageFormat::Gif.output_name(), None);
+    }
+
+    #[test]
+    fn the_frame_type_decides_if_a_jpeg_is_progressive() {
+        // SOI、APP0（长度 16），然后是 SOF 标记
+        let jpeg = |sof: u8| [&JPEG[..4], &[0x00, 0x10], &[0; 14], &[0xFF, sof, 0x00, 0x11]].concat();
+        assert!(is_progressive_jpeg(&jpeg(0xC2)));
+        assert!(!is_progressive_jpeg(&jpeg(0xC0)));
+        // SOF 之前的填充字节被跳过
+        let padded = [&JPEG[..2], &[0xFF, 0xFF, 0xFF, 0xC2, 0x00, 0x11]].concat();
+        assert!(is_progressive_jpeg(&padded));
+        // 在 SOF 之前截断、出现 SOS，或者根本不是 JPEG
+        assert!(!is_progressive_jpeg(&jpeg(0xC2)[..12]));
+        assert!(!is_progressive_jpeg(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x0C]));
+        assert!(!is_progressive_jpeg(PNG));
+    }
+}
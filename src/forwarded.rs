@@ -0,0 +1,166 @@
+use anyhow::Result;
+use ipnet::IpNet;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use warp::Filter;
+
+use crate::server::PeerAddr;
+
+/// 经过代理解析后的真实客户端信息
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub scheme: String,
+}
+
+/// 受信任的代理网段，只有来自这些地址的 X-Forwarded-* 头才会被采信
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Arc<Vec<IpNet>>,
+}
+
+impl TrustedProxies {
+    // 支持 CIDR（10.0.0.0/8）和单个 IP 地址
+    pub fn parse(entries: &[String]) -> Result<Self> {
+        let networks = entries
+            .iter()
+            .map(|entry| {
+                let entry = entry.trim();
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow::anyhow!("Invalid trusted proxy '{}', expected an IP or CIDR", entry))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            networks: Arc::new(networks),
+        })
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn resolve(
+        &self,
+        peer: Option<SocketAddr>,
+        forwarded_for: Option<&str>,
+        forwarded_proto: Option<&str>,
+    ) -> ClientInfo {
+        let peer_ip = peer.map(|addr| addr.ip());
+        let untrusted = ClientInfo {
+            ip: peer_ip,
+            scheme: "http".to_string(),
+        };
+
+        // 直连的对端不是受信任代理时，忽略其转发头，防止伪造
+        match peer_ip {
+            Some(ip) if self.is_trusted(&ip) => {}
+            _ => return untrusted,
+        }
+
+        // X-Forwarded-For 从右向左依次是离我们最近的代理，跳过受信任代理后的第一个地址即真实客户端
+        let chain: Vec<IpAddr> = forwarded_for
+            .map(|value| {
+                value
+                    .split(',')
+                    .filter_map(|part| part.trim().parse::<IpAddr>().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let ip = chain
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(ip))
+            .or_else(|| chain.first())
+            .copied()
+            .or(peer_ip);
+
+        let scheme = forwarded_proto
+            .and_then(|value| value.split(',').next())
+            .map(|proto| proto.trim().to_ascii_lowercase())
+            .filter(|proto| proto == "http" || proto == "https")
+            .unwrap_or_else(|| "http".to_string());
+
+        ClientInfo { ip, scheme }
+    }
+}
+
+/// 提取真实客户端信息的 warp filter
+pub fn client_info(
+    proxies: TrustedProxies,
+) -> impl Filter<Extract = (ClientInfo,), Error = warp::Rejection> + Clone {
+    // 对端地址由 server::serve 以请求扩展的方式传入
+    warp::ext::optional::<PeerAddr>()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .map(move |peer: Option<PeerAddr>, forwarded_for: Option<String>, forwarded_proto: Option<String>| {
+            proxies.resolve(peer.map(|p| p.0), forwarded_for.as_deref(), forwarded_proto.as_deref())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::parse(&["10.0.0.0/8".to_string(), "192.168.1.1".to_string()]).unwrap()
+    }
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 40000))
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn trusted_proxy_headers_are_honored() {
+        let client = proxies().resolve(peer("10.1.2.3"), Some("203.0.113.7"), Some("HTTPS"));
+        assert_eq!(client.ip, ip("203.0.113.7"));
+        assert_eq!(client.scheme, "https");
+
+        let client = proxies().resolve(peer("192.168.1.1"), Some("203.0.113.7"), Some("https, http"));
+        assert_eq!(client.ip, ip("203.0.113.7"));
+        assert_eq!(client.scheme, "https");
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let client = proxies().resolve(peer("198.51.100.9"), Some("203.0.113.7"), Some("https"));
+        assert_eq!(client.ip, ip("198.51.100.9"));
+        assert_eq!(client.scheme, "http");
+
+        let client = proxies().resolve(None, Some("203.0.113.7"), Some("https"));
+        assert_eq!(client.ip, None);
+        assert_eq!(client.scheme, "http");
+    }
+
+    #[test]
+    fn forwarded_chain_skips_trusted_hops_from_the_right() {
+        // 最左侧的地址由客户端自行填写，可以伪造；取右起第一个不受信任的地址
+        let chain = "1.1.1.1, 203.0.113.7, 10.0.0.5, 192.168.1.1";
+        assert_eq!(proxies().resolve(peer("10.1.2.3"), Some(chain), None).ip, ip("203.0.113.7"));
+        // 整条链都是受信任代理时取最左侧的地址
+        assert_eq!(proxies().resolve(peer("10.1.2.3"), Some("10.0.0.7, 10.0.0.5"), None).ip, ip("10.0.0.7"));
+        // 无法解析的条目被跳过，没有可用地址时回退到对端地址
+        assert_eq!(proxies().resolve(peer("10.1.2.3"), Some("unknown, 203.0.113.7"), None).ip, ip("203.0.113.7"));
+        assert_eq!(proxies().resolve(peer("10.1.2.3"), Some("garbage"), None).ip, ip("10.1.2.3"));
+    }
+
+    #[test]
+    fn unknown_schemes_fall_back_to_http() {
+        assert_eq!(proxies().resolve(peer("10.1.2.3"), None, Some("ftp")).scheme, "http");
+        assert_eq!(proxies().resolve(peer("10.1.2.3"), None, None).scheme, "http");
+    }
+
+    #[test]
+    fn invalid_trusted_proxy_entries_are_rejected() {
+        assert!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(TrustedProxies::parse(&["proxy.internal".to_string()]).is_err());
+        assert!(TrustedProxies::parse(&[" ::1 ".to_string()]).unwrap().is_trusted(&"::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::Result;
+use hyper::{
+    body::HttpBody,
+    client::{connect::dns::Name, HttpConnector},
+    header::RANGE,
+    Body, Client, Request, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{error::RequestError, logging::redact_key, s3_client::S3Object};
+
+/// 从任意 HTTP(S) 地址读取原图（http_source），用于处理不在对象存储中的图片
+///
+/// 请求 `/{bucket}/{host}/{path}` 读取 `https://{host}/{path}`，host 必须在 allowed_hosts 中。
+/// 为防止 SSRF，host 解析出的地址只要有一个不是公网地址就拒绝，实际连接也只使用校验过的地址。
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpSourceConfig {
+    // 代替 S3 bucket 的路径第一段，例如 "web"
+    pub bucket: String,
+    // 允许访问的主机名（可带端口），"*.example.com" 匹配其所有子域名
+    pub allowed_hosts: Vec<String>,
+    // 使用明文 http 访问源站，默认 https
+    #[serde(default)]
+    pub plain_http: bool,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    // 原图允许的最大字节数，超出时返回 413
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_max_bytes() -> u64 {
+    50 * 1024 * 1024
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpStore {
+    config: HttpSourceConfig,
+    client: Client<HttpsConnector<HttpConnector<PublicResolver>>>,
+}
+
+impl HttpStore {
+    pub fn new(config: HttpSourceConfig) -> Self {
+        let mut http = HttpConnector::new_with_resolver(PublicResolver);
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(Duration::from_millis(config.timeout_ms)));
+        let builder = HttpsConnectorBuilder::new().with_native_roots();
+        let https = if config.plain_http { builder.https_or_http() } else { builder.https_only() };
+        let client = Client::builder().build(https.enable_http1().wrap_connector(http));
+        println!(
+            "HTTP source enabled under '{}/' for {} allowed hosts",
+            config.bucket,
+            config.allowed_hosts.len()
+        );
+        Self { config, client }
+    }
+
+    // key 是否属于 HTTP 来源（第一段为 http_source.bucket）
+    pub fn handles(&self, key: &str) -> bool {
+        key.split('/').next() == Some(self.config.bucket.as_str())
+    }
+
+    pub async fn get_object(&self, key: &str) -> Result<S3Object> {
+        let data = self.fetch(key, None).await?;
+        Ok(S3Object { data, metadata: HashMap::new(), expires_at: None, last_modified: None })
+    }
+
+    // 读取 [start, end] 字节区间（含两端）；源站忽略 Range 返回整个文件时截取所需部分
+    pub async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+        let mut data = self.fetch(key, Some((start, end))).await?;
+        data.truncate((end - start + 1) as usize);
+        Ok(data)
+    }
+
+    async fn fetch(&self, key: &str, range: Option<(u64, u64)>) -> Result<Vec<u8>> {
+        let uri = self.source_uri(key)?;
+        let host = uri.host().unwrap_or_default();
+        // 提前解析一次以便返回明确的 403；连接时 PublicResolver 会再次校验，防止 DNS 重绑定
+        let addrs = tokio::time::timeout(self.timeout(), resolve_public(host))
+            .await
+            .map_err(|_| anyhow::anyhow!("Resolving HTTP source host '{}' timed out", host))?;
+        if let Err(e) = addrs {
+            eprintln!("Rejected HTTP source '{}': {}", redact_key(key), e);
+            return Err(RequestError::forbidden(format!("Host '{}' does not resolve to a public address", host)).into());
+        }
+
+        let mut request = Request::get(uri.clone());
+        if let Some((start, end)) = range {
+            request = request.header(RANGE, format!("bytes={}-{}", start, end));
+        }
+        let request = request.body(Body::empty())?;
+        let limit = match range {
+            Some((start, end)) => (end - start + 1).min(self.config.max_bytes),
+            None => self.config.max_bytes,
+        };
+        let fetch = async {
+            let response = self.client.request(request).await?;
+            match response.status() {
+                StatusCode::OK | StatusCode::PARTIAL_CONTENT => {}
+                StatusCode::NOT_FOUND | StatusCode::GONE => return Err(RequestError::not_found("Image not found").into()),
+                // 不跟随重定向：目标地址没有经过 allowed_hosts 校验
+                status => anyhow::bail!("HTTP source returned {} for '{}'", status, redact_key(key)),
+            }
+            let mut body = response.into_body();
+            let mut data = Vec::new();
+            while let Some(chunk) = body.data().await {
+                data.extend_from_slice(&chunk?);
+                if data.len() as u64 > limit {
+                    // 区间读取只需要前 limit 个字节，由 get_range 截断
+                    if range.is_some() {
+                        break;
+                    }
+                    return Err(RequestError::new(413, "Source image exceeds http_source.max_bytes").into());
+                }
+            }
+            Ok(data)
+        };
+        let data = tokio::time::timeout(self.timeout(), fetch)
+            .await
+            .map_err(|_| anyhow::anyhow!("HTTP source request for '{}' timed out", redact_key(key)))??;
+        println!("Fetched '{}' from HTTP source, size: {} bytes", redact_key(key), data.len());
+        Ok(data)
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.config.timeout_ms)
+    }
+
+    // "{bucket}/{host}/{path}" → http(s)://{host}/{path}，host 不在 allowed_hosts 中时返回 403
+    fn source_uri(&self, key: &str) -> Result<Uri> {
+        let rest = key.split_once('/').map_or("", |(_, rest)| rest);
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let host = host.to_ascii_lowercase();
+        // 只接受主机名加可选端口：路径已解码，"evil.com?.example.com" 之类的段能通过后缀匹配，
+        // 但解析为 URL 时 authority 在 '?'/'#'/'@' 处截断，实际访问的是另一台主机
+        if !is_host_segment(&host) || !self.host_allowed(&host) {
+            eprintln!("Rejected HTTP source '{}': host is not allowed", redact_key(key));
+            return Err(RequestError::forbidden(format!("Host '{}' is not an allowed HTTP source", host)).into());
+        }
+        let scheme = if self.config.plain_http { "http" } else { "https" };
+        // path 来自已解码的请求路径，按 URL 规则重新编码
+        let path = percent_encoding::utf8_percent_encode(path, PATH).to_string();
+        let uri: Uri = format!("{}://{}/{}", scheme, host, path)
+            .parse()
+            .map_err(|_| RequestError::bad_request(format!("Invalid HTTP source URL for host '{}'", host)))?;
+        // 再确认解析出的 authority 就是校验过的 host
+        if uri.authority().map(|authority| authority.as_str()) != Some(host.as_str()) {
+            return Err(RequestError::forbidden(format!("Host '{}' is not an allowed HTTP source", host)).into());
+        }
+        Ok(uri)
+    }
+
+    fn host_allowed(&self, host: &str) -> bool {
+        self.config.allowed_hosts.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            match allowed.strip_prefix("*.") {
+                Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => host == allowed,
+            }
+        })
+    }
+}
+
+// [a-z0-9.-]+ 加可选的 :端口
+fn is_host_segment(segment: &str) -> bool {
+    let (host, port) = match segment.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (segment, None),
+    };
+    !host.is_empty()
+        && host.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'.' || b == b'-')
+        && port.is_none_or(|port| !port.is_empty() && port.len() <= 5 && port.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// 路径中需要编码的字符：控制字符、空格以及在 URL 中有特殊含义的字符，保留 '/'
+const PATH: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// 解析 host（不含端口）的全部地址，只要有一个不是公网地址就返回错误
+async fn resolve_public(host: &str) -> io::Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?.collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("'{}' has no addresses", host)));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("'{}' resolves to non-public address {}", host, addr.ip()),
+        ));
+    }
+    Ok(addrs)
+}
+
+// 私有、回环、链路本地、CGNAT、文档、组播等非公网地址段
+fn is_public_ip(ip: IpAddr) -> bool {
+    static BLOCKED: OnceLock<Vec<IpNet>> = OnceLock::new();
+    let blocked = BLOCKED.get_or_init(|| {
+        [
+            "0.0.0.0/8", "10.0.0.0/8", "100.64.0.0/10", "127.0.0.0/8", "169.254.0.0/16", "172.16.0.0/12",
+            "192.0.0.0/24", "192.0.2.0/24", "192.168.0.0/16", "198.18.0.0/15", "198.51.100.0/24",
+            "203.0.113.0/24", "224.0.0.0/3", "::/127", "64:ff9b::/96", "100::/64", "2001:db8::/32",
+            "fc00::/7", "fe80::/10", "ff00::/8",
+        ]
+        .iter()
+        .map(|net| net.parse().unwrap())
+        .collect()
+    });
+    // IPv4 映射地址（::ffff:a.b.c.d）按其中的 IPv4 地址判断
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    };
+    !blocked.iter().any(|net| net.contains(&ip))
+}
+
+// HttpConnector 使用的解析器：连接前校验解析结果，拒绝非公网地址
+#[derive(Debug, Clone)]
+struct PublicResolver;
+
+impl tower_service::Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        Box::pin(async move { resolve_public(name.as_str()).await.map(Vec::into_iter) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> HttpStore {
+        HttpStore::new(HttpSourceConfig {
+            bucket: "web".to_string(),
+            allowed_hosts: vec!["images.example.com".to_string(), "*.cdn.example.com".to_string(), "127.0.0.1".to_string()],
+            plain_http: false,
+            timeout_ms: default_timeout_ms(),
+            max_bytes: default_max_bytes(),
+        })
+    }
+
+    fn status(result: Result<impl std::fmt::Debug>) -> u16 {
+        result.unwrap_err().downcast_ref::<RequestError>().unwrap().status
+    }
+
+    #[test]
+    fn allowed_hosts_map_to_https_urls() {
+        let store = store();
+        assert!(store.handles("web/images.example.com/a.jpg"));
+        assert!(!store.handles("website/images.example.com/a.jpg"));
+        let uri = store.source_uri("web/images.example.com/photos/a b.jpg").unwrap();
+        assert_eq!(uri.to_string(), "https://images.example.com/photos/a%20b.jpg");
+        let uri = store.source_uri("web/eu.cdn.example.com/a.jpg").unwrap();
+        assert_eq!(uri.host(), Some("eu.cdn.example.com"));
+    }
+
+    #[test]
+    fn other_hosts_are_forbidden() {
+        let store = store();
+        assert_eq!(status(store.source_uri("web/evil.com/a.jpg")), 403);
+        // 通配符不匹配裸域名
+        assert_eq!(status(store.source_uri("web/cdn.example.com/a.jpg")), 403);
+        assert_eq!(status(store.source_uri("web/images.example.com.evil.com/a.jpg")), 403);
+    }
+
+    #[test]
+    fn host_segments_cannot_smuggle_another_authority() {
+        let store = store();
+        for key in [
+            "web/evil.com?.cdn.example.com/a.jpg",
+            "web/evil.com#.cdn.example.com/a.jpg",
+            "web/evil.com@x.cdn.example.com/a.jpg",
+            "web/evil.com\\.cdn.example.com/a.jpg",
+            "web/images.example.com:80x/a.jpg",
+        ] {
+            assert_eq!(status(store.source_uri(key)), 403, "{}", key);
+        }
+    }
+
+    #[test]
+    fn non_public_addresses_are_blocked() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn private_ip_sources_are_rejected_before_connecting() {
+        // 即使在 allowed_hosts 中，解析到私有地址的主机也返回 403
+        assert_eq!(status(store().get_object("web/127.0.0.1/a.jpg").await), 403);
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::{
+    error::RequestError,
+    image_processor::{ProcessingParams, Region, Rotation, OPTIONAL_OUTPUT_FORMATS, OUTPUT_FORMATS, TILE_SIZE},
+};
+
+/// 支持的 IIIF Image API 版本，对应 /iiif/2/ 与 /iiif/3/ 两个前缀
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IiifVersion {
+    V2,
+    V3,
+}
+
+impl IiifVersion {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "2" => Some(Self::V2),
+            "3" => Some(Self::V3),
+            _ => None,
+        }
+    }
+}
+
+// 将 {region}/{size}/{rotation}/{quality}.{format} 解析为处理参数，语法不支持时返回 400
+pub fn parse_image_request(
+    version: IiifVersion,
+    region: &str,
+    size: &str,
+    rotation: &str,
+    quality_format: &str,
+) -> Result<ProcessingParams> {
+    let mut params = ProcessingParams {
+        region: parse_region(region)?,
+        rotation: parse_rotation(rotation)?,
+        ..Default::default()
+    };
+    parse_size(version, size, &mut params)?;
+
+    let (quality, format) = quality_format
+        .rsplit_once('.')
+        .ok_or_else(|| bad_request(format!("missing format in '{}'", quality_format)))?;
+    match quality {
+        "default" | "color" => {}
+        "gray" => params.grayscale = true,
+        other => return Err(bad_request(format!("unsupported quality '{}'", other))),
+    }
+    params.format = Some(format.to_ascii_lowercase());
+    Ok(params)
+}
+
+fn parse_region(value: &str) -> Result<Option<Region>> {
+    if value == "full" {
+        return Ok(None);
+    }
+    if value == "square" {
+        return Ok(Some(Region::Square));
+    }
+    let (percent, coords) = match value.strip_prefix("pct:") {
+        Some(coords) => (true, coords),
+        None => (false, value),
+    };
+    let parts: Vec<f64> = coords
+        .split(',')
+        .map(|v| v.parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| bad_request(format!("invalid region '{}'", value)))?;
+    let [x, y, width, height] = parts[..] else {
+        return Err(bad_request(format!("invalid region '{}'", value)));
+    };
+    if percent {
+        Ok(Some(Region::Percent { x, y, width, height }))
+    } else if [x, y, width, height].iter().all(|v| v.fract() == 0.0) {
+        Ok(Some(Region::Pixels { x: x as i32, y: y as i32, width: width as i32, height: height as i32 }))
+    } else {
+        Err(bad_request(format!("invalid region '{}'", value)))
+    }
+}
+
+// 3.0 中不带 ^ 的尺寸不允许放大，2.1 没有这一限制
+fn parse_size(version: IiifVersion, value: &str, params: &mut ProcessingParams) -> Result<()> {
+    let (upscale, value) = match value.strip_prefix('^') {
+        Some(rest) => (true, rest),
+        None => (version == IiifVersion::V2, value),
+    };
+    params.only_if_larger = !upscale;
+    if value == "max" || value == "full" {
+        return Ok(());
+    }
+    let invalid = || bad_request(format!("invalid size '{}'", value));
+    if let Some(pct) = value.strip_prefix("pct:") {
+        let pct: f64 = pct.parse().map_err(|_| invalid())?;
+        if pct <= 0.0 || (!upscale && pct > 100.0) {
+            return Err(invalid());
+        }
+        params.scale_pct = Some(pct);
+        return Ok(());
+    }
+    let (fit_inside, value) = match value.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let (width, height) = value.split_once(',').ok_or_else(invalid)?;
+    let parse = |v: &str| -> Result<Option<i32>> {
+        if v.is_empty() {
+            return Ok(None);
+        }
+        match v.parse::<i32>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(invalid()),
+        }
+    };
+    params.width = parse(width)?;
+    params.height = parse(height)?;
+    // !w,h 必须同时给出宽高；"," 两边都为空也不合法
+    if (fit_inside && (params.width.is_none() || params.height.is_none())) || (params.width.is_none() && params.height.is_none()) {
+        return Err(invalid());
+    }
+    params.fit_inside = fit_inside;
+    Ok(())
+}
+
+fn parse_rotation(value: &str) -> Result<Option<Rotation>> {
+    let (mirror, degrees) = match value.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let degrees: f64 = degrees.parse().map_err(|_| bad_request(format!("invalid rotation '{}'", value)))?;
+    // 只支持 90° 的整数倍
+    if !(0.0..=360.0).contains(&degrees) || degrees % 90.0 != 0.0 {
+        return Err(bad_request(format!("unsupported rotation '{}', only multiples of 90 are supported", value)));
+    }
+    let degrees = degrees as i32 % 360;
+    if degrees == 0 && !mirror {
+        return Ok(None);
+    }
+    Ok(Some(Rotation { degrees, mirror }))
+}
+
+fn bad_request(message: String) -> anyhow::Error {
+    RequestError::bad_request(message).into()
+}
+
+// info.json 描述文档；瓦片按 TILE_SIZE 切分，缩放倍数一直到整张图能放进一个瓦片为止
+pub fn info_document(version: IiifVersion, id: &str, width: i32, height: i32) -> Value {
+    let mut scale_factors = vec![1];
+    while TILE_SIZE * scale_factors.last().unwrap() < width.max(height) {
+        scale_factors.push(scale_factors.last().unwrap() * 2);
+    }
+    let tiles = json!([{ "width": TILE_SIZE, "scaleFactors": scale_factors }]);
+    let formats: Vec<&str> = OUTPUT_FORMATS.iter().chain(OPTIONAL_OUTPUT_FORMATS).copied().collect();
+    match version {
+        IiifVersion::V2 => json!({
+            "@context": "http://iiif.io/api/image/2/context.json",
+            "@id": id,
+            "protocol": "http://iiif.io/api/image",
+            "width": width,
+            "height": height,
+            "tiles": tiles,
+            "profile": [
+                "http://iiif.io/api/image/2/level1.json",
+                {
+                    "formats": formats,
+                    "qualities": ["default", "color", "gray"],
+                    "supports": [
+                        "mirroring", "regionByPct", "regionByPx", "regionSquare", "rotationBy90s",
+                        "sizeAboveFull", "sizeByConfinedWh", "sizeByDistortedWh", "sizeByH", "sizeByPct", "sizeByW", "sizeByWh",
+                    ],
+                },
+            ],
+        }),
+        IiifVersion::V3 => json!({
+            "@context": "http://iiif.io/api/image/3/context.json",
+            "id": id,
+            "type": "ImageService3",
+            "protocol": "http://iiif.io/api/image",
+            "profile": "level1",
+            "width": width,
+            "height": height,
+            "tiles": tiles,
+            "extraFormats": formats.iter().filter(|f| **f != "jpg").collect::<Vec<_>>(),
+            "extraQualities": ["color", "gray"],
+            "extraFeatures": [
+                "mirroring", "regionByPct", "rotationBy90s", "sizeByConfinedWh", "sizeByPct", "sizeUpscaling",
+            ],
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 按 URL 中 {region}/{size}/{rotation}/{quality}.{format} 部分解析
+    fn parse(version: IiifVersion, path: &str) -> Result<ProcessingParams> {
+        let parts: Vec<&str> = path.split('/').collect();
+        let [region, size, rotation, quality_format] = parts[..] else {
+            panic!("expected four segments in '{}'", path);
+        };
+        parse_image_request(version, region, size, rotation, quality_format)
+    }
+
+    fn status(result: Result<ProcessingParams>) -> u16 {
+        result.unwrap_err().downcast_ref::<RequestError>().map(|e| e.status).unwrap_or_default()
+    }
+
+    #[test]
+    fn full_image_requests_map_to_plain_params() {
+        let params = parse(IiifVersion::V3, "full/max/0/default.jpg").unwrap();
+        assert_eq!((params.region, params.width, params.height, params.rotation), (None, None, None, None));
+        assert_eq!(params.format.as_deref(), Some("jpg"));
+        // 3.0 不带 ^ 时不放大，2.1 允许
+        assert!(params.only_if_larger);
+        assert!(!parse(IiifVersion::V2, "full/full/0/default.jpg").unwrap().only_if_larger);
+        assert!(!parse(IiifVersion::V3, "full/^max/0/default.jpg").unwrap().only_if_larger);
+    }
+
+    #[test]
+    fn regions_sizes_and_rotations_are_parsed() {
+        let params = parse(IiifVersion::V2, "10,20,300,200/150,/90/default.png").unwrap();
+        assert_eq!(params.region, Some(Region::Pixels { x: 10, y: 20, width: 300, height: 200 }));
+        assert_eq!((params.width, params.height), (Some(150), None));
+        assert_eq!(params.rotation, Some(Rotation { degrees: 90, mirror: false }));
+        assert_eq!(params.format.as_deref(), Some("png"));
+
+        let params = parse(IiifVersion::V3, "pct:25,25,50,50/!200,100/!180/gray.webp").unwrap();
+        assert_eq!(params.region, Some(Region::Percent { x: 25.0, y: 25.0, width: 50.0, height: 50.0 }));
+        assert_eq!((params.width, params.height, params.fit_inside), (Some(200), Some(100), true));
+        assert_eq!(params.rotation, Some(Rotation { degrees: 180, mirror: true }));
+        assert!(params.grayscale);
+
+        let params = parse(IiifVersion::V3, "square/,64/360/color.JPG").unwrap();
+        assert_eq!(params.region, Some(Region::Square));
+        assert_eq!((params.width, params.height), (None, Some(64)));
+        assert_eq!(params.rotation, None);
+        assert_eq!(params.format.as_deref(), Some("jpg"));
+
+        assert_eq!(parse(IiifVersion::V2, "full/pct:50/0/default.jpg").unwrap().scale_pct, Some(50.0));
+        assert_eq!(parse(IiifVersion::V3, "full/^pct:150/0/default.jpg").unwrap().scale_pct, Some(150.0));
+    }
+
+    #[test]
+    fn unsupported_syntax_is_a_bad_request() {
+        for (version, path) in [
+            (IiifVersion::V3, "1,2,3/max/0/default.jpg"),
+            (IiifVersion::V3, "1.5,2,3,4/max/0/default.jpg"),
+            (IiifVersion::V3, "full/,/0/default.jpg"),
+            (IiifVersion::V3, "full/!200,/0/default.jpg"),
+            (IiifVersion::V3, "full/0,100/0/default.jpg"),
+            (IiifVersion::V3, "full/pct:150/0/default.jpg"),
+            (IiifVersion::V2, "full/max/45/default.jpg"),
+            (IiifVersion::V2, "full/max/0/bitonal.jpg"),
+            (IiifVersion::V2, "full/max/0/default"),
+        ] {
+            assert_eq!(status(parse(version, path)), 400, "{}", path);
+        }
+    }
+
+    #[test]
+    fn info_document_lists_scale_factors_up_to_a_single_tile() {
+        let info = info_document(IiifVersion::V3, "https://example.com/iiif/3/b/a.jpg", 1000, 600);
+        assert_eq!(info["id"], "https://example.com/iiif/3/b/a.jpg");
+        assert_eq!(info["tiles"][0]["scaleFactors"], json!([1, 2, 4]));
+        let info = info_document(IiifVersion::V2, "id", 256, 100);
+        assert_eq!(info["@id"], "id");
+        assert_eq!(info["tiles"][0]["scaleFactors"], json!([1]));
+    }
+}
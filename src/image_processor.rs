@@ -1,35 +1,461 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use opencv::{
     prelude::*,
     imgcodecs::{imdecode, imencode, ImreadModes},
-    imgproc::{resize, InterpolationFlags},
-    core::{Mat, Size, Vector},
+    imgproc::{
+        calc_hist, cvt_color_def, resize, put_text, get_text_size, InterpolationFlags, COLOR_BGR2GRAY, COLOR_BGR2RGBA,
+        COLOR_BGRA2GRAY, COLOR_BGRA2RGBA, COLOR_GRAY2RGBA, FONT_HERSHEY_SIMPLEX, LINE_AA,
+    },
+    core::{
+        add_weighted, copy_make_border, flip, kmeans, randu, rotate, Mat, Point, Rect, Scalar, Size, TermCriteria, Vector, CV_16U, CV_8U,
+        CV_8UC3, BORDER_CONSTANT, KMEANS_PP_CENTERS, TermCriteria_COUNT, TermCriteria_EPS,
+    },
 };
-use serde::Deserialize;
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime},
     hash::{Hash, Hasher, DefaultHasher},
 };
 
 use crate::{
-    s3_client::S3Client,
-    cache::ImageCache,
+    s3_client::{S3Client, S3Object, S3Stream},
+    cache::{CacheStats, ImageCache},
+    cpu_pool::CpuPool,
+    svg::sanitize_svg,
+    error::RequestError,
+    format::{detect_format, is_progressive_jpeg, ImageFormat},
+    http_store::HttpStore,
+    logging::redact_key,
+    manifest::Manifest,
+    raw::is_raw_key,
 };
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ImageProcessingConfig {
     pub default_quality: i32,
+    // 质量取值范围：请求的 quality 以及按配置得到的默认质量都限制在 [quality_min, quality_max] 内
+    #[serde(default = "default_quality_min")]
+    pub quality_min: i32,
+    #[serde(default = "default_quality_max")]
+    pub quality_max: i32,
     pub max_width: i32,
     pub max_height: i32,
+    // 按输出格式覆盖 max_width/max_height，例如 avif: { max_width: 2048, max_height: 2048 }
+    #[serde(default)]
+    pub format_limits: HashMap<String, FormatLimits>,
+    // 编码失败（例如运行时缺少 WebP/AVIF 编码器）时改用的格式，可串成链，例如 { avif: webp, webp: jpg }
+    #[serde(default)]
+    pub encode_fallbacks: HashMap<String, String>,
+    // 允许请求的最小宽高，低于该值的请求返回 400；未配置时不限制
+    #[serde(default)]
+    pub min_width: Option<i32>,
+    #[serde(default)]
+    pub min_height: Option<i32>,
+    // 上传原图允许的最大字节数
+    #[serde(default = "default_max_source_bytes")]
+    pub max_source_bytes: u64,
+    #[serde(default)]
+    pub text_watermark: TextWatermarkConfig,
+    // 按输出格式追加的 imencode 参数，例如 jpg: { IMWRITE_JPEG_OPTIMIZE: 1 }
+    #[serde(default)]
+    pub encoder_params: HashMap<String, HashMap<String, i32>>,
+    // 同时在处理中的解码图片内存上限(MB)，超出时新请求返回 503；未配置时不限制
+    #[serde(default)]
+    pub max_inflight_memory_mb: Option<u64>,
+    // 系统可用内存低于该值(MB)时，需要解码的新请求返回 503，防止被 OOM kill；未配置时不检查
+    #[serde(default)]
+    pub min_free_memory_mb: Option<u64>,
+    // 命名的处理参数配置档，通过 `?profile=<name>` 选用，例如 thumb: { width: 150, height: 150 }
+    #[serde(default)]
+    pub profiles: HashMap<String, HashMap<String, String>>,
+    // 按 bucket 的默认处理参数，例如 uploads: { quality: 85 }；优先级低于请求参数和配置档
+    #[serde(default)]
+    pub bucket_defaults: HashMap<String, HashMap<String, String>>,
+    // 遵循 Save-Data: on 客户端提示：降低质量，未指定 format 时在客户端支持的情况下改用 AVIF/WebP
+    #[serde(default)]
+    pub honor_save_data: bool,
+    #[serde(default = "default_save_data_quality")]
+    pub save_data_quality: i32,
+    // 自适应质量：处理未指定 quality 的派生图时，用同一次解码缩放的结果额外编码这些质量并分别缓存
+    // （等同 quality=<值> 的请求）；开启 honor_save_data 且 format 明确时另外编码 Save-Data 版本
+    #[serde(default)]
+    pub quality_levels: Vec<i32>,
+    // 图片响应默认的 Content-Disposition：inline 或 attachment（强制下载，防止用户上传的内容被浏览器内联执行）；
+    // 未配置时不加该头。请求参数 download=1 总是使用 attachment
+    #[serde(default)]
+    pub content_disposition_default: Option<ContentDisposition>,
+    // 图片响应附带 X-Content-SHA256（响应体的 SHA-256，十六进制），每次响应都要计算，默认关闭
+    #[serde(default)]
+    pub content_sha256: bool,
+    // preview=1 时读取的原图开头字节数；渐进式 JPEG 的前几次扫描通常已落在其中
+    #[serde(default = "default_preview_bytes")]
+    pub preview_bytes: u64,
+    // 预生成派生图清单（JSON）的路径，未配置时全部实时处理
+    #[serde(default)]
+    pub manifest_path: Option<String>,
+    // 未指定 quality 时按输出像素数在 min/max 之间插值质量；未配置时使用 default_quality
+    #[serde(default)]
+    pub quality_scaling: Option<QualityScalingConfig>,
+    // 返回 SVG 前去掉脚本、事件属性和 javascript: 链接
+    #[serde(default = "default_svg_sanitize")]
+    pub svg_sanitize: bool,
+    // 缓存命中日志的采样比例（0.0-1.0），例如 0.01 表示每 100 次命中记录一次详细日志；未配置时每次命中都记录
+    #[serde(default)]
+    pub cache_hit_log_sample_rate: Option<f64>,
+    // 允许请求的 format 值（如 ["jpg", "webp", "auto"]），其余返回 400；未配置时不限制
+    #[serde(default)]
+    pub allowed_formats: Option<Vec<String>>,
+    // 未指定 interpolation 参数时，按放大/缩小分别选择的默认插值算法
+    #[serde(default)]
+    pub interpolation: InterpolationConfig,
+    // 原图不存在时的处理方式：not_found 返回 404，transparent_pixel 返回 200 和 1x1 透明 PNG
+    #[serde(default)]
+    pub on_missing: MissingPolicy,
+    // 严格模式：出现 QUERY_PARAMS 之外的参数（例如拼错的 widht）时返回 400，默认忽略
+    #[serde(default)]
+    pub strict_params: bool,
+    // OpenCV 解码/处理/编码使用的专用线程数；未配置时在 Tokio 工作线程上直接处理
+    #[serde(default)]
+    pub cpu_threads: Option<usize>,
+    // 每张原图最多同时缓存的派生图数量，超出时按 on_derivative_limit 处理；未配置时不限制
+    #[serde(default)]
+    pub max_derivatives_per_original: Option<usize>,
+    #[serde(default)]
+    pub on_derivative_limit: DerivativeLimitPolicy,
+    // 过载降级：处理中的请求数超过阈值时降低输出质量、跳过可选的耗时处理，代替排队超时；未配置时不降级
+    #[serde(default)]
+    pub degrade: Option<DegradeConfig>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DegradeConfig {
+    // 同时处理（缓存未命中）的请求数超过该值时，新的处理请求按降级参数输出
+    pub max_inflight: u64,
+    // 降级时的输出质量上限
+    #[serde(default = "default_degrade_quality")]
+    pub quality: i32,
+    // 降级时跳过 blurhash 和 PNG 无损优化
+    #[serde(default = "default_skip_optional")]
+    pub skip_optional: bool,
+    // 降级结果的缓存时间（秒），负载回落后尽快按正常质量重新生成
+    #[serde(default = "default_degrade_cache_ttl")]
+    pub cache_ttl_sec: u64,
+}
+
+fn default_degrade_quality() -> i32 {
+    60
+}
+
+fn default_save_data_quality() -> i32 {
+    50
+}
+
+fn default_preview_bytes() -> u64 {
+    64 * 1024
+}
+
+fn default_skip_optional() -> bool {
+    true
+}
+
+fn default_degrade_cache_ttl() -> u64 {
+    60
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingPolicy {
+    #[default]
+    NotFound,
+    TransparentPixel,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentDisposition {
+    Inline,
+    Attachment,
+}
+
+// 原图的派生图数量达到 max_derivatives_per_original 后，新尺寸请求的处理方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DerivativeLimitPolicy {
+    // 返回 400
+    #[default]
+    Reject,
+    // 返回已缓存的同格式派生图中尺寸最接近的一个，没有同格式的派生图时返回 400
+    Nearest,
+    // 移出最早生成的派生图，再正常处理
+    EvictOldest,
+}
+
+// 1x1 全透明 RGBA PNG
+const TRANSPARENT_PIXEL_PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0b, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x60, 0x00, 0x02, 0x00,
+    0x00, 0x05, 0x00, 0x01, 0xe9, 0xfa, 0xdc, 0xd8, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44,
+    0xae, 0x42, 0x60, 0x82,
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct FormatLimits {
+    // 未给出的一边沿用全局值
+    pub max_width: Option<i32>,
+    pub max_height: Option<i32>,
+}
+
+// 插值算法名：nearest / linear / cubic / area / lanczos
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct InterpolationConfig {
+    pub upscale: String,
+    pub downscale: String,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        Self {
+            upscale: "linear".to_string(),
+            downscale: "linear".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QualityScalingConfig {
+    pub min_quality: i32,
+    pub max_quality: i32,
+    // 输出像素数不超过 min_pixels 时使用 min_quality
+    pub min_pixels: u64,
+    // 输出像素数不低于 max_pixels 时使用 max_quality
+    pub max_pixels: u64,
+}
+
+impl QualityScalingConfig {
+    // 在两个断点之间按像素数线性插值
+    fn quality_for(&self, pixels: u64) -> i32 {
+        if pixels <= self.min_pixels || self.max_pixels <= self.min_pixels {
+            return self.min_quality;
+        }
+        if pixels >= self.max_pixels {
+            return self.max_quality;
+        }
+        let t = (pixels - self.min_pixels) as f64 / (self.max_pixels - self.min_pixels) as f64;
+        let quality = self.min_quality as f64 + t * (self.max_quality - self.min_quality) as f64;
+        quality.round() as i32
+    }
+}
+
+fn default_max_source_bytes() -> u64 {
+    20 * 1024 * 1024
+}
+
+fn default_svg_sanitize() -> bool {
+    true
+}
+
+// 文字水印（`text` 参数）的绘制配置
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TextWatermarkConfig {
+    // top-left / top-right / bottom-left / bottom-right / center
+    pub position: String,
+    pub font_scale: f64,
+    pub thickness: i32,
+    // 十六进制 RGB 颜色，如 "#FFFFFF"
+    pub color: String,
+    // 0.0 - 1.0，1.0 为不透明
+    pub opacity: f64,
+    pub margin: i32,
+    // 文字最大字符数，超出部分截断
+    pub max_length: usize,
+}
+
+impl Default for TextWatermarkConfig {
+    fn default() -> Self {
+        Self {
+            position: "bottom-right".to_string(),
+            font_scale: 1.0,
+            thickness: 2,
+            color: "#FFFFFF".to_string(),
+            opacity: 0.6,
+            margin: 10,
+            max_length: 64,
+        }
+    }
+}
+
+fn default_quality_min() -> i32 {
+    1
+}
+
+fn default_quality_max() -> i32 {
+    100
+}
+
+impl ImageProcessingConfig {
+    fn clamp_quality(&self, quality: i32) -> i32 {
+        quality.clamp(self.quality_min, self.quality_max)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ProcessingParams {
     pub width: Option<i32>,
     pub height: Option<i32>,
     pub quality: Option<i32>,
     pub format: Option<String>,
+    pub text: Option<String>,
+    // 目标宽高比（宽, 高），来自 `ar=16:9`
+    pub aspect_ratio: Option<(i32, i32)>,
+    // 像素画模式：最近邻插值 + 整数倍放大
+    pub pixel_art: bool,
+    // 是否计算 blurhash 占位图并通过 X-BlurHash 响应头返回
+    pub blurhash: bool,
+    // PNG 输出是否再做一次无损压缩优化（需要 png-optimize feature）
+    pub optimize: bool,
+    // format=auto 经 Accept 协商得到的格式；此时 quality 按 JPEG 的刻度映射到协商出的格式
+    pub auto_format: bool,
+    // passthrough=1：忽略其他参数，原样返回原图
+    pub passthrough: bool,
+    // 显式指定的插值算法，覆盖按缩放方向选择的默认值
+    pub interpolation: Option<InterpolationFlags>,
+    // 要求的输出方向，原图方向不符时旋转 90°
+    pub orient: Option<Orientation>,
+    // only_if=larger：原图不超过目标尺寸时不缩放（不放大）
+    pub only_if_larger: bool,
+    // Save-Data 请求头：未启用 honor_save_data 时为 None，否则为请求是否带了 Save-Data: on
+    pub save_data: Option<bool>,
+    // preview=1：渐进式 JPEG 只读取并解码开头的 preview_bytes 字节，得到低清晰度的快速预览
+    pub preview: bool,
+    // /tile/ 请求的深度缩放瓦片，设置时忽略 width/height/ar
+    pub tile: Option<Tile>,
+    // 以下来自 IIIF 请求：先裁剪区域，再缩放，最后镜像/旋转和转灰度
+    pub region: Option<Region>,
+    // 按原图（裁剪后）尺寸的百分比缩放，width/height 未设置时生效
+    pub scale_pct: Option<f64>,
+    // max_pixels=N：等比缩小到总像素数不超过 N，与宽高比无关；同时给出尺寸时作用于按尺寸计算的结果
+    pub max_pixels: Option<u64>,
+    // 同时给出 width 和 height 时保持宽高比缩放到框内，而不是拉伸到精确尺寸
+    pub fit_inside: bool,
+    pub rotation: Option<Rotation>,
+    pub grayscale: bool,
+    // square=N：输出 N×N；默认等比缩放到框内后用 background 补边，square_mode=crop 时居中裁剪为正方形
+    pub square: Option<i32>,
+    pub square_crop: bool,
+    // 补边颜色（bg=RRGGBB），未指定时支持透明的输出格式补透明，其余补白色
+    pub background: Option<(u8, u8, u8)>,
+    // 管理员通过 ttl= 指定的本次生成条目的缓存时间；不影响输出，因此不计入缓存键
+    pub ttl_override: Option<Duration>,
+    // download=1：以 attachment 返回；只影响响应头，不计入缓存键
+    pub download: bool,
+    // 过载降级时的质量上限（见 degrade）；降级结果只短时间缓存在原缓存键下，因此不计入缓存键
+    pub quality_cap: Option<i32>,
+}
+
+// Deep Zoom 瓦片：最高层级为原图尺寸（层级 = ceil(log2(长边))），每降一级缩小一半，每级按 TILE_SIZE 切分
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tile {
+    pub level: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+pub const TILE_SIZE: i32 = 256;
+
+// 要裁剪的区域：居中正方形、像素矩形或百分比矩形
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Square,
+    Pixels { x: i32, y: i32, width: i32, height: i32 },
+    Percent { x: f64, y: f64, width: f64, height: f64 },
+}
+
+impl Hash for Region {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Region::Square => 0u8.hash(state),
+            Region::Pixels { x, y, width, height } => (1u8, x, y, width, height).hash(state),
+            Region::Percent { x, y, width, height } => {
+                (2u8, x.to_bits(), y.to_bits(), width.to_bits(), height.to_bits()).hash(state)
+            }
+        }
+    }
+}
+
+// 顺时针旋转角度（0/90/180/270），mirror 表示旋转前先水平翻转
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rotation {
+    pub degrees: i32,
+    pub mirror: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+impl ProcessingParams {
+    // 没有任何处理参数时直接返回原图；blurhash、optimize 只作用于处理结果，不计入
+    pub fn is_empty(&self) -> bool {
+        self.width.is_none()
+            && self.height.is_none()
+            && self.quality.is_none()
+            && self.format.is_none()
+            && self.text.is_none()
+            && self.aspect_ratio.is_none()
+            && !self.pixel_art
+            && self.orient.is_none()
+            && self.tile.is_none()
+            && self.region.is_none()
+            && self.scale_pct.is_none()
+            && self.max_pixels.is_none()
+            && self.rotation.is_none()
+            && !self.grayscale
+            && self.square.is_none()
+            && !self.preview
+    }
+
+    // 影响了本次输出的请求头，响应据此给出 Vary，让共享缓存按这些请求头区分变体；
+    // 新增依赖请求头的协商（客户端提示等）时在这里一并登记
+    pub fn vary_headers(&self) -> Vec<&'static str> {
+        let mut headers = Vec::new();
+        // Save-Data 下未指定 format 的请求同样按 Accept 选格式
+        if self.auto_format || (self.saves_data() && self.format.is_none()) {
+            headers.push("Accept");
+        }
+        if self.save_data.is_some() {
+            headers.push("Save-Data");
+        }
+        headers
+    }
+
+    // 需要按 Save-Data 降低质量的请求；原图直出不受影响
+    fn saves_data(&self) -> bool {
+        self.save_data == Some(true) && !self.passthrough && !self.is_empty()
+    }
+}
+
+impl ProcessingParams {
+    // 缓存键中的输出格式：用决定响应 Content-Type 的格式，而不是请求原文；未指定 format 但需要处理时实际输出 jpg，
+    // 与 format=jpg 共用条目。format=auto 在此之前已协商为具体格式（见 normalize_params）
+    fn output_format_key(&self) -> Option<&str> {
+        match self.format.as_deref() {
+            None if self.is_empty() => None,
+            None => Some("jpg"),
+            Some(format) => Some(format),
+        }
+    }
 }
 
 // 实现 Hash trait 用于缓存键生成
@@ -38,7 +464,76 @@ impl std::hash::Hash for ProcessingParams {
         self.width.hash(state);
         self.height.hash(state);
         self.quality.hash(state);
-        self.format.hash(state);
+        self.output_format_key().hash(state);
+        self.text.hash(state);
+        self.aspect_ratio.hash(state);
+        self.pixel_art.hash(state);
+        self.blurhash.hash(state);
+        self.optimize.hash(state);
+        self.auto_format.hash(state);
+        self.passthrough.hash(state);
+        self.interpolation.map(|i| i as i32).hash(state);
+        self.orient.hash(state);
+        self.only_if_larger.hash(state);
+        self.preview.hash(state);
+        self.save_data.hash(state);
+        self.tile.hash(state);
+        self.region.hash(state);
+        self.scale_pct.map(f64::to_bits).hash(state);
+        self.max_pixels.hash(state);
+        self.fit_inside.hash(state);
+        self.rotation.hash(state);
+        self.grayscale.hash(state);
+        self.square.hash(state);
+        self.square_crop.hash(state);
+        self.background.hash(state);
+    }
+}
+
+/// 处理结果，同时也是缓存中保存的值
+#[derive(Debug, Clone)]
+pub struct ProcessedImage {
+    pub data: Vec<u8>,
+    pub content_type: String,
+    // 输出图片的尺寸；未经解码直接返回的数据（原图直出、预生成派生图）为 None
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    // 该条目的缓存时间，来自原图的 cache-ttl 元数据；None 时使用全局 TTL
+    pub ttl: Option<Duration>,
+    // 原图的过期时间（S3 Expires / 生命周期），缓存条目不会存活到该时间之后
+    pub expires_at: Option<SystemTime>,
+    // 请求 blurhash=1 时计算出的占位图编码
+    pub blurhash: Option<String>,
+    // data 为 zstd 压缩后的数据，只出现在缓存内部（见 cache.compress），读出时解压
+    pub compressed: bool,
+    // 写入缓存的时间，用于 Age 响应头；刚处理完、未经缓存返回的结果为 None
+    pub cached_at: Option<SystemTime>,
+    // 过载降级生成的结果对降级方式的描述（如 "quality=60"），通过 X-Image-Degraded 响应头返回
+    pub degraded: Option<String>,
+    // 原图的最后修改时间，缓存命中时据此回答 If-Modified-Since，不必再 head 原图
+    pub last_modified: Option<SystemTime>,
+}
+
+impl ProcessedImage {
+    // 未经 OpenCV 解码的数据，内容类型从字节识别
+    fn unprocessed(data: Vec<u8>) -> Self {
+        let content_type = detect_format(&data)
+            .map(|f| f.content_type())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        Self {
+            data,
+            content_type,
+            width: None,
+            height: None,
+            ttl: None,
+            expires_at: None,
+            blurhash: None,
+            compressed: false,
+            cached_at: None,
+            degraded: None,
+            last_modified: None,
+        }
     }
 }
 
@@ -47,203 +542,3819 @@ pub struct ImageProcessor {
     s3_client: S3Client,
     cache: ImageCache,
     config: ImageProcessingConfig,
+    // 当前在处理中的解码图片占用的字节数
+    inflight_memory: Arc<AtomicU64>,
+    // 当前在处理中（缓存未命中）的请求数，用于过载降级
+    inflight_jobs: Arc<AtomicU64>,
+    manifest: Option<Arc<Manifest>>,
+    // 启动时解析好的 encoder_params：输出格式 → [flag, value, ...]
+    extra_encoder_params: Arc<HashMap<String, Vec<i32>>>,
+    // 缓存命中计数，用于日志采样
+    cache_hits: Arc<AtomicU64>,
+    // 禁止访问的对象 key 正则（security.deny_patterns）
+    deny_list: Option<Arc<RegexSet>>,
+    // 启动时探测 AVIF 编码器是否可用，format=auto 只在可用时协商出 AVIF
+    avif_available: bool,
+    // 解析好的默认插值：(放大, 缩小)
+    default_interpolation: (InterpolationFlags, InterpolationFlags),
+    // security.require_public_objects：原图 key → 是否 public-read，短时间缓存以减少 ACL 请求
+    public_acl: Option<moka::future::Cache<String, bool>>,
+    // 正在处理（缓存未命中）的缓存键 → 该键的处理锁
+    flights: Flights,
+    free_memory_guard: Option<FreeMemoryGuard>,
+    cpu_pool: CpuPool,
+    // 原图 key → 已缓存的派生图，按生成先后排列；只在配置了 max_derivatives_per_original 时维护
+    derivatives: Arc<Mutex<HashMap<String, Vec<Derivative>>>>,
+    // http_source：该 bucket 下的原图从 HTTP(S) 读取
+    http_store: Option<Arc<HttpStore>>,
+}
+
+// 派生图索引中的一项；条目被缓存淘汰后在下次检查该原图时从索引中清除
+#[derive(Debug, Clone)]
+struct Derivative {
+    cache_key: String,
+    format: Option<String>,
+    width: Option<i32>,
+    height: Option<i32>,
+}
+
+// 解码前要求的最小系统可用内存(字节)和读取可用内存的探针
+#[derive(Clone)]
+struct FreeMemoryGuard {
+    min_free: u64,
+    probe: MemoryProbe,
+}
+
+impl std::fmt::Debug for FreeMemoryGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FreeMemoryGuard").field("min_free", &self.min_free).finish()
+    }
+}
+
+// 返回当前系统可用内存字节数，读取失败时返回 None（不拦截请求）
+pub type MemoryProbe = Arc<dyn Fn() -> Option<u64> + Send + Sync>;
+
+// 通过 sysinfo 读取系统可用内存
+pub fn system_available_memory() -> Option<u64> {
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    Some(system.available_memory()).filter(|&bytes| bytes > 0)
+}
+
+type Flights = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+// 持有某个缓存键的处理锁，drop 时释放并从表中移除（表中已换成新的锁时不动）
+struct Flight {
+    flights: Flights,
+    key: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        let mut flights = self.flights.lock().unwrap();
+        if flights.get(&self.key).is_some_and(|lock| Arc::ptr_eq(lock, &self.lock)) {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+// 解码并处理完、尚未编码的图片，可按多种格式分别编码
+struct PreparedImage {
+    img: Mat,
+    source_format: ImageFormat,
+    blurhash: Option<String>,
+    // format=auto-content 时按内容选出的格式
+    content_format: Option<&'static str>,
+    start_time: SystemTime,
+    _reservation: MemoryReservation,
+}
+
+impl PreparedImage {
+    fn output_format<'a>(&self, params: &'a ProcessingParams) -> &'a str {
+        self.content_format.unwrap_or_else(|| output_format_name(params, self.source_format))
+    }
+}
+
+// format=original 沿用原图格式，原图格式无法编码时回退为 JPEG
+fn output_format_name(params: &ProcessingParams, source_format: ImageFormat) -> &str {
+    match params.format.as_deref() {
+        Some("original") => source_format.output_name().unwrap_or("jpg"),
+        Some(format) => format,
+        None => "jpg",
+    }
+}
+
+enum Prepared {
+    // 无需 OpenCV 编码的结果（原图直出、SVG 原样返回、无法解码时的原图回退）
+    Finished(ProcessedImage),
+    Decoded(PreparedImage),
+}
+
+// 占用一个处理中请求的计数，drop 时归还；inflight 为计入本请求后的数量
+struct JobSlot {
+    counter: Arc<AtomicU64>,
+    inflight: u64,
+}
+
+impl JobSlot {
+    fn new(counter: Arc<AtomicU64>) -> Self {
+        let inflight = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        Self { counter, inflight }
+    }
+}
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// 一次解码占用的在途内存额度，drop 时归还
+struct MemoryReservation {
+    counter: Arc<AtomicU64>,
+    bytes: u64,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
 }
 
 impl ImageProcessor {
-    pub fn new(s3_client: S3Client, cache: ImageCache, config: ImageProcessingConfig) -> Self {
-        Self {
+    pub fn new(s3_client: S3Client, cache: ImageCache, config: ImageProcessingConfig) -> Result<Self> {
+        let extra_encoder_params = resolve_encoder_params(&config.encoder_params)?;
+        for (from, to) in &config.encode_fallbacks {
+            for format in [from, to] {
+                if !OUTPUT_FORMATS.contains(&format.as_str()) && !OPTIONAL_OUTPUT_FORMATS.contains(&format.as_str()) {
+                    anyhow::bail!("encode_fallbacks: unknown output format '{}'", format);
+                }
+            }
+        }
+        let default_interpolation = {
+            let resolve = |name: &str| {
+                parse_interpolation(name)
+                    .ok_or_else(|| anyhow::anyhow!("image_processing.interpolation: unknown algorithm '{}'", name))
+            };
+            (resolve(&config.interpolation.upscale)?, resolve(&config.interpolation.downscale)?)
+        };
+        let cpu_pool = CpuPool::new(config.cpu_threads)?;
+        Ok(Self {
             s3_client,
             cache,
             config,
-        }
+            inflight_memory: Arc::new(AtomicU64::new(0)),
+            inflight_jobs: Arc::new(AtomicU64::new(0)),
+            manifest: None,
+            extra_encoder_params: Arc::new(extra_encoder_params),
+            cache_hits: Arc::new(AtomicU64::new(0)),
+            deny_list: None,
+            avif_available: matches!(encode_probe("avif"), Ok(true)),
+            default_interpolation,
+            public_acl: None,
+            flights: Arc::default(),
+            free_memory_guard: None,
+            cpu_pool,
+            derivatives: Arc::default(),
+            http_store: None,
+        })
     }
 
-    pub async fn process_image_data(
-        &self,
-        image_data: Vec<u8>,
-        params: &ProcessingParams,
-    ) -> Result<(Vec<u8>, String)> {
-        let start_time = SystemTime::now();
-        println!("Starting image processing at {:?}", start_time);
+    pub fn with_free_memory_guard(mut self, min_free_bytes: u64, probe: MemoryProbe) -> Self {
+        println!("Shedding processing requests when available memory is below {} bytes", min_free_bytes);
+        self.free_memory_guard = Some(FreeMemoryGuard { min_free: min_free_bytes, probe });
+        self
+    }
 
-        // For images without processing parameters, return original data directly
-        if params.width.is_none() && params.height.is_none() && params.quality.is_none() && params.format.is_none() {
-            let duration = start_time.elapsed().unwrap_or_default();
-            println!("Processing completed (no changes) in {:?}", duration);
-            return Ok((image_data, "image/jpeg".to_string()));
+    // 系统可用内存低于阈值时拒绝需要解码的请求
+    fn ensure_free_memory(&self) -> Result<()> {
+        let Some(ref guard) = self.free_memory_guard else {
+            return Ok(());
+        };
+        match (guard.probe)() {
+            Some(available) if available < guard.min_free => {
+                eprintln!("Shedding request: available memory {} bytes is below {} bytes", available, guard.min_free);
+                Err(RequestError::service_unavailable("Insufficient free memory, try again later").into())
+            }
+            _ => Ok(()),
         }
-        
-        println!("Processing image with OpenCV: {:?}", params);
-        let load_start = SystemTime::now();
-        
-        // Load image with OpenCV
-        let img_buf = Vector::<u8>::from_iter(image_data.iter().copied());
-        let mut img = imdecode(&img_buf, ImreadModes::IMREAD_ANYCOLOR.into())?;
-        let load_duration = load_start.elapsed().unwrap_or_default();
-        println!("Image loading took: {:?}", load_duration);
+    }
 
-        let resize_start = SystemTime::now();
+    // 在 CPU 线程池上执行需要 OpenCV 的工作
+    async fn on_cpu_pool<R: Send + 'static>(
+        &self,
+        job: impl FnOnce(&ImageProcessor) -> Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let processor = self.clone();
+        self.cpu_pool.run(move || job(&processor)).await
+    }
 
-        // 调整尺寸
-        if let (Some(width), Some(height)) = (params.width, params.height) {
-            let target_width = width.min(self.config.max_width);
-            let target_height = height.min(self.config.max_height);
-            let mut resized_img = Mat::default();
-            resize(
-                &img,
-                &mut resized_img,
-                Size::new(target_width, target_height),
-                0.0,
-                0.0,
-                InterpolationFlags::INTER_LINEAR.into(),
-            )?;
-            img = resized_img;
-        } else if let Some(width) = params.width {
-            let aspect_ratio = img.rows() as f64 / img.cols() as f64;
-            let target_width = width.min(self.config.max_width);
-            let target_height = (target_width as f64 * aspect_ratio) as i32;
-            let mut resized_img = Mat::default();
-            resize(
-                &img,
-                &mut resized_img,
-                Size::new(target_width, target_height),
-                0.0,
-                0.0,
-                InterpolationFlags::INTER_LINEAR.into(),
-            )?;
-            img = resized_img;
-        } else if let Some(height) = params.height {
-            let aspect_ratio = img.cols() as f64 / img.rows() as f64;
-            let target_height = height.min(self.config.max_height);
-            let target_width = (target_height as f64 * aspect_ratio) as i32;
-            let mut resized_img = Mat::default();
-            resize(
-                &img,
-                &mut resized_img,
-                Size::new(target_width, target_height),
-                0.0,
-                0.0,
-                InterpolationFlags::INTER_LINEAR.into(),
-            )?;
-            img = resized_img;
+    // 等待并取得缓存键的处理锁；同键的并发请求依次取得，前一个写入缓存后后面的直接命中
+    async fn join_flight(&self, cache_key: &str) -> Flight {
+        let lock = self.flights.lock().unwrap().entry(cache_key.to_string()).or_default().clone();
+        let guard = lock.clone().lock_owned().await;
+        Flight {
+            flights: self.flights.clone(),
+            key: cache_key.to_string(),
+            lock,
+            _guard: guard,
         }
+    }
 
-        let resize_duration = resize_start.elapsed().unwrap_or_default();
-        println!("Image resizing took: {:?}", resize_duration);
-
-        // 确定输出格式和内容类型
-        let (extension, content_type, quality_flag) = match params.format.as_deref().unwrap_or("jpg") {
-            "png" => (".png", "image/png", 16), // ImwriteFlags::PNG_COMPRESSION equivalent
-            "webp" => (".webp", "image/webp", 64), // ImwriteFlags::WEBP_QUALITY equivalent
-            _ => (".jpg", "image/jpeg", 1), // ImwriteFlags::JPEG_QUALITY equivalent
-        };
+    pub fn with_manifest(mut self, manifest: Manifest) -> Self {
+        self.manifest = Some(Arc::new(manifest));
+        self
+    }
 
-        // 编码图片
-        let encode_start = SystemTime::now();
-        let mut buf = Vector::new();
-        let quality = params.quality.unwrap_or(self.config.default_quality);
-        let params_vec = Vector::from_slice(&[quality_flag, quality]);
-        imencode(extension, &img, &mut buf, &params_vec)?;
-        let encoded_data = buf.to_vec();
-        let encode_duration = encode_start.elapsed().unwrap_or_default();
-        println!("Image encoding took: {:?}", encode_duration);
+    pub fn with_http_source(mut self, store: HttpStore) -> Self {
+        self.http_store = Some(Arc::new(store));
+        self
+    }
 
-        let duration = start_time.elapsed().unwrap_or_default();
-        println!("Processing completed (full pipeline) in {:?}", duration);
+    fn http_source(&self, key: &str) -> Option<&HttpStore> {
+        self.http_store.as_deref().filter(|store| store.handles(key))
+    }
 
-        Ok((encoded_data, content_type.to_string()))
+    // 读取原图：http_source 的 bucket 从 HTTP(S) 读取，其余从 S3
+    async fn get_original(&self, key: &str) -> Result<S3Object> {
+        match self.http_source(key) {
+            Some(store) => store.get_object(key).await,
+            None => self.s3_client.get_object(key).await,
+        }
     }
 
-    pub async fn get_or_process_image(
-        &self,
-        image_key: String,
-        params: ProcessingParams,
-    ) -> Result<(Vec<u8>, String, String)> {
-        let overall_start = SystemTime::now();
-        
-        // 使用更高效的缓存键生成方式
-        let mut hasher = DefaultHasher::new();
-        image_key.hash(&mut hasher);
-        params.width.hash(&mut hasher);
-        params.height.hash(&mut hasher);
-        params.quality.hash(&mut hasher);
-        if let Some(ref format) = params.format {
-            format.hash(&mut hasher);
-        }
-        let cache_key = hasher.finish().to_string();
-        
-        // 检查缓存
-        let cache_check_start = SystemTime::now();
-        if let Some(cached_data) = self.cache.get(&cache_key).await {
-            let cache_duration = cache_check_start.elapsed().unwrap_or_default();
-            println!("Cache check took: {:?}", cache_duration);
-            
-            // 确定缓存数据的内容类型
-            let content_type = self.determine_content_type(&params);
-            let overall_duration = overall_start.elapsed().unwrap_or_default();
-            println!("Request served from cache in {:?}", overall_duration);
-            return Ok((cached_data, content_type, "cache".to_string()));
+    async fn get_original_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+        match self.http_source(key) {
+            Some(store) => store.get_range(key, start, end).await,
+            None => self.s3_client.get_range(key, start, end).await,
         }
-        let cache_duration = cache_check_start.elapsed().unwrap_or_default();
-        println!("Cache check took: {:?}", cache_duration);
+    }
 
-        // 获取原始图片 (同时获取对象并检查是否存在)
-        let s3_fetch_start = SystemTime::now();
-        let original_data = match self.s3_client.get_object(&image_key).await {
-            Ok(data) => data,
-            Err(e) => {
-                eprintln!("Object '{}' does not exist in S3 or cannot be accessed: {}", image_key, e);
-                return Err(anyhow::anyhow!("Failed to get original image {}: {}", image_key, e));
+    // format=auto：按 Accept 依次选择 AVIF、WebP，都不支持时沿用原图格式
+    pub fn resolve_auto_format(&self, params: &mut ProcessingParams, accept: Option<&str>) {
+        let accept = accept.unwrap_or_default();
+        if params.saves_data() {
+            let quality = self.config.save_data_quality;
+            params.quality_cap = Some(params.quality_cap.map_or(quality, |cap| cap.min(quality)));
+            // 客户端不支持更小的格式时保持默认输出（jpg），而不是像 format=auto 那样沿用原图格式
+            if params.format.is_none() {
+                let smaller = if self.avif_available && accepts_media_type(accept, "image/avif") {
+                    Some("avif")
+                } else if accepts_media_type(accept, "image/webp") {
+                    Some("webp")
+                } else {
+                    None
+                };
+                if let Some(format) = smaller {
+                    params.format = Some(format.to_string());
+                    params.auto_format = true;
+                }
             }
+        }
+        if params.format.as_deref() != Some("auto") {
+            return;
+        }
+        let format = if self.avif_available && accepts_media_type(accept, "image/avif") {
+            "avif"
+        } else if accepts_media_type(accept, "image/webp") {
+            "webp"
+        } else {
+            "original"
         };
-        let s3_duration = s3_fetch_start.elapsed().unwrap_or_default();
-        println!("S3 fetch took: {:?}", s3_duration);
+        params.format = Some(format.to_string());
+        params.auto_format = true;
+    }
 
-        // 处理图片
-        let process_start = SystemTime::now();
-        let (processed_data, content_type) = 
-            self.process_image_data(original_data, &params).await?;
-        let process_duration = process_start.elapsed().unwrap_or_default();
-        println!("Image processing took: {:?}", process_duration);
+    // 匹配任一正则的 key（bucket/key）一律返回 403，且不访问 S3
+    pub fn with_deny_patterns(mut self, patterns: &[String]) -> Result<Self> {
+        if !patterns.is_empty() {
+            let set = RegexSet::new(patterns).context("Invalid security.deny_patterns")?;
+            self.deny_list = Some(Arc::new(set));
+        }
+        Ok(self)
+    }
 
-        // 更新缓存
-        let cache_update_start = SystemTime::now();
-        self.cache.insert(cache_key, processed_data.clone()).await;
-        let cache_update_duration = cache_update_start.elapsed().unwrap_or_default();
-        println!("Cache update took: {:?}", cache_update_duration);
+    // 只提供 ACL 为 public-read 的对象，ACL 结果缓存 ttl
+    pub fn with_public_object_check(mut self, ttl: Duration) -> Self {
+        self.public_acl = Some(
+            moka::future::Cache::builder()
+                .max_capacity(100_000)
+                .time_to_live(ttl)
+                .build(),
+        );
+        self
+    }
 
-        let overall_duration = overall_start.elapsed().unwrap_or_default();
-        println!("Request processed and cached in {:?}", overall_duration);
+    // 开启 require_public_objects 时，非 public-read 的对象返回 403；ACL 查询失败时同样拒绝
+    async fn ensure_object_public(&self, image_key: &str) -> Result<()> {
+        let Some(ref acl_cache) = self.public_acl else {
+            return Ok(());
+        };
+        // ACL 只存在于 S3 对象上，HTTP 来源由 allowed_hosts 控制
+        if self.http_source(image_key).is_some() {
+            return Ok(());
+        }
+        let public = match acl_cache.get(image_key) {
+            Some(public) => public,
+            None => {
+                let public = match self.s3_client.is_public_read(image_key).await {
+                    Ok(public) => public,
+                    Err(e) => {
+                        eprintln!("ACL check failed for '{}': {}", redact_key(image_key), e);
+                        false
+                    }
+                };
+                acl_cache.insert(image_key.to_string(), public).await;
+                public
+            }
+        };
+        if !public {
+            eprintln!("Rejected request for non-public object '{}'", redact_key(image_key));
+            return Err(RequestError::forbidden("Forbidden").into());
+        }
+        Ok(())
+    }
 
-        Ok((processed_data, content_type, "newly_processed".to_string()))
+    // 访问控制：deny_patterns 与 require_public_objects
+    pub async fn check_access(&self, image_key: &str) -> Result<()> {
+        self.ensure_key_allowed(image_key)?;
+        self.ensure_object_public(image_key).await
     }
-    
-    pub fn get_cache_stats(&self) -> String {
-        self.cache.get_stats().to_string()
+
+    fn ensure_key_allowed(&self, image_key: &str) -> Result<()> {
+        if self.deny_list.as_ref().is_some_and(|set| set.is_match(image_key)) {
+            eprintln!("Rejected request for denied key '{}'", redact_key(image_key));
+            return Err(RequestError::forbidden("Forbidden").into());
+        }
+        Ok(())
     }
 
-    // 新增：清空缓存（供 /clear-cache 路由调用）
-    pub async fn clear_cache(&self) {
-        self.cache.clear().await;
+    // 原图已有的派生图达到上限时按 on_derivative_limit 处理；Nearest 找到替代结果时返回它
+    async fn enforce_derivative_limit(
+        &self,
+        image_key: &str,
+        cache_key: &str,
+        params: &ProcessingParams,
+    ) -> Result<Option<ProcessedImage>> {
+        let Some(limit) = self.config.max_derivatives_per_original else {
+            return Ok(None);
+        };
+        let existing = {
+            let mut derivatives = self.derivatives.lock().unwrap();
+            let Some(list) = derivatives.get_mut(image_key) else {
+                return Ok(None);
+            };
+            list.retain(|d| d.cache_key != cache_key && self.cache.contains(&d.cache_key));
+            if list.is_empty() {
+                derivatives.remove(image_key);
+                return Ok(None);
+            }
+            if list.len() < limit {
+                return Ok(None);
+            }
+            list.clone()
+        };
+        let over_limit = || {
+            anyhow::Error::from(RequestError::bad_request(format!(
+                "Original '{}' already has {} cached derivatives (limit {}), request an existing size",
+                redact_key(image_key),
+                existing.len(),
+                limit
+            )))
+        };
+        match self.config.on_derivative_limit {
+            DerivativeLimitPolicy::Reject => Err(over_limit()),
+            DerivativeLimitPolicy::EvictOldest => {
+                let oldest = &existing[0];
+                self.cache.remove(&oldest.cache_key).await;
+                if let Some(list) = self.derivatives.lock().unwrap().get_mut(image_key) {
+                    list.retain(|d| d.cache_key != oldest.cache_key);
+                }
+                println!("Derivative limit reached for '{}', evicted oldest derivative {}", redact_key(image_key), oldest.cache_key);
+                Ok(None)
+            }
+            DerivativeLimitPolicy::Nearest => {
+                // 只比较请求中给出的边
+                let distance = |d: &Derivative| {
+                    let side = |requested: Option<i32>, actual: Option<i32>| match (requested, actual) {
+                        (Some(requested), Some(actual)) => (requested - actual).abs(),
+                        _ => 0,
+                    };
+                    side(params.width, d.width) + side(params.height, d.height)
+                };
+                let nearest = existing.iter().filter(|d| d.format == params.format).min_by_key(|d| distance(d));
+                let Some(nearest) = nearest else {
+                    return Err(over_limit());
+                };
+                match self.cache.get(&nearest.cache_key).await {
+                    Some(image) => {
+                        println!(
+                            "Derivative limit reached for '{}', serving nearest derivative {:?}x{:?}",
+                            redact_key(image_key), nearest.width, nearest.height
+                        );
+                        Ok(Some(image))
+                    }
+                    None => Err(over_limit()),
+                }
+            }
+        }
     }
 
-    // 新增：确定内容类型的方法
-    fn determine_content_type(&self, params: &ProcessingParams) -> String {
-        match params.format.as_deref().unwrap_or("jpg") {
-            "png" => "image/png".to_string(),
-            "webp" => "image/webp".to_string(),
-            _ => "image/jpeg".to_string(),
+    fn record_derivative(&self, image_key: &str, cache_key: &str, params: &ProcessingParams, image: &ProcessedImage) {
+        if self.config.max_derivatives_per_original.is_none() {
+            return;
         }
+        let mut derivatives = self.derivatives.lock().unwrap();
+        let list = derivatives.entry(image_key.to_string()).or_default();
+        list.retain(|d| d.cache_key != cache_key);
+        list.push(Derivative {
+            cache_key: cache_key.to_string(),
+            format: params.format.clone(),
+            width: image.width,
+            height: image.height,
+        });
     }
-}
 
-pub fn parse_query_params(params: HashMap<String, String>) -> ProcessingParams {
-    ProcessingParams {
-        width: params.get("width").and_then(|w| w.parse().ok()),
-        height: params.get("height").and_then(|h| h.parse().ok()),
-        quality: params.get("quality")
-            .and_then(|q| q.parse().ok())
-            .map(|q: i32| q.clamp(1, 100)),
-        format: params.get("format").cloned(),
+    // preview=1 的原图：渐进式 JPEG 只读取开头的 preview_bytes 字节，第二项为 true 表示数据是截断的；
+    // 基线 JPEG 截断后只能解出上半部分，其他格式同样无法部分解码，这些情况读取完整原图
+    async fn fetch_preview_source(&self, image_key: &str) -> Result<(S3Object, bool)> {
+        let limit = self.config.preview_bytes;
+        let mut head = self.get_original_range(image_key, 0, limit - 1).await?;
+        if (head.len() as u64) < limit || !is_progressive_jpeg(&head) {
+            println!("Preview for '{}' needs the full original (small or not a progressive JPEG)", redact_key(image_key));
+            return Ok((self.get_original(image_key).await?, false));
+        }
+        // 补上 EOI，解码器把已读到的扫描当作完整图片输出
+        head.extend_from_slice(&[0xFF, 0xD9]);
+        println!("Decoding preview for '{}' from the first {} bytes", redact_key(image_key), limit);
+        let object = S3Object { data: head, metadata: HashMap::new(), expires_at: None, last_modified: None };
+        Ok((object, true))
+    }
+
+    // 为解码后的图片申请在途内存额度，超出 max_inflight_memory_mb 时拒绝
+    fn reserve_memory(&self, bytes: u64) -> Result<MemoryReservation> {
+        let limit = self.config.max_inflight_memory_mb.map(|mb| mb * 1024 * 1024);
+        self.inflight_memory
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| match limit {
+                Some(limit) if current + bytes > limit => None,
+                _ => Some(current + bytes),
+            })
+            .map_err(|current| {
+                RequestError::service_unavailable(format!(
+                    "In-flight image memory limit exceeded ({} bytes in use, {} bytes requested)",
+                    current, bytes
+                ))
+            })?;
+        Ok(MemoryReservation {
+            counter: self.inflight_memory.clone(),
+            bytes,
+        })
     }
-}
\ No newline at end of file
+
+    pub async fn process_image_data(
+        &self,
+        image_data: Vec<u8>,
+        params: &ProcessingParams,
+    ) -> Result<ProcessedImage> {
+        self.process_source(image_data, params, false).await
+    }
+
+    // raw 为 true 时（原图 key 是相机 RAW 扩展名）不看魔数，按 RAW 解码
+    async fn process_source(
+        &self,
+        image_data: Vec<u8>,
+        params: &ProcessingParams,
+        raw: bool,
+    ) -> Result<ProcessedImage> {
+        let params = params.clone();
+        self.on_cpu_pool(move |processor| match processor.prepare_source(image_data, &params, raw)? {
+            Prepared::Finished(image) => Ok(image),
+            Prepared::Decoded(prepared) => {
+                let image = processor.encode_prepared(&prepared, &params, prepared.output_format(&params))?;
+                let duration = prepared.start_time.elapsed().unwrap_or_default();
+                println!("Processing completed (full pipeline) in {:?}", duration);
+                Ok(image)
+            }
+        })
+        .await
+    }
+
+    // 与 params 只差输出质量的其他派生图（见 quality_levels），尚未缓存的才需要编码
+    async fn quality_siblings(&self, image_key: &str, params: &ProcessingParams) -> Vec<(String, ProcessingParams)> {
+        let mut siblings = Vec::new();
+        if params.quality.is_none() {
+            for &level in &self.config.quality_levels {
+                let quality = self.config.clamp_quality(level);
+                siblings.push(ProcessingParams { quality: Some(quality), ..params.clone() });
+            }
+        }
+        // 未指定 format 时 Save-Data 请求会按 Accept 改用其他格式，只有格式明确时缓存键才对得上
+        let explicit_format = params.format.is_some() && !params.auto_format;
+        if params.save_data == Some(false) && explicit_format {
+            let quality = self.config.save_data_quality;
+            let quality_cap = Some(params.quality_cap.map_or(quality, |cap| cap.min(quality)));
+            siblings.push(ProcessingParams { save_data: Some(true), quality_cap, ..params.clone() });
+        }
+
+        let mut pending = Vec::new();
+        for sibling in siblings {
+            let cache_key = self.cache_key(image_key, &sibling);
+            if !self.cache.contains(&cache_key) && !pending.iter().any(|(key, _)| *key == cache_key) {
+                pending.push((cache_key, sibling));
+            }
+        }
+        pending
+    }
+
+    // 原图只解码、缩放一次，请求的质量与 quality_siblings 在线程池上并行编码；其他质量直接写入缓存，返回请求的结果
+    // 其他质量是同一派生图的不同编码，不计入 max_derivatives_per_original
+    async fn process_with_quality_levels(
+        &self,
+        image_key: &str,
+        image_data: Vec<u8>,
+        params: &ProcessingParams,
+        ttl: Option<Duration>,
+        expires_at: Option<SystemTime>,
+        last_modified: Option<SystemTime>,
+    ) -> Result<ProcessedImage> {
+        let raw = is_raw_key(image_key);
+        let siblings = self.quality_siblings(image_key, params).await;
+        if siblings.is_empty() {
+            return self.process_source(image_data, params, raw).await;
+        }
+        let prepare_params = params.clone();
+        let prepared = match self.on_cpu_pool(move |processor| processor.prepare_source(image_data, &prepare_params, raw)).await? {
+            Prepared::Finished(image) => return Ok(image),
+            Prepared::Decoded(prepared) => Arc::new(prepared),
+        };
+        let start_time = prepared.start_time;
+        let encodes = std::iter::once(params).chain(siblings.iter().map(|(_, sibling)| sibling)).map(|variant| {
+            let (prepared, variant) = (prepared.clone(), variant.clone());
+            self.on_cpu_pool(move |processor| processor.encode_prepared(&prepared, &variant, prepared.output_format(&variant)))
+        });
+        let mut images = futures::future::try_join_all(encodes).await?;
+        let levels = images.len();
+        let image = images.remove(0);
+        for ((cache_key, sibling), mut encoded) in siblings.into_iter().zip(images) {
+            encoded.ttl = sibling.ttl_override.or(ttl);
+            encoded.expires_at = expires_at;
+            encoded.last_modified = last_modified;
+            self.cache.insert(cache_key, encoded).await;
+        }
+        println!("Processing completed with {} quality levels in {:?}", levels, start_time.elapsed().unwrap_or_default());
+        Ok(image)
+    }
+
+    // 处理取回的原图；处理中的请求超过 degrade.max_inflight 时按降级参数处理
+    async fn process_original(&self, image_key: &str, original: S3Object, partial: bool, params: &ProcessingParams) -> Result<ProcessedImage> {
+        let process_start = SystemTime::now();
+        let ttl = original.cache_ttl();
+        let expires_at = original.expires_at;
+        // 预览只按 Range 读取了原图开头，没有最后修改时间，补一次 head
+        let last_modified = match original.last_modified {
+            None if partial => self.source_last_modified(image_key).await,
+            last_modified => last_modified,
+        };
+        let job = JobSlot::new(self.inflight_jobs.clone());
+        let degrade = self.degrade_under_load(params, job.inflight);
+        let mut processed = if params.passthrough {
+            self.passthrough_image(original.data)?
+        } else if let Some(degrade) = degrade {
+            let (degraded_params, description) = degraded_params(params, degrade);
+            println!(
+                "Degrading output for '{}' under load ({} in-flight, threshold {}): {}",
+                redact_key(image_key), job.inflight, degrade.max_inflight, description
+            );
+            let mut image = self.process_source(original.data, &degraded_params, is_raw_key(image_key)).await?;
+            image.degraded = Some(description);
+            image
+        } else if !partial {
+            self.process_with_quality_levels(image_key, original.data, params, ttl, expires_at, last_modified).await?
+        } else {
+            self.process_source(original.data, params, is_raw_key(image_key)).await?
+        };
+        drop(job);
+        processed.ttl = params.ttl_override.or(ttl);
+        if let Some(degrade) = degrade {
+            processed.ttl = degraded_ttl(processed.ttl, degrade);
+        }
+        processed.expires_at = expires_at;
+        processed.last_modified = last_modified;
+        let process_duration = process_start.elapsed().unwrap_or_default();
+        println!("Image processing took: {:?}", process_duration);
+        Ok(processed)
+    }
+
+    // 降级结果写入单独的缓存键，负载下降后同样参数的请求仍会重新生成完整质量的结果；降级结果不计入派生图
+    async fn store_processed(&self, image_key: &str, cache_key: String, params: &ProcessingParams, processed: ProcessedImage) {
+        if processed.degraded.is_some() {
+            self.cache.insert(degraded_cache_key(&cache_key), processed).await;
+            return;
+        }
+        if !params.passthrough {
+            self.record_derivative(image_key, &cache_key, params, &processed);
+        }
+        self.cache.insert(cache_key, processed).await;
+    }
+
+    // 计入本请求后处理中的请求数为 inflight 时是否降级；原图直出不降级
+    fn degrade_under_load(&self, params: &ProcessingParams, inflight: u64) -> Option<&DegradeConfig> {
+        self.config.degrade.as_ref().filter(|d| !params.passthrough && inflight > d.max_inflight)
+    }
+
+    // passthrough=1：原样返回原图字节；SVG 可能包含脚本，开启 svg_sanitize 时同样先清理
+    fn passthrough_image(&self, data: Vec<u8>) -> Result<ProcessedImage> {
+        if self.config.svg_sanitize && detect_format(&data) == Some(ImageFormat::Svg) {
+            return Ok(ProcessedImage::unprocessed(sanitize_svg(&data)?));
+        }
+        Ok(ProcessedImage::unprocessed(data))
+    }
+
+    // 解码并完成裁剪、缩放、水印等处理，得到待编码的图片；无需解码的情况直接给出结果
+    fn prepare_source(
+        &self,
+        image_data: Vec<u8>,
+        params: &ProcessingParams,
+        raw: bool,
+    ) -> Result<Prepared> {
+        let start_time = SystemTime::now();
+        println!("Starting image processing at {:?}", start_time);
+
+        // 非图片内容直接拒绝，避免交给 OpenCV 解码
+        let source_format = if raw {
+            ImageFormat::Raw
+        } else {
+            detect_format(&image_data).ok_or_else(|| {
+                RequestError::unsupported_media_type("Source object is not a supported image")
+            })?
+        };
+
+        // SVG 不经过 OpenCV 解码：原样（清理后）返回，或栅格化为 PNG 后继续处理
+        let (image_data, source_format) = if source_format == ImageFormat::Svg {
+            let svg = if self.config.svg_sanitize {
+                sanitize_svg(&image_data)?
+            } else {
+                image_data
+            };
+            match self.rasterize_svg_source(&svg, params)? {
+                Some(png) => (png, ImageFormat::Png),
+                None => {
+                    println!("Serving SVG source without rasterization");
+                    return Ok(Prepared::Finished(ProcessedImage::unprocessed(svg)));
+                }
+            }
+        } else {
+            (image_data, source_format)
+        };
+
+        // For images without processing parameters, return original data directly
+        if params.is_empty() {
+            let duration = start_time.elapsed().unwrap_or_default();
+            println!("Processing completed (no changes) in {:?}", duration);
+            let mut image = ProcessedImage::unprocessed(image_data);
+            if params.blurhash {
+                let img_buf = Vector::<u8>::from_iter(image.data.iter().copied());
+                image.blurhash = decode_image(&img_buf)
+                    .ok_or_else(|| anyhow::anyhow!("source could not be decoded"))
+                    .and_then(|img| compute_blurhash(&img))
+                    .map_err(|e| eprintln!("Warning: blurhash computation failed: {}", e))
+                    .ok();
+            }
+            return Ok(Prepared::Finished(image));
+        }
+        
+        self.ensure_free_memory()?;
+        println!("Processing image with OpenCV: {:?}", params);
+        let load_start = SystemTime::now();
+        
+        // Load image with OpenCV（RAW 由 rawloader 解码）
+        let decoded = if source_format == ImageFormat::Raw {
+            Some(self.decode_raw_source(&image_data)?)
+        } else {
+            let img_buf = Vector::<u8>::from_iter(image_data.iter().copied());
+            decode_image(&img_buf)
+        };
+        let mut img = match decoded {
+            Some(img) => img,
+            None => {
+                // OpenCV 无法解码（例如缺少对应的解码器）时，若无需缩小也无需转换格式，直接返回原图；
+                // 预览读到的只是原图开头，不能当作原图返回
+                let fits = original_fits_request(&image_data, source_format, params).filter(|_| !params.preview);
+                if let Some((width, height)) = fits {
+                    println!("OpenCV cannot decode {} source, returning original {}x{}", source_format.content_type(), width, height);
+                    let mut image = ProcessedImage::unprocessed(image_data);
+                    image.width = Some(width);
+                    image.height = Some(height);
+                    return Ok(Prepared::Finished(image));
+                }
+                anyhow::bail!("Failed to decode {} source image", source_format.content_type());
+            }
+        };
+        let load_duration = load_start.elapsed().unwrap_or_default();
+        println!("Image loading took: {:?}", load_duration);
+
+        // 解码后的内存占用 = rows × cols × channels × depth，处理结束前一直计入在途内存
+        let decoded_bytes = (img.total() * img.elem_size()?) as u64;
+        let reservation = self.reserve_memory(decoded_bytes)?;
+
+        // 方向与 orient 不符（横图要求竖图或反之）时顺时针旋转 90°，正方形不旋转；之后的裁剪和缩放基于旋转后的尺寸
+        if let Some(orient) = params.orient {
+            let mismatched = match orient {
+                Orientation::Portrait => img.cols() > img.rows(),
+                Orientation::Landscape => img.rows() > img.cols(),
+            };
+            if mismatched {
+                let mut rotated = Mat::default();
+                rotate(&img, &mut rotated, opencv::core::ROTATE_90_CLOCKWISE)?;
+                img = rotated;
+            }
+        }
+
+        if let Some(region) = params.region {
+            let rect = region_rect(img.cols(), img.rows(), region)?;
+            img = Mat::roi(&img, rect)?.try_clone()?;
+        }
+
+        if let Some(tile) = params.tile {
+            img = extract_tile(&img, tile)?;
+        }
+
+        // 按宽高比居中裁剪，之后的缩放基于裁剪结果
+        if let Some((ar_width, ar_height)) = params.aspect_ratio {
+            let rect = aspect_crop_rect(img.cols(), img.rows(), ar_width, ar_height);
+            img = Mat::roi(&img, rect)?.try_clone()?;
+        }
+
+        let resize_start = SystemTime::now();
+
+        // 调整尺寸
+        let format = output_format_name(params, source_format);
+        // only_if=larger 且原图两边都不超过目标尺寸时保持原尺寸
+        let target = self
+            .target_size(img.cols(), img.rows(), params, format)
+            .filter(|target| !params.only_if_larger || img.cols() > target.width || img.rows() > target.height);
+        if let Some(target) = target {
+            let interpolation = self.interpolation_for(params, Size::new(img.cols(), img.rows()), target);
+            let mut resized_img = Mat::default();
+            // 缩放失败（例如 OpenCV 对个别色彩空间的内部错误）时降级为按原尺寸编码，而不是让整个请求失败；
+            // 解码失败仍然是硬错误
+            match resize(
+                &img,
+                &mut resized_img,
+                target,
+                0.0,
+                0.0,
+                interpolation.into(),
+            ) {
+                Ok(()) => img = resized_img,
+                Err(e) => eprintln!(
+                    "Warning: resize to {}x{} failed, encoding at original {}x{}: {}",
+                    target.width, target.height, img.cols(), img.rows(), e
+                ),
+            }
+        }
+
+        let resize_duration = resize_start.elapsed().unwrap_or_default();
+        println!("Image resizing took: {:?}", resize_duration);
+
+        // square 补边模式：缩放后长边已是 N，把短边两侧补齐
+        if params.square.is_some() && !params.square_crop {
+            let transparent = params.background.is_none() && matches!(format, "png" | "webp" | "avif");
+            img = pad_to_square(&img, params.background, transparent)?;
+        }
+
+        if let Some(rotation) = params.rotation {
+            img = rotate_image(&img, rotation)?;
+        }
+
+        if params.grayscale && img.channels() > 1 {
+            let code = if img.channels() == 4 { COLOR_BGRA2GRAY } else { COLOR_BGR2GRAY };
+            let mut gray = Mat::default();
+            cvt_color_def(&img, &mut gray, code)?;
+            img = gray;
+        }
+
+        // 绘制文字水印
+        if let Some(ref text) = params.text {
+            img = self.draw_text_watermark(&img, text)?;
+        }
+
+        // blurhash 基于最终输出的图片计算，失败时只记录日志
+        let blurhash = if params.blurhash {
+            compute_blurhash(&img)
+                .map_err(|e| eprintln!("Warning: blurhash computation failed: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        // format=auto-content 按图片内容选择格式：平面图形/截图用 PNG，照片用 JPEG；只取决于图片本身，同一缓存键结果稳定
+        let content_format = if params.format.as_deref() == Some(AUTO_CONTENT_FORMAT) {
+            let format = classify_content(&img).unwrap_or_else(|e| {
+                eprintln!("Warning: content classification failed, using jpg: {}", e);
+                "jpg"
+            });
+            println!("format=auto-content selected {}", format);
+            Some(format)
+        } else {
+            None
+        };
+
+        Ok(Prepared::Decoded(PreparedImage {
+            img,
+            source_format,
+            blurhash,
+            content_format,
+            start_time,
+            _reservation: reservation,
+        }))
+    }
+
+    // 按指定输出格式编码处理好的图片，失败时按 encode_fallbacks 依次换用其他格式
+    fn encode_prepared(&self, prepared: &PreparedImage, params: &ProcessingParams, format: &str) -> Result<ProcessedImage> {
+        let mut format = format;
+        let mut tried = Vec::new();
+        loop {
+            let error = match self.encode_as(prepared, params, format) {
+                Ok(image) => return Ok(image),
+                Err(e) => e,
+            };
+            tried.push(format);
+            match self.config.encode_fallbacks.get(format).filter(|next| !tried.contains(&next.as_str())) {
+                Some(next) => {
+                    eprintln!("Warning: encoding to {} failed ({}), falling back to {}", format, error, next);
+                    format = next;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    // imencode 的参数：输出质量及 encoder_params 中该格式的额外参数；pixels 为输出像素数
+    fn encode_params(&self, quality_flag: i32, pixels: u64, params: &ProcessingParams, format: &str) -> Vec<i32> {
+        // 质量只取决于请求参数和输出尺寸，而输出尺寸由原图和参数确定，因此现有缓存键已能区分
+        let mut quality = params.quality.unwrap_or_else(|| match self.config.quality_scaling {
+            Some(ref scaling) => scaling.quality_for(pixels),
+            None => self.config.default_quality,
+        });
+        quality = self.config.clamp_quality(quality);
+        // 自动协商换了格式时，客户端给的（按 JPEG 调好的）质量换算成目标格式的近似等效质量
+        if params.auto_format {
+            quality = equivalent_quality(quality, format);
+        }
+        if let Some(cap) = params.quality_cap {
+            quality = quality.min(cap);
+        }
+        let mut encode_params = vec![quality_flag, quality];
+        if let Some(extra) = self.extra_encoder_params.get(format) {
+            encode_params.extend(extra.iter().copied());
+        }
+        encode_params
+    }
+
+    fn encode_as(&self, prepared: &PreparedImage, params: &ProcessingParams, format: &str) -> Result<ProcessedImage> {
+        let img = &prepared.img;
+        let (extension, content_type, quality_flag) = output_format(format);
+
+        // 编码图片
+        let encode_start = SystemTime::now();
+        let mut buf = Vector::new();
+        let params_vec = Vector::from_slice(&self.encode_params(quality_flag, img.total() as u64, params, format));
+        if !imencode(extension, img, &mut buf, &params_vec)? || buf.is_empty() {
+            anyhow::bail!("OpenCV could not encode {} output", format);
+        }
+        let mut encoded_data = buf.to_vec();
+        let encode_duration = encode_start.elapsed().unwrap_or_default();
+        println!("Image encoding took: {:?}", encode_duration);
+
+        if params.optimize && format == "png" {
+            encoded_data = optimize_png(encoded_data);
+        }
+
+        Ok(ProcessedImage {
+            data: encoded_data,
+            content_type: content_type.to_string(),
+            width: Some(img.cols()),
+            height: Some(img.rows()),
+            ttl: None,
+            expires_at: None,
+            blurhash: prepared.blurhash.clone(),
+            compressed: false,
+            cached_at: None,
+            degraded: None,
+            last_modified: None,
+        })
+    }
+
+    // 有处理参数时按请求尺寸栅格化 SVG；未启用 svg feature 时总是原样返回
+    #[cfg(feature = "svg")]
+    fn rasterize_svg_source(&self, svg: &[u8], params: &ProcessingParams) -> Result<Option<Vec<u8>>> {
+        if params.is_empty() {
+            return Ok(None);
+        }
+        crate::svg::rasterize_svg(svg, params.width, params.height, self.config.max_width, self.config.max_height)
+            .map(Some)
+    }
+
+    #[cfg(not(feature = "svg"))]
+    fn rasterize_svg_source(&self, _svg: &[u8], _params: &ProcessingParams) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "raw")]
+    fn decode_raw_source(&self, data: &[u8]) -> Result<Mat> {
+        crate::raw::decode_raw(data, self.config.max_width, self.config.max_height)
+    }
+
+    #[cfg(not(feature = "raw"))]
+    fn decode_raw_source(&self, _data: &[u8]) -> Result<Mat> {
+        Err(RequestError::unsupported_media_type("RAW decoding is not enabled (build with the `raw` feature)").into())
+    }
+
+    // 校验请求参数是否符合尺寸策略
+    fn validate_params(&self, params: &ProcessingParams) -> Result<()> {
+        if let (Some(width), Some(min)) = (params.width, self.config.min_width) {
+            if width < min {
+                return Err(RequestError::bad_request(format!("width {} is below the minimum of {}", width, min)).into());
+            }
+        }
+        if let (Some(height), Some(min)) = (params.height, self.config.min_height) {
+            if height < min {
+                return Err(RequestError::bad_request(format!("height {} is below the minimum of {}", height, min)).into());
+            }
+        }
+        // format=auto 在此之前已被协商为具体格式，按客户端请求的 "auto" 校验
+        let requested = if params.auto_format { Some("auto") } else { params.format.as_deref() };
+        if let (Some(format), Some(allowed)) = (requested, self.config.allowed_formats.as_ref()) {
+            if !allowed.iter().any(|f| f.eq_ignore_ascii_case(format)) {
+                return Err(RequestError::bad_request(format!("format '{}' is not allowed", format)).into());
+            }
+        }
+        Ok(())
+    }
+
+    // 输出格式对应的最大宽高：format_limits 中的覆盖值，否则为全局 max_width/max_height
+    fn max_dimensions(&self, format: &str) -> (i32, i32) {
+        let limits = self.config.format_limits.get(format);
+        (
+            limits.and_then(|l| l.max_width).unwrap_or(self.config.max_width),
+            limits.and_then(|l| l.max_height).unwrap_or(self.config.max_height),
+        )
+    }
+
+    // 像素画使用最近邻插值，保持硬边缘；其次是请求指定的插值，最后按放大/缩小选择配置的默认值
+    fn interpolation_for(&self, params: &ProcessingParams, source: Size, target: Size) -> InterpolationFlags {
+        let upscale = target.width as i64 * target.height as i64 > source.width as i64 * source.height as i64;
+        if params.pixel_art {
+            InterpolationFlags::INTER_NEAREST
+        } else if let Some(interpolation) = params.interpolation {
+            interpolation
+        } else if upscale {
+            self.default_interpolation.0
+        } else {
+            self.default_interpolation.1
+        }
+    }
+
+    // 根据 width/height 参数计算目标尺寸（只给一边时按原图宽高比推算），并限制在输出格式的最大宽高内
+    fn target_size(&self, cols: i32, rows: i32, params: &ProcessingParams, format: &str) -> Option<Size> {
+        let (max_width, max_height) = self.max_dimensions(format);
+        let (mut width, mut height) = match (params.width, params.height) {
+            // 保持宽高比缩放到 width × height 的框内
+            (Some(width), Some(height)) if params.fit_inside => {
+                let scale = (width.min(max_width) as f64 / cols as f64).min(height.min(max_height) as f64 / rows as f64);
+                ((cols as f64 * scale) as i32, (rows as f64 * scale) as i32)
+            }
+            (Some(width), Some(height)) => (width.min(max_width), height.min(max_height)),
+            (Some(width), None) => {
+                let width = width.min(max_width);
+                (width, (width as f64 * rows as f64 / cols as f64) as i32)
+            }
+            (None, Some(height)) => {
+                let height = height.min(max_height);
+                ((height as f64 * cols as f64 / rows as f64) as i32, height)
+            }
+            (None, None) => {
+                // 只给了 max_pixels 时以原图尺寸为基础
+                let pct = params.scale_pct.or(params.max_pixels.map(|_| 100.0))?;
+                let scale = (pct / 100.0).min(max_width as f64 / cols as f64).min(max_height as f64 / rows as f64);
+                ((cols as f64 * scale) as i32, (rows as f64 * scale) as i32)
+            }
+        };
+
+        // 像素画放大时尽量取整数倍，避免像素块大小不一
+        if params.pixel_art {
+            width = snap_to_integer_scale(cols, width, max_width);
+            height = snap_to_integer_scale(rows, height, max_height);
+        }
+
+        if let Some(budget) = params.max_pixels {
+            (width, height) = fit_pixel_budget(width, height, budget);
+        }
+
+        Some(Size::new(width.max(1), height.max(1)))
+    }
+
+    // 按配置的位置、字号、颜色和透明度在图片上绘制文字
+    fn draw_text_watermark(&self, img: &Mat, text: &str) -> Result<Mat> {
+        let wm = &self.config.text_watermark;
+        let mut baseline = 0;
+        let text_size = get_text_size(text, FONT_HERSHEY_SIMPLEX, wm.font_scale, wm.thickness, &mut baseline)?;
+
+        // put_text 的原点是文字基线的左端
+        let (cols, rows) = (img.cols(), img.rows());
+        let left = wm.margin;
+        let right = cols - text_size.width - wm.margin;
+        let top = wm.margin + text_size.height;
+        let bottom = rows - wm.margin - baseline;
+        let origin = match wm.position.as_str() {
+            "top-left" => Point::new(left, top),
+            "top-right" => Point::new(right, top),
+            "bottom-left" => Point::new(left, bottom),
+            "center" => Point::new((cols - text_size.width) / 2, (rows + text_size.height) / 2),
+            _ => Point::new(right, bottom),
+        };
+
+        let (r, g, b) = parse_hex_color(&wm.color).unwrap_or((255, 255, 255));
+        let color = Scalar::new(b as f64, g as f64, r as f64, 255.0);
+
+        let mut overlay = img.try_clone()?;
+        put_text(&mut overlay, text, origin, FONT_HERSHEY_SIMPLEX, wm.font_scale, color, wm.thickness, LINE_AA, false)?;
+
+        let opacity = wm.opacity.clamp(0.0, 1.0);
+        let mut blended = Mat::default();
+        add_weighted(&overlay, opacity, img, 1.0 - opacity, 0.0, &mut blended, -1)?;
+        Ok(blended)
+    }
+
+    pub async fn get_or_process_image(
+        &self,
+        image_key: String,
+        params: ProcessingParams,
+    ) -> Result<(ProcessedImage, String)> {
+        let overall_start = SystemTime::now();
+
+        // 缓存命中前也要检查，对象改为私有后最多在 ACL 缓存时间内仍可访问
+        self.check_access(&image_key).await?;
+        let params = normalize_params(params);
+        if !params.passthrough {
+            self.validate_params(&params)?;
+        }
+        
+        let cache_key = self.cache_key(&image_key, &params);
+        
+        // 检查缓存
+        let cache_check_start = SystemTime::now();
+        if let Some(cached) = self.cache.get(&cache_key).await {
+            let hits = self.cache_hits.fetch_add(1, Ordering::Relaxed) + 1;
+            match self.config.cache_hit_log_sample_rate {
+                None => {
+                    let cache_duration = cache_check_start.elapsed().unwrap_or_default();
+                    println!("Cache check took: {:?}", cache_duration);
+
+                    let overall_duration = overall_start.elapsed().unwrap_or_default();
+                    println!("Request served from cache in {:?}", overall_duration);
+                }
+                Some(rate) if should_sample(hits, rate) => {
+                    let overall_duration = overall_start.elapsed().unwrap_or_default();
+                    println!(
+                        "Sampled cache hit #{}: key='{}' cache_key={} params={:?} content_type={} bytes={} size={:?}x{:?} in {:?}",
+                        hits,
+                        redact_key(&image_key),
+                        redact_key(&cache_key),
+                        params,
+                        cached.content_type,
+                        cached.data.len(),
+                        cached.width,
+                        cached.height,
+                        overall_duration
+                    );
+                }
+                Some(_) => {}
+            }
+            return Ok((cached, "cache".to_string()));
+        }
+        let cache_duration = cache_check_start.elapsed().unwrap_or_default();
+        println!("Cache check took: {:?}", cache_duration);
+
+        // 同一缓存键的并发未命中只处理一次，其余请求等待后从缓存读取
+        let _flight = self.join_flight(&cache_key).await;
+        if let Some(cached) = self.cache.get(&cache_key).await {
+            println!("Request for '{}' served from cache after waiting for in-flight processing", redact_key(&image_key));
+            return Ok((cached, "cache".to_string()));
+        }
+
+        // 清单中存在预生成的派生图时直接返回，不经过 OpenCV
+        if let Some(object_key) = self.manifest.as_ref().and_then(|m| m.lookup(&cache_key)) {
+            let object = self.s3_client.get_object(object_key).await
+                .with_context(|| format!("Failed to get pre-rendered derivative {}", redact_key(object_key)))?;
+            let ttl = object.cache_ttl();
+            let (expires_at, last_modified) = (object.expires_at, object.last_modified);
+            let mut image = ProcessedImage::unprocessed(object.data);
+            image.ttl = params.ttl_override.or(ttl);
+            image.expires_at = expires_at;
+            image.last_modified = last_modified;
+            self.cache.insert(cache_key, image.clone()).await;
+            let overall_duration = overall_start.elapsed().unwrap_or_default();
+            println!("Request served from manifest object '{}' in {:?}", redact_key(object_key), overall_duration);
+            return Ok((image, "manifest".to_string()));
+        }
+
+        if !params.passthrough {
+            if let Some(nearest) = self.enforce_derivative_limit(&image_key, &cache_key, &params).await? {
+                return Ok((nearest, "nearest_derivative".to_string()));
+            }
+        }
+
+        // 过载时复用此前的降级结果，不再回源处理；完整质量的条目（上面已查过）优先
+        if self.degrade_under_load(&params, self.inflight_jobs.load(Ordering::SeqCst) + 1).is_some() {
+            if let Some(cached) = self.cache.get(&degraded_cache_key(&cache_key)).await {
+                return Ok((cached, "cache".to_string()));
+            }
+        }
+
+        // 获取原始图片 (同时获取对象并检查是否存在)
+        let s3_fetch_start = SystemTime::now();
+        let fetched = if params.preview {
+            self.fetch_preview_source(&image_key).await
+        } else {
+            self.get_original(&image_key).await.map(|object| (object, false))
+        };
+        let (original, partial) = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                eprintln!("Object '{}' does not exist in S3 or cannot be accessed: {}", redact_key(&image_key), e);
+                return Err(e.context(format!("Failed to get original image {}", redact_key(&image_key))));
+            }
+        };
+        let s3_duration = s3_fetch_start.elapsed().unwrap_or_default();
+        println!("S3 fetch took: {:?}", s3_duration);
+
+        let processed = self.process_original(&image_key, original, partial, &params).await?;
+
+        // 更新缓存
+        let cache_update_start = SystemTime::now();
+        self.store_processed(&image_key, cache_key, &params, processed.clone()).await;
+        let cache_update_duration = cache_update_start.elapsed().unwrap_or_default();
+        println!("Cache update took: {:?}", cache_update_duration);
+
+        let overall_duration = overall_start.elapsed().unwrap_or_default();
+        println!("Request processed and cached in {:?}", overall_duration);
+
+        let source = if params.passthrough {
+            "passthrough"
+        } else if partial {
+            "preview"
+        } else {
+            "newly_processed"
+        };
+        Ok((processed, source.to_string()))
+    }
+    
+    // 同一组参数按多种输出格式返回（formats=webp,jpg）：原图只解码、缩放一次（格式的最大宽高不同时按组各一次），再分别编码；
+    // 每个格式的结果按各自的缓存键（等同 format=<格式> 的请求）读写缓存
+    pub async fn get_or_process_variants(
+        &self,
+        image_key: String,
+        params: ProcessingParams,
+        formats: &[String],
+    ) -> Result<Vec<ProcessedImage>> {
+        self.check_access(&image_key).await?;
+        if params.passthrough {
+            return Err(RequestError::bad_request("formats cannot be combined with passthrough").into());
+        }
+        if formats.is_empty() || formats.len() > MAX_VARIANT_FORMATS {
+            return Err(RequestError::bad_request(format!("formats must list 1 to {} output formats", MAX_VARIANT_FORMATS)).into());
+        }
+        let variants = formats
+            .iter()
+            .map(|format| {
+                if !OUTPUT_FORMATS.contains(&format.as_str()) && !OPTIONAL_OUTPUT_FORMATS.contains(&format.as_str()) {
+                    return Err(RequestError::bad_request(format!("unknown output format '{}'", format)).into());
+                }
+                let variant = ProcessingParams {
+                    format: Some(format.clone()),
+                    auto_format: false,
+                    ..params.clone()
+                };
+                self.validate_params(&variant)?;
+                Ok(variant)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let cache_keys: Vec<String> = variants.iter().map(|variant| self.cache_key(&image_key, variant)).collect();
+        let mut results = Vec::with_capacity(variants.len());
+        for cache_key in &cache_keys {
+            results.push(self.cache.get(cache_key).await);
+        }
+        if results.iter().all(Option::is_some) {
+            println!("All {} variants of '{}' served from cache", variants.len(), redact_key(&image_key));
+            return Ok(results.into_iter().flatten().collect());
+        }
+
+        // 与单个格式的请求共用同一把 in-flight 锁，按缓存键排序加锁，避免格式顺序不同的两个请求互相等待
+        let mut missing: Vec<&String> = cache_keys.iter().zip(&results).filter(|(_, cached)| cached.is_none()).map(|(key, _)| key).collect();
+        missing.sort();
+        missing.dedup();
+        let mut flights = Vec::with_capacity(missing.len());
+        for cache_key in missing {
+            flights.push(self.join_flight(cache_key).await);
+        }
+
+        // 等待期间其他请求可能已处理完；之后与单个格式的请求一样检查派生图数量上限，过载时用降级结果
+        let under_load = self.degrade_under_load(&params, self.inflight_jobs.load(Ordering::SeqCst) + 1).is_some();
+        for ((variant, cache_key), cached) in variants.iter().zip(&cache_keys).zip(results.iter_mut()) {
+            if cached.is_some() {
+                continue;
+            }
+            *cached = match self.cache.get(cache_key).await {
+                Some(image) => Some(image),
+                None => match self.enforce_derivative_limit(&image_key, cache_key, variant).await? {
+                    Some(nearest) => Some(nearest),
+                    None if under_load => self.cache.get(&degraded_cache_key(cache_key)).await,
+                    None => None,
+                },
+            };
+        }
+        if results.iter().all(Option::is_some) {
+            println!("All {} variants of '{}' served from cache", variants.len(), redact_key(&image_key));
+            return Ok(results.into_iter().flatten().collect());
+        }
+
+        let original = self.get_original(&image_key).await
+            .with_context(|| format!("Failed to get original image {}", redact_key(&image_key)))?;
+        let ttl = original.cache_ttl();
+        let (expires_at, last_modified) = (original.expires_at, original.last_modified);
+        let raw = is_raw_key(&image_key);
+        let start_time = SystemTime::now();
+        let job = JobSlot::new(self.inflight_jobs.clone());
+        let degrade = self.degrade_under_load(&params, job.inflight);
+
+        // 各格式的最大宽高（format_limits）不同时缩放结果也不同，按最大宽高分组，每组只处理一次
+        let mut prepared: Vec<((i32, i32), Arc<PreparedImage>)> = Vec::new();
+        let mut images = Vec::with_capacity(variants.len());
+        for (variant, cached) in variants.iter().zip(results) {
+            if let Some(image) = cached {
+                images.push(image);
+                continue;
+            }
+            let (encode_params, description) = match degrade {
+                Some(degrade) => {
+                    let (degraded, description) = degraded_params(variant, degrade);
+                    (degraded, Some(description))
+                }
+                None => (variant.clone(), None),
+            };
+            let format = variant.format.as_deref().unwrap_or("jpg");
+            let limits = self.max_dimensions(format);
+            let index = match prepared.iter().position(|(l, _)| *l == limits) {
+                Some(index) => index,
+                None => {
+                    let (data, params) = (original.data.clone(), encode_params.clone());
+                    match self.on_cpu_pool(move |processor| processor.prepare_source(data, &params, raw)).await? {
+                        Prepared::Decoded(image) => {
+                            prepared.push((limits, Arc::new(image)));
+                            prepared.len() - 1
+                        }
+                        Prepared::Finished(_) => {
+                            return Err(RequestError::unsupported_media_type(format!(
+                                "'{}' cannot be encoded to multiple formats", redact_key(&image_key)
+                            ))
+                            .into());
+                        }
+                    }
+                }
+            };
+            let (source, format) = (prepared[index].1.clone(), format.to_string());
+            let mut image = self.on_cpu_pool(move |processor| processor.encode_prepared(&source, &encode_params, &format)).await?;
+            image.ttl = variant.ttl_override.or(ttl);
+            if let Some(degrade) = degrade {
+                image.ttl = degraded_ttl(image.ttl, degrade);
+            }
+            image.degraded = description;
+            image.expires_at = expires_at;
+            image.last_modified = last_modified;
+            self.store_processed(&image_key, self.cache_key(&image_key, variant), variant, image.clone()).await;
+            images.push(image);
+        }
+        drop(job);
+        drop(flights);
+        let duration = start_time.elapsed().unwrap_or_default();
+        println!("Processed {} variants of '{}' in {:?}", images.len(), redact_key(&image_key), duration);
+        Ok(images)
+    }
+
+    // 校验上传内容是可解码的图片后写入 S3，返回识别出的内容类型
+    pub async fn upload_image(&self, image_key: &str, data: Vec<u8>) -> Result<String> {
+        let content_type = detect_format(&data)
+            .ok_or_else(|| {
+                RequestError::unsupported_media_type(format!("Upload for '{}' is not a recognized image", redact_key(image_key)))
+            })?
+            .content_type();
+
+        let img_buf = Vector::<u8>::from_slice(&data);
+        let img = self.cpu_pool.run(move || Ok(decode_image(&img_buf))).await?;
+        if img.is_none() {
+            return Err(RequestError::unsupported_media_type(format!(
+                "Upload for '{}' could not be decoded as an image", redact_key(image_key)
+            ))
+            .into());
+        }
+
+        let size = data.len();
+        self.s3_client.put_object(image_key, data, content_type).await?;
+        println!("Uploaded '{}' ({}, {} bytes)", redact_key(image_key), content_type, size);
+
+        Ok(content_type.to_string())
+    }
+
+    // 视频等非图片对象：检查访问权限后按 Range 流式返回，不经过缓存和 OpenCV
+    pub async fn stream_media(&self, key: &str, range: Option<&str>) -> Result<S3Stream> {
+        self.check_access(key).await?;
+        if self.http_source(key).is_some() {
+            return Err(RequestError::not_found("Media passthrough is not available for HTTP sources").into());
+        }
+        self.s3_client.get_object_stream(key, range).await
+    }
+
+    // 只读取文件头（先 64KB，不够时再读 1MB）解析原图尺寸和格式，不下载整个对象
+    pub async fn image_info(&self, image_key: &str) -> Result<(i32, i32, &'static str)> {
+        self.check_access(image_key).await?;
+        for len in [64 * 1024, 1024 * 1024] {
+            let head = self.get_original_range(image_key, 0, len - 1).await?;
+            if let Ok(size) = imagesize::blob_size(&head) {
+                let content_type = detect_format(&head)
+                    .map(|f| f.content_type())
+                    .unwrap_or("application/octet-stream");
+                return Ok((size.width as i32, size.height as i32, content_type));
+            }
+            // 对象本身比读取的区间短，说明已经拿到整个文件
+            if (head.len() as u64) < len {
+                break;
+            }
+        }
+        Err(RequestError::unsupported_media_type(format!("Cannot read image dimensions of '{}'", redact_key(image_key))).into())
+    }
+
+    // 列出 "bucket/prefix" 下的原图 key，供缓存预热使用
+    pub async fn list_originals(&self, prefix: &str, limit: usize) -> Result<Vec<String>> {
+        self.s3_client.list_objects(prefix, limit).await
+    }
+
+    // 回答 If-Modified-Since 用的原图最后修改时间：本机缓存中已有该派生图时取条目中记录的值，未命中时才 head 原图
+    pub async fn last_modified(&self, image_key: &str, params: &ProcessingParams) -> Option<SystemTime> {
+        let cache_key = self.cache_key(image_key, &normalize_params(params.clone()));
+        if let Some(modified) = self.cache.last_modified(&cache_key) {
+            return Some(modified);
+        }
+        self.source_last_modified(image_key).await
+    }
+
+    // 原图在 S3 中的最后修改时间（秒级精度），获取失败时返回 None
+    async fn source_last_modified(&self, image_key: &str) -> Option<SystemTime> {
+        if self.http_source(image_key).is_some() {
+            return None;
+        }
+        match self.s3_client.last_modified(image_key).await {
+            Ok(modified) => modified,
+            Err(e) => {
+                eprintln!("Failed to read last modified time of '{}': {}", redact_key(image_key), e);
+                None
+            }
+        }
+    }
+
+    // 基准测试：用内置的合成图片跑完整处理流程 iterations 次，不访问 S3 也不写缓存，返回最后一次的结果和每次耗时
+    pub async fn bench(&self, params: &ProcessingParams, iterations: usize) -> Result<(ProcessedImage, Vec<Duration>)> {
+        let source = bench_source()?;
+        let mut timings = Vec::with_capacity(iterations);
+        let mut last = None;
+        for _ in 0..iterations.max(1) {
+            let start = SystemTime::now();
+            last = Some(self.process_image_data(source.to_vec(), params).await?);
+            timings.push(start.elapsed().unwrap_or_default());
+        }
+        Ok((last.expect("at least one iteration"), timings))
+    }
+
+    // 启动自检：对每种输出格式编码 1x1 图片，返回编码失败的格式（例如运行时缺少 libjpeg/libwebp）
+    pub fn self_test(&self) -> Vec<String> {
+        codec_failures(OUTPUT_FORMATS, encode_probe)
+    }
+
+    // on_missing 为 transparent_pixel 且错误是原图不存在（404）时，返回用于替代 404 的透明像素
+    pub fn missing_fallback(&self, e: &anyhow::Error) -> Option<ProcessedImage> {
+        if self.config.on_missing != MissingPolicy::TransparentPixel {
+            return None;
+        }
+        e.downcast_ref::<RequestError>().filter(|re| re.status == 404)?;
+        Some(ProcessedImage {
+            data: TRANSPARENT_PIXEL_PNG.to_vec(),
+            content_type: "image/png".to_string(),
+            width: Some(1),
+            height: Some(1),
+            ttl: None,
+            expires_at: None,
+            blurhash: None,
+            compressed: false,
+            cached_at: None,
+            degraded: None,
+            last_modified: None,
+        })
+    }
+
+    pub fn max_source_bytes(&self) -> u64 {
+        self.config.max_source_bytes
+    }
+
+    pub fn content_sha256(&self) -> bool {
+        self.config.content_sha256
+    }
+
+    // 本次响应的 Content-Disposition：download=1 时为 attachment，否则为配置的默认值
+    pub fn content_disposition(&self, params: &ProcessingParams) -> Option<ContentDisposition> {
+        if params.download {
+            Some(ContentDisposition::Attachment)
+        } else {
+            self.config.content_disposition_default
+        }
+    }
+
+    // 主色与平均色：原图缩小到 COLOR_SAMPLE_SIZE 以内后计算，结果以 JSON 按 "color:{key}" 存入图片缓存
+    pub async fn image_colors(&self, image_key: &str) -> Result<ImageColors> {
+        self.check_access(image_key).await?;
+        let cache_key = format!("color:{}", self.s3_client.effective_key(image_key));
+        if let Some(cached) = self.cache.get(&cache_key).await {
+            if let Ok(colors) = serde_json::from_slice(&cached.data) {
+                return Ok(colors);
+            }
+        }
+
+        let original = self.get_original(image_key).await
+            .with_context(|| format!("Failed to get original image {}", redact_key(image_key)))?;
+        let ttl = original.cache_ttl();
+        let expires_at = original.expires_at;
+        let params = ProcessingParams {
+            width: Some(COLOR_SAMPLE_SIZE),
+            height: Some(COLOR_SAMPLE_SIZE),
+            fit_inside: true,
+            only_if_larger: true,
+            interpolation: Some(InterpolationFlags::INTER_AREA),
+            ..Default::default()
+        };
+        let raw = is_raw_key(image_key);
+        let colors = self
+            .on_cpu_pool(move |processor| match processor.prepare_source(original.data, &params, raw)? {
+                Prepared::Decoded(prepared) => compute_colors(&prepared.img),
+                Prepared::Finished(_) => {
+                    Err(RequestError::unsupported_media_type("Cannot decode the source image to compute its colors").into())
+                }
+            })
+            .await?;
+
+        let mut entry = ProcessedImage::unprocessed(serde_json::to_vec(&colors)?);
+        entry.content_type = "application/json".to_string();
+        entry.ttl = ttl;
+        entry.expires_at = expires_at;
+        self.cache.insert(cache_key, entry).await;
+        Ok(colors)
+    }
+
+    // 每个通道的直方图，基于缩小到 HISTOGRAM_SAMPLE_SIZE 以内的图片计算；结果按 bins 分别缓存
+    pub async fn image_histogram(&self, image_key: &str, bins: i32) -> Result<ImageHistogram> {
+        if !(1..=256).contains(&bins) {
+            return Err(RequestError::bad_request("bins must be between 1 and 256").into());
+        }
+        self.check_access(image_key).await?;
+        let cache_key = format!("histogram:{}:{}", bins, self.s3_client.effective_key(image_key));
+        if let Some(cached) = self.cache.get(&cache_key).await {
+            if let Ok(histogram) = serde_json::from_slice(&cached.data) {
+                return Ok(histogram);
+            }
+        }
+
+        let original = self.get_original(image_key).await
+            .with_context(|| format!("Failed to get original image {}", redact_key(image_key)))?;
+        let ttl = original.cache_ttl();
+        let expires_at = original.expires_at;
+        let params = ProcessingParams {
+            width: Some(HISTOGRAM_SAMPLE_SIZE),
+            height: Some(HISTOGRAM_SAMPLE_SIZE),
+            fit_inside: true,
+            only_if_larger: true,
+            interpolation: Some(InterpolationFlags::INTER_AREA),
+            ..Default::default()
+        };
+        let raw = is_raw_key(image_key);
+        let histogram = self
+            .on_cpu_pool(move |processor| match processor.prepare_source(original.data, &params, raw)? {
+                Prepared::Decoded(prepared) => compute_histogram(&prepared.img, bins),
+                Prepared::Finished(_) => {
+                    Err(RequestError::unsupported_media_type("Cannot decode the source image to compute its histogram").into())
+                }
+            })
+            .await?;
+
+        let mut entry = ProcessedImage::unprocessed(serde_json::to_vec(&histogram)?);
+        entry.content_type = "application/json".to_string();
+        entry.ttl = ttl;
+        entry.expires_at = expires_at;
+        self.cache.insert(cache_key, entry).await;
+        Ok(histogram)
+    }
+
+    // 按同一组处理参数把图片编码成每种支持的格式，返回 格式 → 字节数；只解码和处理一次，结果短期缓存
+    pub async fn format_sizes(&self, image_key: &str, params: ProcessingParams) -> Result<BTreeMap<String, usize>> {
+        self.check_access(image_key).await?;
+        let params = ProcessingParams { format: None, auto_format: false, ..normalize_params(params) };
+        let cache_key = format!("sizes:{}", self.cache_key(image_key, &params));
+        if let Some(cached) = self.cache.get(&cache_key).await {
+            if let Ok(sizes) = serde_json::from_slice(&cached.data) {
+                return Ok(sizes);
+            }
+        }
+
+        let original = self.get_original(image_key).await
+            .with_context(|| format!("Failed to get original image {}", redact_key(image_key)))?;
+        let ttl = original.cache_ttl().map_or(SIZES_CACHE_TTL, |ttl| ttl.min(SIZES_CACHE_TTL));
+        let expires_at = original.expires_at;
+        let raw = is_raw_key(image_key);
+        let sizes = self
+            .on_cpu_pool(move |processor| {
+                let prepared = match processor.prepare_source(original.data, &params, raw)? {
+                    Prepared::Decoded(prepared) => prepared,
+                    Prepared::Finished(_) => {
+                        return Err(RequestError::unsupported_media_type("Cannot decode the source image to compare formats").into())
+                    }
+                };
+                let mut sizes = BTreeMap::new();
+                for format in OUTPUT_FORMATS.iter().chain(OPTIONAL_OUTPUT_FORMATS) {
+                    match processor.encode_as(&prepared, &params, format) {
+                        Ok(image) => {
+                            sizes.insert(format.to_string(), image.data.len());
+                        }
+                        // 可选格式的编码器可能没有编译进 OpenCV，跳过即可
+                        Err(e) if OPTIONAL_OUTPUT_FORMATS.contains(format) => {
+                            eprintln!("Warning: skipping {} in format size comparison: {}", format, e);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(sizes)
+            })
+            .await?;
+
+        let mut entry = ProcessedImage::unprocessed(serde_json::to_vec(&sizes)?);
+        entry.content_type = "application/json".to_string();
+        entry.ttl = Some(ttl);
+        entry.expires_at = expires_at;
+        self.cache.insert(cache_key, entry).await;
+        Ok(sizes)
+    }
+
+    pub fn cache_expires_in(&self, image: &ProcessedImage) -> Option<Duration> {
+        self.cache.expires_in(image)
+    }
+
+    pub fn get_cache_stats(&self) -> CacheStats {
+        self.cache.get_stats()
+    }
+
+    // 缓存键基于实际请求 S3 的 key（含 s3.key_prefix）
+    fn cache_key(&self, image_key: &str, params: &ProcessingParams) -> String {
+        cache_key(&self.s3_client.effective_key(image_key), params)
+    }
+
+    // 按与请求相同的规则计算缓存键并移除这一个派生图，返回 (缓存键, 是否存在)
+    pub async fn evict_derivative(&self, image_key: &str, params: ProcessingParams) -> (String, bool) {
+        let cache_key = self.cache_key(image_key, &normalize_params(params));
+        let existed = self.cache.remove(&cache_key).await;
+        (cache_key, existed)
+    }
+
+    // 新增：清空缓存（供 /clear-cache 路由调用）
+    pub async fn clear_cache(&self) {
+        self.cache.clear().await;
+    }
+}
+
+// 支持的输出格式
+pub const OUTPUT_FORMATS: &[&str] = &["jpg", "png", "webp"];
+// 依赖 OpenCV 编译选项的输出格式，编码器缺失时不影响就绪状态
+pub const OPTIONAL_OUTPUT_FORMATS: &[&str] = &["avif"];
+// formats= 一次最多请求的输出格式数
+pub const MAX_VARIANT_FORMATS: usize = 4;
+
+// JPEG 质量与 WebP/AVIF 的近似感知等效质量对照，中间值线性插值
+const QUALITY_EQUIVALENTS: &[(i32, i32, i32)] = &[
+    // (jpeg, webp, avif)
+    (1, 1, 1),
+    (50, 45, 30),
+    (60, 55, 38),
+    (70, 64, 45),
+    (80, 73, 53),
+    (85, 78, 58),
+    (90, 84, 66),
+    (95, 91, 78),
+    (100, 100, 100),
+];
+
+// 将 JPEG 刻度的质量换算为目标格式的等效质量；jpg/png 原样返回（PNG 的质量参数是压缩级别，不做换算）
+fn equivalent_quality(jpeg_quality: i32, format: &str) -> i32 {
+    let pick = match format {
+        "webp" => |&(_, webp, _): &(i32, i32, i32)| webp,
+        "avif" => |&(_, _, avif): &(i32, i32, i32)| avif,
+        _ => return jpeg_quality,
+    };
+    let q = jpeg_quality.clamp(1, 100);
+    QUALITY_EQUIVALENTS
+        .windows(2)
+        .find(|w| q <= w[1].0)
+        .map(|w| {
+            let (lo, hi) = (w[0], w[1]);
+            let t = (q - lo.0) as f64 / (hi.0 - lo.0) as f64;
+            (pick(&lo) as f64 + t * (pick(&hi) - pick(&lo)) as f64).round() as i32
+        })
+        .unwrap_or(q)
+}
+
+// Accept 头中是否接受指定的媒体类型（q=0 视为不接受）
+fn accepts_media_type(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        let matches = parts.next().is_some_and(|t| t.eq_ignore_ascii_case(media_type));
+        let rejected = parts.any(|p| {
+            p.strip_prefix("q=")
+                .and_then(|q| q.parse::<f64>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        matches && !rejected
+    })
+}
+
+// 输出格式对应的扩展名、内容类型和质量参数标志，未知格式按 JPEG 处理
+fn output_format(format: &str) -> (&'static str, &'static str, i32) {
+    match format {
+        "png" => (".png", "image/png", 16), // ImwriteFlags::PNG_COMPRESSION equivalent
+        "webp" => (".webp", "image/webp", 64), // ImwriteFlags::WEBP_QUALITY equivalent
+        "avif" => (".avif", "image/avif", 512), // ImwriteFlags::AVIF_QUALITY equivalent
+        _ => (".jpg", "image/jpeg", 1), // ImwriteFlags::JPEG_QUALITY equivalent
+    }
+}
+
+// oxipng 无损再压缩，结果不小于原数据或失败时保留原数据
+#[cfg(feature = "png-optimize")]
+fn optimize_png(data: Vec<u8>) -> Vec<u8> {
+    let start = SystemTime::now();
+    match oxipng::optimize_from_memory(&data, &oxipng::Options::from_preset(2)) {
+        Ok(optimized) if optimized.len() < data.len() => {
+            println!(
+                "PNG optimization: {} -> {} bytes in {:?}",
+                data.len(),
+                optimized.len(),
+                start.elapsed().unwrap_or_default()
+            );
+            optimized
+        }
+        Ok(_) => data,
+        Err(e) => {
+            eprintln!("Warning: PNG optimization failed: {}", e);
+            data
+        }
+    }
+}
+
+#[cfg(not(feature = "png-optimize"))]
+fn optimize_png(data: Vec<u8>) -> Vec<u8> {
+    data
+}
+
+pub const ORIENTATIONS: &[(&str, Orientation)] = &[
+    ("portrait", Orientation::Portrait),
+    ("landscape", Orientation::Landscape),
+];
+
+pub const INTERPOLATIONS: &[(&str, InterpolationFlags)] = &[
+    ("nearest", InterpolationFlags::INTER_NEAREST),
+    ("linear", InterpolationFlags::INTER_LINEAR),
+    ("cubic", InterpolationFlags::INTER_CUBIC),
+    ("area", InterpolationFlags::INTER_AREA),
+    ("lanczos", InterpolationFlags::INTER_LANCZOS4),
+];
+
+fn parse_orientation(value: &str) -> Option<Orientation> {
+    let value = value.trim();
+    ORIENTATIONS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+        .map(|(_, orientation)| *orientation)
+}
+
+fn parse_interpolation(name: &str) -> Option<InterpolationFlags> {
+    let name = name.trim();
+    INTERPOLATIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|(_, flag)| *flag)
+}
+
+// 按比例每隔 1/rate 次命中采样一次，只需一个原子计数器
+fn should_sample(count: u64, rate: f64) -> bool {
+    if rate <= 0.0 {
+        return false;
+    }
+    let interval = (1.0 / rate.min(1.0)).round().max(1.0) as u64;
+    count.is_multiple_of(interval)
+}
+
+// 将裁剪区域换算为原图内的像素矩形；超出原图的部分截掉，完全落在原图之外时返回 400
+fn region_rect(cols: i32, rows: i32, region: Region) -> Result<Rect> {
+    let (x, y, width, height) = match region {
+        Region::Square => {
+            let side = cols.min(rows);
+            ((cols - side) / 2, (rows - side) / 2, side, side)
+        }
+        Region::Pixels { x, y, width, height } => (x, y, width, height),
+        Region::Percent { x, y, width, height } => (
+            (cols as f64 * x / 100.0) as i32,
+            (rows as f64 * y / 100.0) as i32,
+            (cols as f64 * width / 100.0).round() as i32,
+            (rows as f64 * height / 100.0).round() as i32,
+        ),
+    };
+    if x < 0 || y < 0 || x >= cols || y >= rows || width <= 0 || height <= 0 {
+        return Err(RequestError::bad_request(format!("region {},{},{},{} is outside the {}x{} image", x, y, width, height, cols, rows)).into());
+    }
+    Ok(Rect::new(x, y, width.min(cols - x), height.min(rows - y)))
+}
+
+// transparent 为 true 且图片带透明通道时补透明像素，否则补 background（默认白色）
+fn pad_to_square(img: &Mat, background: Option<(u8, u8, u8)>, transparent: bool) -> Result<Mat> {
+    let (cols, rows) = (img.cols(), img.rows());
+    if cols == rows {
+        return Ok(img.try_clone()?);
+    }
+    let side = cols.max(rows);
+    let (left, top) = ((side - cols) / 2, (side - rows) / 2);
+    let scale = if img.depth() == CV_16U { 257.0 } else { 1.0 };
+    let (r, g, b) = background.unwrap_or((255, 255, 255));
+    let alpha = if transparent { 0.0 } else { 255.0 };
+    let value = if img.channels() == 1 {
+        // 灰度图按亮度取值
+        let luma = 0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64;
+        Scalar::all(luma * scale)
+    } else {
+        Scalar::new(b as f64 * scale, g as f64 * scale, r as f64 * scale, alpha * scale)
+    };
+    let mut padded = Mat::default();
+    copy_make_border(img, &mut padded, top, side - rows - top, left, side - cols - left, BORDER_CONSTANT, value)?;
+    Ok(padded)
+}
+
+fn rotate_image(img: &Mat, rotation: Rotation) -> Result<Mat> {
+    let mut flipped = Mat::default();
+    let img = if rotation.mirror {
+        flip(img, &mut flipped, 1)?;
+        &flipped
+    } else {
+        img
+    };
+    let code = match rotation.degrees {
+        90 => opencv::core::ROTATE_90_CLOCKWISE,
+        180 => opencv::core::ROTATE_180,
+        270 => opencv::core::ROTATE_90_COUNTERCLOCKWISE,
+        _ => return Ok(img.try_clone()?),
+    };
+    let mut rotated = Mat::default();
+    rotate(img, &mut rotated, code)?;
+    Ok(rotated)
+}
+
+// 裁出瓦片在原图中对应的区域并缩放到该层级的比例；边缘瓦片小于 TILE_SIZE
+fn extract_tile(img: &Mat, tile: Tile) -> Result<Mat> {
+    let (cols, rows) = (img.cols(), img.rows());
+    let max_level = (cols.max(rows).max(1) as f64).log2().ceil() as u32;
+    if tile.level > max_level {
+        return Err(RequestError::bad_request(format!("tile level {} exceeds the maximum level {}", tile.level, max_level)).into());
+    }
+    let scale = 0.5f64.powi((max_level - tile.level) as i32);
+    let level_width = (cols as f64 * scale).ceil() as i64;
+    let level_height = (rows as f64 * scale).ceil() as i64;
+    let (left, top) = (tile.x as i64 * TILE_SIZE as i64, tile.y as i64 * TILE_SIZE as i64);
+    if left >= level_width || top >= level_height {
+        return Err(RequestError::not_found(format!("tile {}/{}/{} is outside the image", tile.level, tile.x, tile.y)).into());
+    }
+    let tile_width = (level_width - left).min(TILE_SIZE as i64) as i32;
+    let tile_height = (level_height - top).min(TILE_SIZE as i64) as i32;
+
+    // 换算回原图坐标
+    let x = ((left as f64 / scale) as i32).min(cols - 1);
+    let y = ((top as f64 / scale) as i32).min(rows - 1);
+    let width = ((tile_width as f64 / scale).ceil() as i32).clamp(1, cols - x);
+    let height = ((tile_height as f64 / scale).ceil() as i32).clamp(1, rows - y);
+    let region = Mat::roi(img, Rect::new(x, y, width, height))?;
+    if width == tile_width && height == tile_height {
+        return Ok(region.try_clone()?);
+    }
+    let mut scaled = Mat::default();
+    resize(&region, &mut scaled, Size::new(tile_width, tile_height), 0.0, 0.0, InterpolationFlags::INTER_AREA.into())?;
+    Ok(scaled)
+}
+
+// 缩小到固定尺寸并统一为 8 位 RGBA，便于按像素分析
+fn small_rgba(img: &Mat, size: Size, interpolation: InterpolationFlags) -> Result<Mat> {
+    if img.empty() {
+        anyhow::bail!("empty image");
+    }
+    let mut small = Mat::default();
+    resize(img, &mut small, size, 0.0, 0.0, interpolation.into())?;
+    // 16 位等深度先转换为 8 位
+    if small.depth() != CV_8U {
+        let mut converted = Mat::default();
+        let alpha = if small.depth() == CV_16U { 1.0 / 257.0 } else { 1.0 };
+        small.convert_to(&mut converted, CV_8U, alpha, 0.0)?;
+        small = converted;
+    }
+    let code = match small.channels() {
+        1 => COLOR_GRAY2RGBA,
+        3 => COLOR_BGR2RGBA,
+        4 => COLOR_BGRA2RGBA,
+        n => anyhow::bail!("unsupported channel count {}", n),
+    };
+    let mut rgba = Mat::default();
+    cvt_color_def(&small, &mut rgba, code)?;
+    Ok(rgba)
+}
+
+/// /color 的结果，颜色为 "#rrggbb"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageColors {
+    pub dominant: String,
+    pub average: String,
+}
+
+/// /histogram 的结果：每个通道 bins 个区间的像素数，区间等分 0-255
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageHistogram {
+    pub bins: i32,
+    // 参与统计的像素数（缩小后的图片）
+    pub pixels: u64,
+    // red/green/blue，原图带透明通道时另有 alpha
+    pub channels: BTreeMap<String, Vec<u64>>,
+}
+
+// 计算直方图前把原图缩小到的边长上限，像素数的分布与原图近似
+const HISTOGRAM_SAMPLE_SIZE: i32 = 512;
+
+// 计算主色前把原图缩小到的边长上限
+const COLOR_SAMPLE_SIZE: i32 = 64;
+const COLOR_CLUSTERS: i32 = 4;
+
+// /sizes 结果的缓存时间，只用于临时比较，不必长期占用缓存
+const SIZES_CACHE_TTL: Duration = Duration::from_secs(300);
+
+// 主色为 k-means 聚类后像素最多的簇中心；基本透明的像素不参与，整张图都透明时退回全部像素
+fn compute_colors(img: &Mat) -> Result<ImageColors> {
+    let rgba = small_rgba(img, Size::new(img.cols(), img.rows()), InterpolationFlags::INTER_NEAREST)?;
+    let pixels = rgba.data_bytes()?;
+    let mut opaque = pixels.chunks_exact(4).filter(|p| p[3] >= 128).peekable();
+    let samples: Vec<f32> = if opaque.peek().is_some() {
+        opaque.flat_map(|p| [p[0] as f32, p[1] as f32, p[2] as f32]).collect()
+    } else {
+        pixels.chunks_exact(4).flat_map(|p| [p[0] as f32, p[1] as f32, p[2] as f32]).collect()
+    };
+    let count = (samples.len() / 3) as i32;
+
+    let mut sum = [0f64; 3];
+    for pixel in samples.chunks_exact(3) {
+        for (total, value) in sum.iter_mut().zip(pixel) {
+            *total += *value as f64;
+        }
+    }
+    let average = sum.map(|total| (total / count as f64) as f32);
+
+    let data = Mat::new_rows_cols_with_data(count, 3, &samples)?;
+    let clusters = COLOR_CLUSTERS.min(count);
+    let mut labels = Mat::default();
+    let mut centers = Mat::default();
+    let criteria = TermCriteria::new(TermCriteria_COUNT + TermCriteria_EPS, 10, 1.0)?;
+    kmeans(&data, clusters, &mut labels, criteria, 3, KMEANS_PP_CENTERS, &mut centers)?;
+    let mut sizes = vec![0usize; clusters as usize];
+    for &label in labels.data_typed::<i32>()? {
+        sizes[label as usize] += 1;
+    }
+    let largest = (0..sizes.len()).max_by_key(|&i| sizes[i]).unwrap_or(0);
+    let dominant = centers.at_row::<f32>(largest as i32)?;
+
+    Ok(ImageColors {
+        dominant: hex_color(dominant),
+        average: hex_color(&average),
+    })
+}
+
+// 转为 8 位 RGBA 后对每个通道做 calc_hist
+fn compute_histogram(img: &Mat, bins: i32) -> Result<ImageHistogram> {
+    let has_alpha = img.channels() == 4;
+    let rgba = small_rgba(img, Size::new(img.cols(), img.rows()), InterpolationFlags::INTER_NEAREST)?;
+    let images = Vector::<Mat>::from_iter([rgba]);
+    let mut channels = BTreeMap::new();
+    for (index, name) in ["red", "green", "blue", "alpha"].into_iter().enumerate() {
+        if name == "alpha" && !has_alpha {
+            continue;
+        }
+        let mut hist = Mat::default();
+        calc_hist(
+            &images,
+            &Vector::from_slice(&[index as i32]),
+            &Mat::default(),
+            &mut hist,
+            &Vector::from_slice(&[bins]),
+            &Vector::from_slice(&[0.0, 256.0]),
+            false,
+        )?;
+        let counts = hist.data_typed::<f32>()?.iter().map(|&count| count as u64).collect();
+        channels.insert(name.to_string(), counts);
+    }
+    Ok(ImageHistogram {
+        bins,
+        pixels: (img.cols() as u64) * (img.rows() as u64),
+        channels,
+    })
+}
+
+fn hex_color(rgb: &[f32]) -> String {
+    let channel = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(rgb[0]), channel(rgb[1]), channel(rgb[2]))
+}
+
+// 先缩小到 32x32 再计算 blurhash（4x3 分量），开销与原图尺寸无关
+fn compute_blurhash(img: &Mat) -> Result<String> {
+    let rgba = small_rgba(img, Size::new(32, 32), InterpolationFlags::INTER_AREA)?;
+    blurhash::encode(4, 3, rgba.cols() as u32, rgba.rows() as u32, rgba.data_bytes()?)
+        .map_err(|e| anyhow::anyhow!("blurhash encode failed: {:?}", e))
+}
+
+pub const AUTO_CONTENT_FORMAT: &str = "auto-content";
+// 最多 64x64 的采样中不同颜色数不超过该值视为平面图形
+const GRAPHIC_MAX_COLORS: usize = 256;
+
+// 最近邻采样保留原始颜色：有透明像素或颜色很少（图形、截图）时选 PNG，否则（照片）选 JPEG
+fn classify_content(img: &Mat) -> Result<&'static str> {
+    let rgba = small_rgba(img, Size::new(img.cols().min(64), img.rows().min(64)), InterpolationFlags::INTER_NEAREST)?;
+    let mut colors = std::collections::HashSet::new();
+    for pixel in rgba.data_bytes()?.chunks_exact(4) {
+        if pixel[3] < 255 {
+            return Ok("png");
+        }
+        colors.insert([pixel[0], pixel[1], pixel[2]]);
+    }
+    Ok(if colors.len() <= GRAPHIC_MAX_COLORS { "png" } else { "jpg" })
+}
+
+// 依次尝试的解码模式：ANYCOLOR 保留原图的位深和通道，部分 CMYK JPEG 或特殊色彩空间的图片
+// 用它解码得到空结果，但 COLOR / UNCHANGED 可以解码
+const DECODE_MODES: &[(ImreadModes, &str)] = &[
+    (ImreadModes::IMREAD_ANYCOLOR, "IMREAD_ANYCOLOR"),
+    (ImreadModes::IMREAD_COLOR_BGR, "IMREAD_COLOR"),
+    (ImreadModes::IMREAD_UNCHANGED, "IMREAD_UNCHANGED"),
+];
+
+// 按 DECODE_MODES 逐个尝试 imdecode，全部失败时返回 None
+fn decode_image(img_buf: &Vector<u8>) -> Option<Mat> {
+    decode_with_fallback(|mode| imdecode(img_buf, mode.into()))
+}
+
+fn decode_with_fallback(decode: impl Fn(ImreadModes) -> opencv::Result<Mat>) -> Option<Mat> {
+    for (index, &(mode, name)) in DECODE_MODES.iter().enumerate() {
+        match decode(mode) {
+            Ok(img) if !img.empty() => {
+                if index > 0 {
+                    println!("Source decoded with {} after {} failed", name, DECODE_MODES[0].1);
+                }
+                return Some(img);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("imdecode with {} failed: {}", name, e),
+        }
+    }
+    None
+}
+
+// 请求只涉及尺寸且不小于原图、输出格式与原图一致时，返回从文件头读取的原图尺寸
+fn original_fits_request(data: &[u8], source_format: ImageFormat, params: &ProcessingParams) -> Option<(i32, i32)> {
+    let same_format = match params.format.as_deref() {
+        Some("original") => true,
+        Some(format) => source_format.output_name() == Some(format),
+        None => source_format.output_name() == Some("jpg"),
+    };
+    // 只允许 width/height/format；其余任何改变输出的参数（方向、瓦片、区域、旋转、灰度等）都不能用原图代替
+    let others = ProcessingParams { width: None, height: None, format: None, ..params.clone() };
+    if !same_format
+        || !others.is_empty()
+        || others.blurhash
+        || others.optimize
+        || others.save_data == Some(true)
+        || others.quality_cap.is_some()
+    {
+        return None;
+    }
+
+    let size = imagesize::blob_size(data).ok()?;
+    let (width, height) = (i32::try_from(size.width).ok()?, i32::try_from(size.height).ok()?);
+    let fits = params.width.is_none_or(|w| w >= width) && params.height.is_none_or(|h| h >= height);
+    fits.then_some((width, height))
+}
+
+// OpenCV 的 IMWRITE_* 参数，使用数值而不是绑定常量，避免依赖编译时的 OpenCV 版本
+const IMWRITE_FLAGS: &[(&str, i32)] = &[
+    ("IMWRITE_JPEG_QUALITY", 1),
+    ("IMWRITE_JPEG_PROGRESSIVE", 2),
+    ("IMWRITE_JPEG_OPTIMIZE", 3),
+    ("IMWRITE_JPEG_RST_INTERVAL", 4),
+    ("IMWRITE_JPEG_LUMA_QUALITY", 5),
+    ("IMWRITE_JPEG_CHROMA_QUALITY", 6),
+    ("IMWRITE_JPEG_SAMPLING_FACTOR", 7),
+    ("IMWRITE_PNG_COMPRESSION", 16),
+    ("IMWRITE_PNG_STRATEGY", 17),
+    ("IMWRITE_PNG_BILEVEL", 18),
+    ("IMWRITE_PNG_FILTER", 19),
+    ("IMWRITE_WEBP_QUALITY", 64),
+    ("IMWRITE_AVIF_QUALITY", 512),
+    ("IMWRITE_AVIF_DEPTH", 513),
+    ("IMWRITE_AVIF_SPEED", 514),
+];
+
+// 校验并解析 encoder_params；格式或参数名未知时启动失败
+fn resolve_encoder_params(config: &HashMap<String, HashMap<String, i32>>) -> Result<HashMap<String, Vec<i32>>> {
+    let mut resolved = HashMap::new();
+    for (format, params) in config {
+        if !OUTPUT_FORMATS.contains(&format.as_str()) && !OPTIONAL_OUTPUT_FORMATS.contains(&format.as_str()) {
+            anyhow::bail!("encoder_params: unknown output format '{}'", format);
+        }
+        let mut flags = Vec::with_capacity(params.len() * 2);
+        for (name, value) in params {
+            // 配置加载时键名会被转为小写
+            let flag = IMWRITE_FLAGS
+                .iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(name))
+                .map(|(_, flag)| *flag)
+                .ok_or_else(|| anyhow::anyhow!("encoder_params.{}: unknown OpenCV parameter '{}'", format, name))?;
+            flags.push(flag);
+            flags.push(*value);
+        }
+        resolved.insert(format.clone(), flags);
+    }
+    Ok(resolved)
+}
+
+// 将 1x1 图片编码为指定格式，用于检测编解码器是否可用
+// 探测结果不是 Ok(true) 的格式
+fn codec_failures(formats: &[&str], probe: impl Fn(&str) -> Result<bool>) -> Vec<String> {
+    formats
+        .iter()
+        .filter(|format| {
+            let result = probe(format);
+            if let Err(ref e) = result {
+                eprintln!("OpenCV self-test failed for format '{}': {}", format, e);
+            }
+            !matches!(result, Ok(true))
+        })
+        .map(|format| format.to_string())
+        .collect()
+}
+
+fn encode_probe(format: &str) -> Result<bool> {
+    let (extension, _, _) = output_format(format);
+    let img = Mat::new_rows_cols_with_default(1, 1, CV_8UC3, Scalar::all(0.0))?;
+    let mut buf = Vector::new();
+    let encoded = imencode(extension, &img, &mut buf, &Vector::new())?;
+    Ok(encoded && !buf.is_empty())
+}
+
+// /bench 使用的 1920x1080 随机噪声 JPEG（噪声难以压缩，接近最坏情况），首次使用时生成
+fn bench_source() -> Result<&'static [u8]> {
+    static SOURCE: OnceLock<Vec<u8>> = OnceLock::new();
+    if let Some(source) = SOURCE.get() {
+        return Ok(source);
+    }
+    let mut img = Mat::new_rows_cols_with_default(1080, 1920, CV_8UC3, Scalar::all(0.0))?;
+    randu(&mut img, &Scalar::all(0.0), &Scalar::all(255.0))?;
+    let mut buf = Vector::new();
+    imencode(".jpg", &img, &mut buf, &Vector::from_slice(&[1, 90]))?;
+    Ok(SOURCE.get_or_init(|| buf.to_vec()))
+}
+
+// 放大时将目标边长对齐到原边长的整数倍（超过上限时向下取整），缩小时保持不变
+fn snap_to_integer_scale(source: i32, target: i32, max: i32) -> i32 {
+    if source <= 0 || target <= source {
+        return target;
+    }
+    let factor = (target as f64 / source as f64).round() as i32;
+    let factor = if source * factor > max { max / source } else { factor };
+    if factor >= 1 {
+        source * factor
+    } else {
+        target
+    }
+}
+
+// 总像素数超过 budget 时按 sqrt(budget / 像素数) 等比缩小；向下取整保证结果不超过 budget（边长至少为 1）
+fn fit_pixel_budget(width: i32, height: i32, budget: u64) -> (i32, i32) {
+    let pixels = width.max(1) as u64 * height.max(1) as u64;
+    if pixels <= budget {
+        return (width, height);
+    }
+    let scale = (budget as f64 / pixels as f64).sqrt();
+    let (mut width, mut height) = (((width as f64 * scale) as i32).max(1), ((height as f64 * scale) as i32).max(1));
+    // 浮点误差可能让乘积略超出 budget，逐像素收缩较长的一边
+    while width as u64 * height as u64 > budget && width.max(height) > 1 {
+        if width >= height {
+            width -= 1;
+        } else {
+            height -= 1;
+        }
+    }
+    (width, height)
+}
+
+// 计算指定宽高比下、居中的最大裁剪区域
+fn aspect_crop_rect(cols: i32, rows: i32, ar_width: i32, ar_height: i32) -> Rect {
+    let (cols64, rows64) = (cols as i64, rows as i64);
+    let (ar_w, ar_h) = (ar_width as i64, ar_height as i64);
+    if cols64 * ar_h > rows64 * ar_w {
+        // 原图更宽，裁掉左右两侧
+        let width = ((rows64 * ar_w / ar_h) as i32).max(1);
+        Rect::new((cols - width) / 2, 0, width, rows)
+    } else {
+        // 原图更高，裁掉上下两侧
+        let height = ((cols64 * ar_h / ar_w) as i32).max(1);
+        Rect::new(0, (rows - height) / 2, cols, height)
+    }
+}
+
+// 解析 "16:9" 形式的宽高比，两部分都必须为正整数
+fn parse_aspect_ratio(value: &str) -> Option<(i32, i32)> {
+    let (width, height) = value.split_once(':')?;
+    let width: i32 = width.trim().parse().ok()?;
+    let height: i32 = height.trim().parse().ok()?;
+    if width > 0 && height > 0 {
+        Some((width, height))
+    } else {
+        None
+    }
+}
+
+// 解析 "#RRGGBB" / "RRGGBB" 形式的颜色
+fn parse_hex_color(value: &str) -> Option<(u8, u8, u8)> {
+    let hex = value.trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
+    let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
+    let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
+    Some((r, g, b))
+}
+
+// 水印文字只保留可打印 ASCII（Hershey 字体不支持其他字符），并限制长度
+fn sanitize_watermark_text(text: &str, max_length: usize) -> Option<String> {
+    let cleaned: String = text
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .take(max_length)
+        .collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        None
+    } else {
+        Some(cleaned.to_string())
+    }
+}
+
+// 降级结果的缓存键，与完整质量的条目分开
+fn degraded_cache_key(cache_key: &str) -> String {
+    format!("degraded:{}", cache_key)
+}
+
+// 降级结果只缓存 cache_ttl_sec，不超过原有的 TTL
+fn degraded_ttl(ttl: Option<Duration>, degrade: &DegradeConfig) -> Option<Duration> {
+    let short = Duration::from_secs(degrade.cache_ttl_sec);
+    Some(ttl.map_or(short, |ttl| ttl.min(short)))
+}
+
+// 过载时实际使用的处理参数，以及写入 X-Image-Degraded 的降级说明
+fn degraded_params(params: &ProcessingParams, degrade: &DegradeConfig) -> (ProcessingParams, String) {
+    let mut degraded = params.clone();
+    degraded.quality_cap = Some(params.quality_cap.map_or(degrade.quality, |cap| cap.min(degrade.quality)));
+    let mut description = vec![format!("quality={}", degrade.quality)];
+    if degrade.skip_optional {
+        let mut skipped = Vec::new();
+        if degraded.blurhash {
+            degraded.blurhash = false;
+            skipped.push("blurhash");
+        }
+        if degraded.optimize {
+            degraded.optimize = false;
+            skipped.push("optimize");
+        }
+        if !skipped.is_empty() {
+            description.push(format!("skipped={}", skipped.join(",")));
+        }
+    }
+    (degraded, description.join("; "))
+}
+
+// passthrough=1 时丢弃其余参数，所有原图直出请求共用同一个缓存条目
+fn normalize_params(mut params: ProcessingParams) -> ProcessingParams {
+    if params.passthrough {
+        return ProcessingParams { passthrough: true, ttl_override: params.ttl_override, download: params.download, ..Default::default() };
+    }
+    // 未经 Accept 协商的 format=auto（预热、清单等）按不支持 AVIF/WebP 的客户端处理，保证缓存键对应实际输出格式
+    if params.format.as_deref() == Some("auto") {
+        params.format = Some("original".to_string());
+        params.auto_format = true;
+    }
+    params
+}
+
+// 由原图 key 和处理参数计算缓存键
+pub fn cache_key(image_key: &str, params: &ProcessingParams) -> String {
+    let mut hasher = DefaultHasher::new();
+    image_key.hash(&mut hasher);
+    params.hash(&mut hasher);
+    hasher.finish().to_string()
+}
+
+// 解析布尔型参数：1/true/yes/on 视为开启
+fn parse_bool(value: &str) -> bool {
+    matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+/// 图片请求的查询参数取值类型，/openapi.json 据此生成参数 schema
+pub enum ParamKind {
+    Integer { min: i32, max: Option<i32> },
+    Boolean,
+    Text,
+    // 输出格式：OUTPUT_FORMATS、OPTIONAL_OUTPUT_FORMATS 以及 original/auto
+    Format,
+    // 逗号分隔的输出格式列表
+    FormatList,
+    Interpolation,
+    Orientation,
+    AspectRatio,
+    Choice(&'static [&'static str]),
+}
+
+pub struct QueryParam {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    pub description: &'static str,
+}
+
+// 图片请求支持的查询参数；新增参数时和 parse_query_params 一起修改
+pub const QUERY_PARAMS: &[QueryParam] = &[
+    QueryParam { name: "width", kind: ParamKind::Integer { min: 1, max: None }, description: "Target width in pixels, clamped to the configured maximum" },
+    QueryParam { name: "height", kind: ParamKind::Integer { min: 1, max: None }, description: "Target height in pixels, clamped to the configured maximum" },
+    QueryParam { name: "quality", kind: ParamKind::Integer { min: 1, max: Some(100) }, description: "Encoder quality" },
+    QueryParam { name: "format", kind: ParamKind::Format, description: "Output format; original keeps the source format, auto negotiates from Accept, auto-content picks png for graphics and jpg for photos" },
+    QueryParam { name: "profile", kind: ParamKind::Text, description: "Name of a configured parameter profile" },
+    QueryParam { name: "ar", kind: ParamKind::AspectRatio, description: "Centered aspect ratio crop applied before resizing, e.g. 16:9" },
+    QueryParam { name: "orient", kind: ParamKind::Orientation, description: "Rotate the source 90 degrees when its orientation differs" },
+    QueryParam { name: "square", kind: ParamKind::Integer { min: 1, max: None }, description: "Square NxN output; overrides width, height and ar" },
+    QueryParam { name: "square_mode", kind: ParamKind::Choice(&["pad", "crop"]), description: "pad (default) fits the image inside and fills with bg; crop cover-crops to a square" },
+    QueryParam { name: "bg", kind: ParamKind::Text, description: "Padding color as RRGGBB; when omitted, transparent for images with alpha in png/webp/avif output, otherwise white" },
+    QueryParam { name: "max_pixels", kind: ParamKind::Integer { min: 1, max: None }, description: "Downscale (keeping the aspect ratio) so width x height does not exceed this many pixels" },
+    QueryParam { name: "only_if", kind: ParamKind::Choice(&["larger"]), description: "Only resize when the source exceeds the requested size (never upscale)" },
+    QueryParam { name: "interpolation", kind: ParamKind::Interpolation, description: "Resize algorithm" },
+    QueryParam { name: "pixel_art", kind: ParamKind::Boolean, description: "Nearest-neighbour resizing with integer upscale factors" },
+    QueryParam { name: "blurhash", kind: ParamKind::Boolean, description: "Add an X-BlurHash header for the output" },
+    QueryParam { name: "passthrough", kind: ParamKind::Boolean, description: "Return the original object unchanged, ignoring other parameters" },
+    QueryParam { name: "download", kind: ParamKind::Boolean, description: "Serve with Content-Disposition: attachment" },
+    QueryParam { name: "preview", kind: ParamKind::Boolean, description: "Fast low-detail preview decoded from the leading bytes of a progressive JPEG" },
+    QueryParam { name: "optimize", kind: ParamKind::Boolean, description: "Losslessly re-compress PNG output (png-optimize feature)" },
+    QueryParam { name: "text", kind: ParamKind::Text, description: "Text watermark (printable ASCII)" },
+    QueryParam { name: "formats", kind: ParamKind::FormatList, description: "Return several output formats as one multipart/mixed response" },
+    QueryParam { name: "encoding", kind: ParamKind::Choice(&["base64"]), description: "Return the output as a base64 data URI" },
+    QueryParam { name: "ttl", kind: ParamKind::Integer { min: 0, max: None }, description: "Cache TTL in seconds for the produced entry (admin token required, otherwise ignored)" },
+    QueryParam { name: "token", kind: ParamKind::Text, description: "Signed transform policy token (security.policy_secret); the request must fall within its policy" },
+];
+
+// 不在 QUERY_PARAMS 中的参数名，按名称排序
+pub fn unknown_query_params(params: &HashMap<String, String>) -> Vec<String> {
+    let mut unknown: Vec<String> = params
+        .keys()
+        .filter(|name| !QUERY_PARAMS.iter().any(|param| param.name == name.as_str()))
+        .cloned()
+        .collect();
+    unknown.sort();
+    unknown
+}
+
+// strict_params 开启时未知参数（多为拼写错误，如 widht）返回 400；关闭时忽略
+pub fn check_query_params(params: &HashMap<String, String>, config: &ImageProcessingConfig) -> Result<()> {
+    if !config.strict_params {
+        return Ok(());
+    }
+    let unknown = unknown_query_params(params);
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(RequestError::bad_request(format!("Unknown query parameters: {}", unknown.join(", "))).into())
+}
+
+pub fn parse_query_params(params: HashMap<String, String>, config: &ImageProcessingConfig) -> ProcessingParams {
+    parse_params(None, params, config)
+}
+
+// 针对某个对象（或 "bucket/prefix"）解析参数，额外补齐该 bucket 的 bucket_defaults
+pub fn parse_query_params_for_key(
+    image_key: &str,
+    params: HashMap<String, String>,
+    config: &ImageProcessingConfig,
+) -> ProcessingParams {
+    parse_params(Some(image_key), params, config)
+}
+
+fn parse_params(image_key: Option<&str>, mut params: HashMap<String, String>, config: &ImageProcessingConfig) -> ProcessingParams {
+    // 展开配置档：请求中显式给出的参数优先，其余由配置档补齐
+    if let Some(name) = params.get("profile").cloned() {
+        // 配置加载时键名会被转为小写
+        match config.profiles.get(&name.to_lowercase()) {
+            Some(profile) => {
+                for (key, value) in profile {
+                    params.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+            None => eprintln!("Unknown processing profile '{}', ignoring", name),
+        }
+    }
+    // 再由 bucket 默认参数补齐；合并后的参数参与缓存键计算，修改默认值不会命中旧的派生图
+    let bucket = image_key.map(|key| key.split_once('/').map_or(key, |(bucket, _)| bucket));
+    if let Some(defaults) = bucket.and_then(|bucket| config.bucket_defaults.get(&bucket.to_lowercase())) {
+        for (key, value) in defaults {
+            params.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+
+    let mut processing = ProcessingParams {
+        width: params.get("width").and_then(|w| w.parse().ok()),
+        height: params.get("height").and_then(|h| h.parse().ok()),
+        quality: params.get("quality")
+            .and_then(|q| q.parse().ok())
+            .map(|q: i32| config.clamp_quality(q)),
+        format: params.get("format").cloned(),
+        text: params.get("text")
+            .and_then(|t| sanitize_watermark_text(t, config.text_watermark.max_length)),
+        aspect_ratio: params.get("ar").and_then(|ar| parse_aspect_ratio(ar)),
+        pixel_art: params.get("pixel_art").is_some_and(|v| parse_bool(v)),
+        blurhash: params.get("blurhash").is_some_and(|v| parse_bool(v)),
+        optimize: params.get("optimize").is_some_and(|v| parse_bool(v)),
+        auto_format: false,
+        passthrough: params.get("passthrough").is_some_and(|v| parse_bool(v)),
+        interpolation: params.get("interpolation").and_then(|v| parse_interpolation(v)),
+        orient: params.get("orient").and_then(|v| parse_orientation(v)),
+        only_if_larger: params.get("only_if").is_some_and(|v| v.eq_ignore_ascii_case("larger")),
+        preview: params.get("preview").is_some_and(|v| parse_bool(v)),
+        save_data: None,
+        download: params.get("download").is_some_and(|v| parse_bool(v)),
+        tile: None,
+        region: None,
+        scale_pct: None,
+        max_pixels: params.get("max_pixels").and_then(|v| v.parse().ok()).filter(|n| *n > 0),
+        fit_inside: false,
+        rotation: None,
+        grayscale: false,
+        square: None,
+        square_crop: false,
+        background: params.get("bg").and_then(|v| parse_hex_color(v)),
+        ttl_override: None,
+        quality_cap: None,
+    };
+
+    // square=N 换算为对应的尺寸参数：补边模式等比缩放到 N×N 框内，裁剪模式先裁成 1:1 再缩放
+    if let Some(size) = params.get("square").and_then(|v| v.parse::<i32>().ok()).filter(|size| *size > 0) {
+        processing.square = Some(size);
+        processing.square_crop = params.get("square_mode").is_some_and(|mode| mode.eq_ignore_ascii_case("crop"));
+        processing.width = Some(size);
+        processing.height = Some(size);
+        if processing.square_crop {
+            processing.aspect_ratio = Some((1, 1));
+        } else {
+            processing.aspect_ratio = None;
+            processing.fit_inside = true;
+        }
+    }
+    processing
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::CacheConfig, mock_s3::MockS3, s3_client::S3Config};
+
+    // 不访问 S3 的处理器，原图由测试直接传入
+    async fn processor(config: serde_json::Value) -> ImageProcessor {
+        let s3_config = S3Config {
+            endpoint: "http://127.0.0.1:9".to_string(),
+            access_key: "test".to_string(),
+            secret_key: "test".to_string(),
+            region: String::new(),
+            use_path_style: true,
+            key_prefix: String::new(),
+            circuit_breaker: None,
+            throttle_backoff: None,
+        };
+        processor_with(s3_config, config).await
+    }
+
+    // 从内存 S3 读取原图的处理器
+    async fn processor_on(s3: &MockS3, config: serde_json::Value) -> ImageProcessor {
+        processor_with(s3.config(), config).await
+    }
+
+    async fn processor_with(s3_config: S3Config, config: serde_json::Value) -> ImageProcessor {
+        let cache_config: CacheConfig = serde_json::from_value(serde_json::json!({
+            "max_capacity_mb": 16,
+            "time_to_live_sec": 60,
+            "time_to_idle_sec": 60,
+        }))
+        .unwrap();
+        let mut processing = serde_json::json!({ "default_quality": 80, "max_width": 1920, "max_height": 1080 });
+        if let (Some(processing), serde_json::Value::Object(extra)) = (processing.as_object_mut(), config) {
+            processing.extend(extra);
+        }
+        let s3_client = S3Client::new(s3_config).await.unwrap();
+        ImageProcessor::new(s3_client, ImageCache::new(cache_config), serde_json::from_value(processing).unwrap()).unwrap()
+    }
+
+    fn jpeg(width: i32, height: i32) -> Vec<u8> {
+        let img = Mat::new_rows_cols_with_default(height, width, CV_8UC3, Scalar::all(128.0)).unwrap();
+        let mut buf = Vector::new();
+        assert!(imencode(".jpg", &img, &mut buf, &Vector::new()).unwrap());
+        buf.to_vec()
+    }
+
+    // 随机噪声难以压缩，编码大小能反映质量
+    fn noisy_jpeg(width: i32, height: i32) -> Vec<u8> {
+        let mut img = Mat::new_rows_cols_with_default(height, width, CV_8UC3, Scalar::all(0.0)).unwrap();
+        randu(&mut img, &Scalar::all(0.0), &Scalar::all(255.0)).unwrap();
+        let mut buf = Vector::new();
+        assert!(imencode(".jpg", &img, &mut buf, &Vector::from_slice(&[1, 100])).unwrap());
+        buf.to_vec()
+    }
+
+    fn original(data: Vec<u8>) -> S3Object {
+        S3Object { data, metadata: HashMap::new(), expires_at: None, last_modified: None }
+    }
+
+    fn degrade_config(quality: i32) -> DegradeConfig {
+        serde_json::from_value(serde_json::json!({ "max_inflight": 2, "quality": quality })).unwrap()
+    }
+
+    #[test]
+    fn degraded_params_cap_quality_and_skip_optional_work() {
+        let params = ProcessingParams { width: Some(100), quality: Some(90), blurhash: true, optimize: true, ..Default::default() };
+        let (degraded, description) = degraded_params(&params, &degrade_config(60));
+        assert_eq!(degraded.quality_cap, Some(60));
+        assert!(!degraded.blurhash && !degraded.optimize);
+        assert_eq!(description, "quality=60; skipped=blurhash,optimize");
+
+        // 已有更低的上限（如 Save-Data）时保留
+        let (degraded, _) = degraded_params(&ProcessingParams { quality_cap: Some(40), ..params.clone() }, &degrade_config(60));
+        assert_eq!(degraded.quality_cap, Some(40));
+
+        let keep: DegradeConfig = serde_json::from_value(serde_json::json!({ "max_inflight": 2, "skip_optional": false })).unwrap();
+        let (degraded, description) = degraded_params(&params, &keep);
+        assert!(degraded.blurhash && degraded.optimize);
+        assert_eq!(description, format!("quality={}", keep.quality));
+    }
+
+    #[tokio::test]
+    async fn high_concurrency_lowers_the_effective_quality() {
+        let processor = processor(serde_json::json!({ "degrade": { "max_inflight": 2, "quality": 40, "cache_ttl_sec": 30 } })).await;
+        let key = "bucket/photo.jpg";
+        let params = ProcessingParams { width: Some(128), quality: Some(90), format: Some("jpg".to_string()), ..Default::default() };
+        let source = noisy_jpeg(256, 256);
+
+        let full = processor.process_original(key, original(source.clone()), false, &params).await.unwrap();
+        assert_eq!(full.degraded, None);
+
+        // 另有两个请求在处理中，本请求是第三个，超过 max_inflight
+        let _busy: Vec<_> = (0..2).map(|_| JobSlot::new(processor.inflight_jobs.clone())).collect();
+        let degrade = processor.degrade_under_load(&params, processor.inflight_jobs.load(Ordering::SeqCst) + 1).unwrap();
+        let (degraded_params, _) = degraded_params(&params, degrade);
+        assert_eq!(processor.encode_params(1, 128 * 128, &degraded_params, "jpg")[1], 40);
+        assert_eq!(processor.encode_params(1, 128 * 128, &params, "jpg")[1], 90);
+
+        let degraded = processor.process_original(key, original(source), false, &params).await.unwrap();
+        assert_eq!(degraded.degraded.as_deref(), Some("quality=40"));
+        assert_eq!(degraded.ttl, Some(Duration::from_secs(30)));
+        assert!(degraded.data.len() < full.data.len(), "{} >= {}", degraded.data.len(), full.data.len());
+
+        // 降级结果不占用完整质量的缓存键
+        let cache_key = processor.cache_key(key, &params);
+        processor.store_processed(key, cache_key.clone(), &params, degraded).await;
+        assert!(!processor.cache.contains(&cache_key));
+        assert!(processor.cache.contains(&degraded_cache_key(&cache_key)));
+        processor.store_processed(key, cache_key.clone(), &params, full).await;
+        assert!(processor.cache.get(&cache_key).await.unwrap().degraded.is_none());
+    }
+
+    #[tokio::test]
+    async fn requests_with_different_content_types_never_share_a_cache_key() {
+        let processor = processor(serde_json::json!({})).await;
+        let key = "bucket/photo.jpg";
+        let source = jpeg(64, 64);
+        let auto = ProcessingParams { width: Some(32), format: Some("auto".to_string()), ..Default::default() };
+
+        // format=auto 协商为 webp 与（不支持 webp 时）沿用原图 jpg
+        let mut webp = auto.clone();
+        processor.resolve_auto_format(&mut webp, Some("image/webp,*/*"));
+        let mut jpg = auto.clone();
+        processor.resolve_auto_format(&mut jpg, Some("image/jpeg"));
+        assert_ne!(processor.cache_key(key, &webp), processor.cache_key(key, &jpg));
+        let served = |image: ProcessedImage| (image.content_type.clone(), detect_format(&image.data).map(|f| f.content_type()));
+        assert_eq!(
+            served(processor.process_original(key, original(source.clone()), false, &webp).await.unwrap()),
+            ("image/webp".to_string(), Some("image/webp"))
+        );
+        assert_eq!(
+            served(processor.process_original(key, original(source.clone()), false, &jpg).await.unwrap()),
+            ("image/jpeg".to_string(), Some("image/jpeg"))
+        );
+
+        // 未指定 format 的处理请求实际输出 jpg，与 format=jpg 共用条目；原样返回原图的请求不共用
+        let omitted = ProcessingParams { width: Some(32), ..Default::default() };
+        let explicit = ProcessingParams { format: Some("jpg".to_string()), ..omitted.clone() };
+        assert_eq!(processor.cache_key(key, &omitted), processor.cache_key(key, &explicit));
+        let png_key = "bucket/logo.png";
+        let untouched = ProcessingParams::default();
+        assert_ne!(
+            processor.cache_key(png_key, &untouched),
+            processor.cache_key(png_key, &ProcessingParams { format: Some("jpg".to_string()), ..Default::default() })
+        );
+    }
+
+    #[tokio::test]
+    async fn encode_fallback_is_served_with_the_fallback_content_type() {
+        let processor = processor(serde_json::json!({ "max_width": 20000, "encode_fallbacks": { "webp": "jpg" } })).await;
+        let key = "bucket/strip.jpg";
+        // WebP 的尺寸上限是 16383，超出时编码失败，按 encode_fallbacks 改用 jpg
+        let params = ProcessingParams { format: Some("webp".to_string()), ..Default::default() };
+        let image = processor.process_original(key, original(jpeg(16400, 1)), false, &params).await.unwrap();
+        assert_eq!(image.content_type, "image/jpeg");
+        assert_eq!(detect_format(&image.data).map(|f| f.content_type()), Some("image/jpeg"));
+
+        // 回退结果只存在 webp 请求自己的键下，与 format=jpg 的条目互不干扰
+        let webp_key = processor.cache_key(key, &params);
+        let jpg_key = processor.cache_key(key, &ProcessingParams { format: Some("jpg".to_string()), ..Default::default() });
+        assert_ne!(webp_key, jpg_key);
+        processor.store_processed(key, webp_key.clone(), &params, image).await;
+        assert_eq!(processor.cache.get(&webp_key).await.unwrap().content_type, "image/jpeg");
+        assert!(!processor.cache.contains(&jpg_key));
+    }
+
+    #[test]
+    fn requested_quality_is_clamped_to_the_configured_range() {
+        let config: ImageProcessingConfig = serde_json::from_value(serde_json::json!({
+            "default_quality": 80,
+            "max_width": 1920,
+            "max_height": 1080,
+            "quality_min": 40,
+            "quality_max": 90,
+        }))
+        .unwrap();
+        assert_eq!(config.clamp_quality(10), 40);
+        assert_eq!(config.clamp_quality(95), 90);
+        assert_eq!(config.clamp_quality(70), 70);
+
+        let query = |quality: &str| HashMap::from([("quality".to_string(), quality.to_string())]);
+        assert_eq!(parse_query_params(query("10"), &config).quality, Some(40));
+        assert_eq!(parse_query_params(query("100"), &config).quality, Some(90));
+    }
+
+    #[test]
+    fn jpeg_quality_is_remapped_to_webp_and_avif_equivalents() {
+        assert_eq!(equivalent_quality(80, "webp"), 73);
+        assert_eq!(equivalent_quality(80, "avif"), 53);
+        assert_eq!(equivalent_quality(100, "webp"), 100);
+        // 对照点之间线性插值
+        assert_eq!(equivalent_quality(75, "webp"), 69);
+        assert_eq!(equivalent_quality(75, "avif"), 49);
+        // jpg/png 不换算
+        assert_eq!(equivalent_quality(80, "jpg"), 80);
+        assert_eq!(equivalent_quality(80, "png"), 80);
+        // 换算结果随 JPEG 质量单调不减
+        for format in ["webp", "avif"] {
+            let qualities: Vec<i32> = (1..=100).map(|q| equivalent_quality(q, format)).collect();
+            assert!(qualities.windows(2).all(|w| w[0] <= w[1]), "{}", format);
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiated_formats_encode_with_the_equivalent_quality() {
+        let processor = processor(serde_json::json!({})).await;
+        let negotiated = ProcessingParams { quality: Some(80), auto_format: true, ..Default::default() };
+        assert_eq!(processor.encode_params(64, 100, &negotiated, "webp")[1], 73);
+        assert_eq!(processor.encode_params(64, 100, &negotiated, "avif")[1], 53);
+        // 明确请求 format=webp 时按原值使用
+        let explicit = ProcessingParams { quality: Some(80), format: Some("webp".to_string()), ..Default::default() };
+        assert_eq!(processor.encode_params(64, 100, &explicit, "webp")[1], 80);
+    }
+
+    #[test]
+    fn pixel_budget_keeps_the_aspect_ratio_of_wide_and_tall_sources() {
+        assert_eq!(fit_pixel_budget(4000, 1000, 1_000_000), (2000, 500));
+        assert_eq!(fit_pixel_budget(1000, 4000, 1_000_000), (500, 2000));
+        // 极端比例下短边至少保留 1 像素
+        assert_eq!(fit_pixel_budget(10000, 1, 100), (100, 1));
+        assert_eq!(fit_pixel_budget(1, 10000, 100), (1, 100));
+        // 不超出预算时原样返回
+        assert_eq!(fit_pixel_budget(3000, 2000, 6_000_000), (3000, 2000));
+
+        let (width, height) = fit_pixel_budget(4000, 3000, 1_000_000);
+        assert!(width as u64 * height as u64 <= 1_000_000);
+        assert!((width as f64 / height as f64 - 4.0 / 3.0).abs() < 0.01, "{}x{}", width, height);
+    }
+
+    #[test]
+    fn aspect_ratios_parse_only_positive_integer_pairs() {
+        assert_eq!(parse_aspect_ratio("16:9"), Some((16, 9)));
+        assert_eq!(parse_aspect_ratio(" 1 : 1 "), Some((1, 1)));
+        for invalid in ["16x9", "0:1", "1:-1", "a:b", "16:", "1.5:1"] {
+            assert_eq!(parse_aspect_ratio(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn aspect_crop_is_centered_and_has_the_requested_ratio() {
+        // 4:3 横图裁成 1:1：去掉左右两侧
+        let rect = aspect_crop_rect(400, 300, 1, 1);
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (50, 0, 300, 300));
+        // 竖图裁成 16:9：去掉上下两侧
+        let rect = aspect_crop_rect(900, 1600, 16, 9);
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (0, 547, 900, 506));
+        // 比例已经一致时不裁剪
+        let rect = aspect_crop_rect(400, 300, 4, 3);
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (0, 0, 400, 300));
+    }
+
+    #[tokio::test]
+    async fn square_aspect_ratio_on_a_4_3_source_comes_out_square() {
+        let processor = processor(serde_json::json!({})).await;
+        let config = processor.config.clone();
+        let query = HashMap::from([("ar".to_string(), "1:1".to_string()), ("format".to_string(), "jpg".to_string())]);
+        let params = parse_query_params(query, &config);
+        assert_eq!(params.aspect_ratio, Some((1, 1)));
+        let image = processor.process_source(jpeg(400, 300), &params, false).await.unwrap();
+        assert_eq!((image.width, image.height), (Some(300), Some(300)));
+    }
+
+    #[test]
+    fn cache_hit_logging_samples_the_configured_fraction() {
+        let logged = |rate: f64| (1..=10_000u64).filter(|&hit| should_sample(hit, rate)).count();
+        assert_eq!(logged(0.01), 100);
+        assert_eq!(logged(0.1), 1000);
+        assert_eq!(logged(0.25), 2500);
+        assert_eq!(logged(1.0), 10_000);
+        assert_eq!(logged(0.0), 0);
+        // 无法整除的比例取最接近的间隔：1/0.3 ≈ 3
+        assert!((logged(0.3) as f64 / 10_000.0 - 1.0 / 3.0).abs() < 0.01);
+    }
+
+    fn processing_config(extra: serde_json::Value) -> ImageProcessingConfig {
+        let mut config = serde_json::json!({ "default_quality": 80, "max_width": 1920, "max_height": 1080 });
+        if let (Some(config), serde_json::Value::Object(extra)) = (config.as_object_mut(), extra) {
+            config.extend(extra);
+        }
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn misspelled_params_are_rejected_only_in_strict_mode() {
+        let query = HashMap::from([
+            ("widht".to_string(), "300".to_string()),
+            ("qualty".to_string(), "50".to_string()),
+            ("format".to_string(), "webp".to_string()),
+        ]);
+        assert_eq!(unknown_query_params(&query), vec!["qualty".to_string(), "widht".to_string()]);
+
+        let strict = processing_config(serde_json::json!({ "strict_params": true }));
+        let e = check_query_params(&query, &strict).unwrap_err();
+        let e = e.downcast_ref::<RequestError>().unwrap();
+        assert_eq!((e.status, e.message.as_str()), (400, "Unknown query parameters: qualty, widht"));
+
+        // 宽松模式下忽略拼错的参数，其余参数照常生效
+        let lenient = processing_config(serde_json::json!({}));
+        check_query_params(&query, &lenient).unwrap();
+        let params = parse_query_params(query, &lenient);
+        assert_eq!((params.width, params.quality, params.format.as_deref()), (None, None, Some("webp")));
+    }
+
+    #[test]
+    fn known_params_pass_strict_mode() {
+        let strict = processing_config(serde_json::json!({ "strict_params": true }));
+        let query: HashMap<String, String> =
+            ["width", "height", "quality", "format", "token", "ttl"].iter().map(|name| (name.to_string(), "1".to_string())).collect();
+        check_query_params(&query, &strict).unwrap();
+    }
+
+    #[tokio::test]
+    async fn metadata_cache_ttl_drives_the_cache_expiry() {
+        let processor = processor(serde_json::json!({})).await;
+        let key = "bucket/photo.jpg";
+        let params = ProcessingParams { width: Some(32), format: Some("jpg".to_string()), ..Default::default() };
+        let source = S3Object {
+            metadata: HashMap::from([("cache-ttl".to_string(), "1".to_string())]),
+            ..original(jpeg(64, 64))
+        };
+        let image = processor.process_original(key, source, false, &params).await.unwrap();
+        assert_eq!(image.ttl, Some(Duration::from_secs(1)));
+
+        let cache_key = processor.cache_key(key, &params);
+        processor.store_processed(key, cache_key.clone(), &params, image).await;
+        let cached = processor.cache.get(&cache_key).await.unwrap();
+        assert!(processor.cache_expires_in(&cached).unwrap() <= Duration::from_secs(1));
+        // 全局 TTL 为 60 秒，条目按元数据的 1 秒过期
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(processor.cache.get(&cache_key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_manifest_hit_is_served_from_s3_without_processing() {
+        let s3 = MockS3::start().await;
+        // 预生成对象不是可解码的图片，原图也不存在：命中时只能原样返回
+        s3.put("derived/hero-300.webp", b"pre-rendered hero".to_vec());
+        s3.put("photos/other.jpg", jpeg(400, 200));
+        let path = std::env::temp_dir().join(format!("manifest-{}.json", std::process::id()));
+        std::fs::write(&path, r#"[{ "key": "photos/hero.jpg", "params": { "width": 300, "format": "webp" }, "object": "derived/hero-300.webp" }]"#).unwrap();
+        let config = processing_config(serde_json::json!({}));
+        let manifest = Manifest::load(path.to_str().unwrap(), &config, "").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let processor = processor_on(&s3, serde_json::json!({})).await.with_manifest(manifest);
+
+        let query = |width: &str| HashMap::from([("width".to_string(), width.to_string()), ("format".to_string(), "webp".to_string())]);
+        let params = parse_query_params_for_key("photos/hero.jpg", query("300"), &config);
+        let (image, source) = processor.get_or_process_image("photos/hero.jpg".to_string(), params).await.unwrap();
+        assert_eq!(source, "manifest");
+        assert_eq!(image.data, b"pre-rendered hero");
+
+        // 清单之外的参数组合照常处理
+        let params = parse_query_params_for_key("photos/other.jpg", query("100"), &config);
+        let (image, source) = processor.get_or_process_image("photos/other.jpg".to_string(), params).await.unwrap();
+        assert_eq!(source, "newly_processed");
+        assert_eq!((image.content_type.as_str(), image.width), ("image/webp", Some(100)));
+    }
+
+    #[tokio::test]
+    async fn work_over_the_inflight_memory_ceiling_is_shed_with_503() {
+        // 1MB 上限：400x300 的 BGR 图解码后约 352KB
+        let processor = processor(serde_json::json!({ "max_inflight_memory_mb": 1 })).await;
+        let params = ProcessingParams { width: Some(100), format: Some("jpg".to_string()), ..Default::default() };
+
+        // 单张解码后就超过上限
+        let err = processor.process_source(jpeg(1000, 1000), &params, false).await.unwrap_err();
+        let err = err.downcast_ref::<RequestError>().unwrap();
+        assert_eq!(err.status, 503);
+        assert!(err.message.starts_with("In-flight image memory limit exceeded"), "{}", err.message);
+
+        // 其他请求占着 800KB 时，本来放得下的图也被拒绝；释放后照常处理
+        let other = processor.reserve_memory(800 * 1024).unwrap();
+        let err = processor.process_source(jpeg(400, 300), &params, false).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().map(|e| e.status), Some(503));
+        drop(other);
+        let image = processor.process_source(jpeg(400, 300), &params, false).await.unwrap();
+        assert_eq!(image.width, Some(100));
+        assert_eq!(processor.inflight_memory.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn requests_are_shed_while_the_probe_reports_low_memory() {
+        const MB: u64 = 1024 * 1024;
+        // u64::MAX 表示探针读取失败
+        let available = Arc::new(AtomicU64::new(100 * MB));
+        let probe: MemoryProbe = {
+            let available = available.clone();
+            Arc::new(move || Some(available.load(Ordering::SeqCst)).filter(|&bytes| bytes != u64::MAX))
+        };
+        let processor = processor(serde_json::json!({})).await.with_free_memory_guard(512 * MB, probe);
+        let params = ProcessingParams { width: Some(50), format: Some("jpg".to_string()), ..Default::default() };
+
+        let err = processor.process_source(jpeg(200, 100), &params, false).await.unwrap_err();
+        let err = err.downcast_ref::<RequestError>().unwrap();
+        assert_eq!((err.status, err.message.as_str()), (503, "Insufficient free memory, try again later"));
+
+        available.store(2048 * MB, Ordering::SeqCst);
+        assert_eq!(processor.process_source(jpeg(200, 100), &params, false).await.unwrap().width, Some(50));
+
+        // 读不到可用内存时不拦截
+        available.store(u64::MAX, Ordering::SeqCst);
+        assert!(processor.process_source(jpeg(200, 100), &params, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_derivative_cap_follow_the_configured_policy() {
+        let key = "bucket/popular.jpg";
+        let sized = |width: i32| ProcessingParams { width: Some(width), format: Some("jpg".to_string()), ..Default::default() };
+        // 已缓存 100 和 400 宽的两个派生图，上限为 2
+        let seeded = |policy: &'static str| async move {
+            let processor = processor(serde_json::json!({ "max_derivatives_per_original": 2, "on_derivative_limit": policy })).await;
+            for width in [100, 400] {
+                let image = ProcessedImage { width: Some(width), ..ProcessedImage::unprocessed(jpeg(8, 8)) };
+                processor.store_processed(key, processor.cache_key(key, &sized(width)), &sized(width), image).await;
+            }
+            processor
+        };
+
+        let processor = seeded("reject").await;
+        let err = processor.enforce_derivative_limit(key, &processor.cache_key(key, &sized(350)), &sized(350)).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().map(|e| e.status), Some(400));
+        // 已有的尺寸不受上限影响
+        assert!(processor.enforce_derivative_limit(key, &processor.cache_key(key, &sized(400)), &sized(400)).await.unwrap().is_none());
+
+        let processor = seeded("nearest").await;
+        let nearest = processor.enforce_derivative_limit(key, &processor.cache_key(key, &sized(350)), &sized(350)).await.unwrap();
+        assert_eq!(nearest.unwrap().width, Some(400));
+        // 没有同格式的派生图可替代时仍返回 400
+        let webp = ProcessingParams { format: Some("webp".to_string()), ..sized(350) };
+        assert!(processor.enforce_derivative_limit(key, &processor.cache_key(key, &webp), &webp).await.is_err());
+
+        let processor = seeded("evict_oldest").await;
+        assert!(processor.enforce_derivative_limit(key, &processor.cache_key(key, &sized(350)), &sized(350)).await.unwrap().is_none());
+        assert!(!processor.cache.contains(&processor.cache_key(key, &sized(100))));
+        assert!(processor.cache.contains(&processor.cache_key(key, &sized(400))));
+    }
+
+    #[tokio::test]
+    async fn evicting_one_variant_leaves_the_other_variants_cached() {
+        let processor = processor(serde_json::json!({})).await;
+        let key = "bucket/photo.jpg";
+        let config = processor.config.clone();
+        let variant = |query: &[(&str, &str)]| {
+            let query = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            normalize_params(parse_query_params(query, &config))
+        };
+        let variants = [variant(&[("width", "100")]), variant(&[("width", "200")]), variant(&[("width", "100"), ("format", "webp")])];
+        for params in &variants {
+            processor.store_processed(key, processor.cache_key(key, params), params, ProcessedImage::unprocessed(jpeg(8, 8))).await;
+        }
+
+        let (cache_key, existed) = processor.evict_derivative(key, variant(&[("width", "100")])).await;
+        assert!(existed);
+        assert_eq!(cache_key, processor.cache_key(key, &variants[0]));
+        assert!(!processor.cache.contains(&cache_key));
+        for params in &variants[1..] {
+            assert!(processor.cache.contains(&processor.cache_key(key, params)), "{:?}", params);
+        }
+        // 再次移除同一个派生图时报告不存在
+        assert!(!processor.evict_derivative(key, variant(&[("width", "100")])).await.1);
+    }
+
+    #[tokio::test]
+    async fn denied_keys_get_403_without_an_s3_call_and_other_keys_are_served() {
+        let s3 = MockS3::start().await;
+        s3.put("bucket/private/payroll.jpg", jpeg(64, 64));
+        s3.put("bucket/public/cat.jpg", jpeg(64, 64));
+        let patterns = vec![r"^bucket/private/".to_string(), r"\.psd$".to_string()];
+        let processor = processor_on(&s3, serde_json::json!({})).await.with_deny_patterns(&patterns).unwrap();
+        let params = ProcessingParams { width: Some(32), format: Some("jpg".to_string()), ..Default::default() };
+
+        for key in ["bucket/private/payroll.jpg", "bucket/public/layers.psd"] {
+            let err = processor.get_or_process_image(key.to_string(), params.clone()).await.unwrap_err();
+            assert_eq!(err.downcast_ref::<RequestError>().map(|e| e.status), Some(403), "{}", key);
+        }
+        assert_eq!(s3.requests(), 0);
+
+        let (image, source) = processor.get_or_process_image("bucket/public/cat.jpg".to_string(), params).await.unwrap();
+        assert_eq!((source.as_str(), image.width), ("newly_processed", Some(32)));
+    }
+
+    #[tokio::test]
+    async fn invalid_deny_patterns_are_a_config_error() {
+        let processor = processor(serde_json::json!({})).await;
+        let err = processor.with_deny_patterns(&["(unclosed".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("security.deny_patterns"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn only_allowlisted_output_formats_can_be_requested() {
+        let unrestricted = processor(serde_json::json!({})).await;
+        let processor = processor(serde_json::json!({ "allowed_formats": ["webp", "jpg"] })).await;
+        let format = |format: &str| ProcessingParams { format: Some(format.to_string()), ..Default::default() };
+        processor.validate_params(&format("webp")).unwrap();
+        processor.validate_params(&format("JPG")).unwrap();
+        let err = processor.validate_params(&format("tiff")).unwrap_err();
+        let err = err.downcast_ref::<RequestError>().unwrap();
+        assert_eq!((err.status, err.message.as_str()), (400, "format 'tiff' is not allowed"));
+        // format=auto 协商出的格式按 "auto" 校验
+        let auto = ProcessingParams { auto_format: true, ..format("webp") };
+        assert!(processor.validate_params(&auto).is_err());
+        // 未指定格式的请求不受限制
+        processor.validate_params(&ProcessingParams::default()).unwrap();
+
+        unrestricted.validate_params(&format("tiff")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dimensions_below_the_minimum_are_rejected() {
+        let processor = processor(serde_json::json!({ "min_width": 16, "min_height": 10 })).await;
+        let size = |width: Option<i32>, height: Option<i32>| ProcessingParams { width, height, ..Default::default() };
+        let message = |params: ProcessingParams| {
+            let err = processor.validate_params(&params).unwrap_err();
+            let err = err.downcast_ref::<RequestError>().unwrap();
+            assert_eq!(err.status, 400);
+            err.message.clone()
+        };
+        assert_eq!(message(size(Some(1), None)), "width 1 is below the minimum of 16");
+        assert_eq!(message(size(Some(100), Some(9))), "height 9 is below the minimum of 10");
+        processor.validate_params(&size(Some(16), Some(10))).unwrap();
+        // 只限制请求中给出的边
+        processor.validate_params(&size(None, Some(50))).unwrap();
+
+        // 不经过 S3 直接拒绝
+        let err = processor.get_or_process_image("bucket/pixel.gif".to_string(), size(Some(1), Some(1))).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().map(|e| e.status), Some(400));
+    }
+
+    #[tokio::test]
+    async fn avif_is_clamped_to_its_own_max_while_jpeg_uses_the_global_max() {
+        let processor = processor(serde_json::json!({ "format_limits": { "avif": { "max_width": 800 } } })).await;
+        let params = ProcessingParams { width: Some(3000), ..Default::default() };
+        assert_eq!(processor.max_dimensions("avif"), (800, 1080));
+        assert_eq!(processor.max_dimensions("jpg"), (1920, 1080));
+
+        let size = |format: &str| processor.target_size(4000, 2000, &params, format).map(|s| (s.width, s.height));
+        assert_eq!(size("avif"), Some((800, 400)));
+        assert_eq!(size("jpg"), Some((1920, 960)));
+        // 在 AVIF 上限以内的请求不受影响
+        let small = ProcessingParams { width: Some(600), ..Default::default() };
+        assert_eq!(processor.target_size(4000, 2000, &small, "avif").map(|s| (s.width, s.height)), Some((600, 300)));
+    }
+
+    #[test]
+    fn bucket_defaults_apply_only_to_their_bucket() {
+        let config = processing_config(serde_json::json!({
+            "bucket_defaults": { "uploads": { "quality": "85", "format": "webp" } },
+        }));
+        let query = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+
+        let uploads = parse_query_params_for_key("uploads/avatar.jpg", query(&[("width", "64")]), &config);
+        assert_eq!((uploads.width, uploads.quality, uploads.format.as_deref()), (Some(64), Some(85), Some("webp")));
+        // 请求中显式给出的参数优先
+        let explicit = parse_query_params_for_key("uploads/avatar.jpg", query(&[("quality", "60")]), &config);
+        assert_eq!((explicit.quality, explicit.format.as_deref()), (Some(60), Some("webp")));
+        // 其他 bucket 不受影响
+        let photos = parse_query_params_for_key("photos/avatar.jpg", query(&[("width", "64")]), &config);
+        assert_eq!((photos.quality, photos.format), (None, None));
+
+        // 合并后的参数参与缓存键计算
+        let without_defaults = parse_query_params(query(&[("width", "64")]), &config);
+        assert_ne!(cache_key("uploads/avatar.jpg", &uploads), cache_key("uploads/avatar.jpg", &without_defaults));
+    }
+
+    #[tokio::test]
+    async fn a_profile_supplies_its_params_and_explicit_params_override_them() {
+        let processor = processor(serde_json::json!({
+            "profiles": { "thumb": { "width": "150", "height": "150", "quality": "70", "format": "jpg" } },
+        })).await;
+        let config = processor.config.clone();
+        let query = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+
+        let thumb = parse_query_params(query(&[("profile", "thumb")]), &config);
+        assert_eq!((thumb.width, thumb.height, thumb.quality), (Some(150), Some(150), Some(70)));
+        let image = processor.process_source(jpeg(600, 400), &thumb, false).await.unwrap();
+        assert_eq!((image.width, image.height), (Some(150), Some(150)));
+
+        let wider = parse_query_params(query(&[("profile", "THUMB"), ("width", "300")]), &config);
+        assert_eq!((wider.width, wider.height, wider.quality), (Some(300), Some(150), Some(70)));
+        let image = processor.process_source(jpeg(600, 400), &wider, false).await.unwrap();
+        assert_eq!((image.width, image.height), (Some(300), Some(150)));
+
+        // 未知的配置档被忽略
+        let unknown = parse_query_params(query(&[("profile", "hero"), ("width", "80")]), &config);
+        assert_eq!((unknown.width, unknown.height, unknown.quality), (Some(80), None, None));
+    }
+
+    #[tokio::test]
+    async fn a_tiny_output_gets_lower_quality_than_a_large_one() {
+        let scaling = serde_json::json!({ "min_quality": 60, "max_quality": 90, "min_pixels": 10_000, "max_pixels": 1_000_000 });
+        let processor = processor(serde_json::json!({ "quality_scaling": scaling })).await;
+        let scaling = processor.config.quality_scaling.clone().unwrap();
+        assert_eq!(scaling.quality_for(64 * 64), 60);
+        assert_eq!(scaling.quality_for(505_000), 75);
+        assert_eq!(scaling.quality_for(1920 * 1080), 90);
+
+        // 同一个未指定质量的请求，只有输出尺寸不同
+        let params = ProcessingParams { format: Some("jpg".to_string()), ..Default::default() };
+        let quality = |pixels: u64| processor.encode_params(1, pixels, &params, "jpg")[1];
+        assert!(quality(100 * 100) < quality(1600 * 1200), "{} vs {}", quality(100 * 100), quality(1600 * 1200));
+        // 显式给出的质量不按尺寸调整
+        let explicit = ProcessingParams { quality: Some(82), ..params.clone() };
+        assert_eq!(processor.encode_params(1, 100 * 100, &explicit, "jpg")[1], 82);
+
+        // 编码结果随之变化：小图按 60 编码，每像素字节数明显低于按 90 编码的大图
+        let small = ProcessingParams { width: Some(100), ..params.clone() };
+        let large = ProcessingParams { width: Some(1000), ..params };
+        let small = processor.process_source(noisy_jpeg(1000, 1000), &small, false).await.unwrap();
+        let large = processor.process_source(noisy_jpeg(1000, 1000), &large, false).await.unwrap();
+        let per_pixel = |image: &ProcessedImage| image.data.len() as f64 / (image.width.unwrap() * image.height.unwrap()) as f64;
+        assert!(per_pixel(&small) < per_pixel(&large), "{} vs {}", per_pixel(&small), per_pixel(&large));
+    }
+
+    fn decode(data: &[u8]) -> Mat {
+        decode_image(&Vector::from_slice(data)).expect("output could not be decoded")
+    }
+
+    // 黑白相间的 4x4 棋盘格 PNG
+    fn checkerboard_png() -> Vec<u8> {
+        let mut img = Mat::new_rows_cols_with_default(4, 4, CV_8UC3, Scalar::all(0.0)).unwrap();
+        for row in 0..4 {
+            for col in 0..4 {
+                if (row + col) % 2 == 0 {
+                    *img.at_2d_mut::<opencv::core::Vec3b>(row, col).unwrap() = opencv::core::Vec3b::from([255, 255, 255]);
+                }
+            }
+        }
+        let mut buf = Vector::new();
+        assert!(imencode(".png", &img, &mut buf, &Vector::new()).unwrap());
+        buf.to_vec()
+    }
+
+    #[test]
+    fn pixel_art_upscales_snap_to_integer_factors() {
+        assert_eq!(snap_to_integer_scale(16, 50, 1920), 48);
+        assert_eq!(snap_to_integer_scale(16, 40, 1920), 48);
+        // 取整后超出上限时取上限内最大的整数倍
+        assert_eq!(snap_to_integer_scale(100, 290, 250), 200);
+        // 缩小不调整
+        assert_eq!(snap_to_integer_scale(100, 30, 1920), 30);
+    }
+
+    #[tokio::test]
+    async fn pixel_art_upscaling_keeps_hard_edges_unlike_linear() {
+        let processor = processor(serde_json::json!({})).await;
+        let levels = |image: &ProcessedImage| {
+            let img = decode(&image.data);
+            img.data_bytes().unwrap().iter().copied().collect::<std::collections::BTreeSet<u8>>()
+        };
+        // 4 → 17 取整为 16（4 倍）
+        let pixel_art = ProcessingParams { width: Some(17), pixel_art: true, format: Some("png".to_string()), ..Default::default() };
+        let crisp = processor.process_source(checkerboard_png(), &pixel_art, false).await.unwrap();
+        assert_eq!((crisp.width, crisp.height), (Some(16), Some(16)));
+        assert_eq!(levels(&crisp), [0, 255].into_iter().collect());
+
+        let linear = ProcessingParams {
+            width: Some(16),
+            interpolation: Some(InterpolationFlags::INTER_LINEAR),
+            format: Some("png".to_string()),
+            ..Default::default()
+        };
+        let smooth = processor.process_source(checkerboard_png(), &linear, false).await.unwrap();
+        assert!(levels(&smooth).iter().any(|&level| level != 0 && level != 255), "{:?}", levels(&smooth));
+    }
+
+    #[tokio::test]
+    async fn the_default_interpolation_depends_on_the_scale_direction() {
+        let processor = processor(serde_json::json!({ "interpolation": { "upscale": "cubic", "downscale": "area" } })).await;
+        let source = Size::new(400, 300);
+        let params = ProcessingParams::default();
+        assert_eq!(processor.interpolation_for(&params, source, Size::new(800, 600)), InterpolationFlags::INTER_CUBIC);
+        assert_eq!(processor.interpolation_for(&params, source, Size::new(200, 150)), InterpolationFlags::INTER_AREA);
+        // 请求指定的插值和像素画优先于默认值
+        let explicit = ProcessingParams { interpolation: Some(InterpolationFlags::INTER_LANCZOS4), ..Default::default() };
+        assert_eq!(processor.interpolation_for(&explicit, source, Size::new(200, 150)), InterpolationFlags::INTER_LANCZOS4);
+        let pixel_art = ProcessingParams { pixel_art: true, ..explicit };
+        assert_eq!(processor.interpolation_for(&pixel_art, source, Size::new(800, 600)), InterpolationFlags::INTER_NEAREST);
+
+        // 未配置时两个方向都是 linear
+        let processor = self::processor(serde_json::json!({})).await;
+        assert_eq!(processor.interpolation_for(&params, source, Size::new(800, 600)), InterpolationFlags::INTER_LINEAR);
+        assert_eq!(processor.interpolation_for(&params, source, Size::new(200, 150)), InterpolationFlags::INTER_LINEAR);
+    }
+
+    #[tokio::test]
+    async fn only_if_larger_leaves_small_sources_unscaled() {
+        let processor = processor(serde_json::json!({})).await;
+        let query = HashMap::from([
+            ("width".to_string(), "300".to_string()),
+            ("only_if".to_string(), "larger".to_string()),
+            ("format".to_string(), "jpg".to_string()),
+        ]);
+        let params = parse_query_params(query, &processor.config);
+        assert!(params.only_if_larger);
+
+        let small = processor.process_source(jpeg(100, 80), &params, false).await.unwrap();
+        assert_eq!((small.width, small.height), (Some(100), Some(80)));
+        let large = processor.process_source(jpeg(800, 600), &params, false).await.unwrap();
+        assert_eq!((large.width, large.height), (Some(300), Some(225)));
+
+        // 不带 only_if 时小图照常放大
+        let upscaled = ProcessingParams { only_if_larger: false, ..params };
+        let small = processor.process_source(jpeg(100, 80), &upscaled, false).await.unwrap();
+        assert_eq!((small.width, small.height), (Some(300), Some(240)));
+    }
+
+    // 纯色 PNG，color 为 BGR
+    fn solid_png(width: i32, height: i32, color: (f64, f64, f64)) -> Vec<u8> {
+        let img = Mat::new_rows_cols_with_default(height, width, CV_8UC3, Scalar::new(color.0, color.1, color.2, 0.0)).unwrap();
+        let mut buf = Vector::new();
+        assert!(imencode(".png", &img, &mut buf, &Vector::new()).unwrap());
+        buf.to_vec()
+    }
+
+    fn bgr_at(img: &Mat, row: i32, col: i32) -> [u8; 3] {
+        let pixel = img.at_2d::<opencv::core::Vec3b>(row, col).unwrap();
+        [pixel[0], pixel[1], pixel[2]]
+    }
+
+    #[tokio::test]
+    async fn square_pads_a_wide_source_to_n_by_n_with_bars() {
+        let processor = processor(serde_json::json!({})).await;
+        let query = HashMap::from([
+            ("square".to_string(), "200".to_string()),
+            ("bg".to_string(), "ff0000".to_string()),
+            ("format".to_string(), "png".to_string()),
+        ]);
+        let params = parse_query_params(query, &processor.config);
+        // 400x100 的蓝色图缩放为 200x50，上下各补 75 行红色
+        let image = processor.process_source(solid_png(400, 100, (255.0, 0.0, 0.0)), &params, false).await.unwrap();
+        assert_eq!((image.width, image.height), (Some(200), Some(200)));
+        let img = decode(&image.data);
+        assert_eq!((img.cols(), img.rows()), (200, 200));
+        for row in [0, 74, 125, 199] {
+            assert_eq!(bgr_at(&img, row, 100), [0, 0, 255], "row {}", row);
+        }
+        for row in [76, 100, 123] {
+            assert_eq!(bgr_at(&img, row, 100), [255, 0, 0], "row {}", row);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_landscape_source_with_orient_portrait_comes_out_taller_than_wide() {
+        let processor = processor(serde_json::json!({})).await;
+        let orient = |value: &str| {
+            let query = HashMap::from([("orient".to_string(), value.to_string()), ("format".to_string(), "jpg".to_string())]);
+            parse_query_params(query, &processor.config)
+        };
+        let portrait = processor.process_source(jpeg(300, 200), &orient("portrait"), false).await.unwrap();
+        assert_eq!((portrait.width, portrait.height), (Some(200), Some(300)));
+        // 已经符合的方向不旋转
+        let landscape = processor.process_source(jpeg(300, 200), &orient("landscape"), false).await.unwrap();
+        assert_eq!((landscape.width, landscape.height), (Some(300), Some(200)));
+        // 旋转后再按 width 缩放
+        let resized = ProcessingParams { width: Some(100), ..orient("portrait") };
+        let resized = processor.process_source(jpeg(300, 200), &resized, false).await.unwrap();
+        assert_eq!((resized.width, resized.height), (Some(100), Some(150)));
+
+        assert_ne!(cache_key("b/a.jpg", &orient("portrait")), cache_key("b/a.jpg", &orient("landscape")));
+    }
+
+    #[test]
+    fn a_tile_is_the_right_sub_region_at_the_right_scale() {
+        // 1000x600：左半边蓝色，右半边红色；最高层级 ceil(log2(1000)) = 10
+        let mut img = Mat::new_rows_cols_with_default(600, 1000, CV_8UC3, Scalar::new(255.0, 0.0, 0.0, 0.0)).unwrap();
+        Mat::roi_mut(&mut img, Rect::new(500, 0, 500, 600))
+            .unwrap()
+            .set_to(&Scalar::new(0.0, 0.0, 255.0, 0.0), &opencv::core::no_array())
+            .unwrap();
+        let tile = |level, x, y| extract_tile(&img, Tile { level, x, y });
+        let (blue, red) = ([255, 0, 0], [0, 0, 255]);
+
+        // 原始比例：(2, 0) 对应原图 512..768 列，整块为红色
+        let full = tile(10, 2, 0).unwrap();
+        assert_eq!((full.cols(), full.rows()), (256, 256));
+        assert_eq!((bgr_at(&full, 0, 0), bgr_at(&full, 255, 255)), (red, red));
+        // 底边的瓦片只剩 600 - 512 = 88 行
+        let edge = tile(10, 0, 2).unwrap();
+        assert_eq!((edge.cols(), edge.rows()), (256, 88));
+        assert_eq!(bgr_at(&edge, 0, 0), blue);
+
+        // 缩小一半：层级尺寸 500x300，(0, 0) 覆盖原图左上 512x512，第 250 列附近是蓝红分界
+        let half = tile(9, 0, 0).unwrap();
+        assert_eq!((half.cols(), half.rows()), (256, 256));
+        assert_eq!((bgr_at(&half, 10, 10), bgr_at(&half, 10, 252)), (blue, red));
+        // 最低层级整张图缩成 1x1
+        let lowest = tile(0, 0, 0).unwrap();
+        assert_eq!((lowest.cols(), lowest.rows()), (1, 1));
+
+        let status = |e: anyhow::Error| e.downcast_ref::<RequestError>().map(|e| e.status);
+        assert_eq!(status(tile(10, 4, 0).unwrap_err()), Some(404));
+        assert_eq!(status(tile(11, 0, 0).unwrap_err()), Some(400));
+    }
+
+    #[tokio::test]
+    async fn flat_graphics_pick_a_lossless_format_and_noisy_photos_pick_jpeg() {
+        let flat = Mat::new_rows_cols_with_default(120, 160, CV_8UC3, Scalar::new(40.0, 180.0, 90.0, 0.0)).unwrap();
+        assert_eq!(classify_content(&flat).unwrap(), "png");
+        let mut noisy = Mat::new_rows_cols_with_default(120, 160, CV_8UC3, Scalar::all(0.0)).unwrap();
+        randu(&mut noisy, &Scalar::all(0.0), &Scalar::all(255.0)).unwrap();
+        assert_eq!(classify_content(&noisy).unwrap(), "jpg");
+
+        let processor = processor(serde_json::json!({})).await;
+        let params = ProcessingParams { width: Some(100), format: Some("auto-content".to_string()), ..Default::default() };
+        let graphic = processor.process_source(solid_png(200, 150, (40.0, 180.0, 90.0)), &params, false).await.unwrap();
+        assert_eq!(graphic.content_type, "image/png");
+        let source = noisy_jpeg(200, 150);
+        let photo = processor.process_source(source.clone(), &params, false).await.unwrap();
+        assert_eq!(photo.content_type, "image/jpeg");
+        // 同一张图每次选出的格式相同，缓存结果稳定
+        let again = processor.process_source(source, &params, false).await.unwrap();
+        assert_eq!(again.content_type, photo.content_type);
+    }
+
+    fn prepared(processor: &ImageProcessor, width: i32, height: i32) -> PreparedImage {
+        PreparedImage {
+            img: Mat::new_rows_cols_with_default(height, width, CV_8UC3, Scalar::all(128.0)).unwrap(),
+            source_format: ImageFormat::Jpeg,
+            blurhash: None,
+            content_format: None,
+            start_time: SystemTime::now(),
+            _reservation: processor.reserve_memory(0).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_webp_encode_walks_the_fallback_chain() {
+        let webp = ProcessingParams { format: Some("webp".to_string()), ..Default::default() };
+        // WebP 最大边长 16383，16400 宽的图编码失败
+        let processor = processor(serde_json::json!({ "encode_fallbacks": { "webp": "png" } })).await;
+        let image = processor.encode_prepared(&prepared(&processor, 16400, 1), &webp, "webp").unwrap();
+        assert_eq!(image.content_type, "image/png");
+        assert_eq!(detect_format(&image.data), Some(ImageFormat::Png));
+        // 能编码时不回退
+        let image = processor.encode_prepared(&prepared(&processor, 64, 64), &webp, "webp").unwrap();
+        assert_eq!(image.content_type, "image/webp");
+
+        // 没有配置回退时返回原来的编码错误
+        let strict = self::processor(serde_json::json!({})).await;
+        assert!(strict.encode_prepared(&prepared(&strict, 16400, 1), &webp, "webp").is_err());
+
+        // 回退链成环且每个格式都失败（JPEG 最大边长 65500）时不会无限循环
+        let cyclic = self::processor(serde_json::json!({ "encode_fallbacks": { "webp": "jpg", "jpg": "webp" } })).await;
+        assert!(cyclic.encode_prepared(&prepared(&cyclic, 70000, 1), &webp, "webp").is_err());
+    }
+
+    #[tokio::test]
+    async fn configured_encoder_params_are_appended_to_the_encode_call() {
+        let processor = processor(serde_json::json!({ "encoder_params": { "jpg": { "imwrite_jpeg_progressive": 1 } } })).await;
+        let params = ProcessingParams { format: Some("jpg".to_string()), ..Default::default() };
+        assert_eq!(processor.encode_params(1, 100, &params, "jpg"), vec![1, 80, 2, 1]);
+        // 只作用于配置的格式
+        assert_eq!(processor.encode_params(16, 100, &params, "png"), vec![16, 80]);
+
+        // 参数确实传给了编码器：输出为渐进式 JPEG
+        let image = processor.process_source(jpeg(64, 64), &ProcessingParams { width: Some(32), ..params }, false).await.unwrap();
+        assert!(is_progressive_jpeg(&image.data));
+
+        let unknown = HashMap::from([("jpg".to_string(), HashMap::from([("imwrite_jpeg_turbo".to_string(), 1)]))]);
+        let err = resolve_encoder_params(&unknown).unwrap_err();
+        assert_eq!(err.to_string(), "encoder_params.jpg: unknown OpenCV parameter 'imwrite_jpeg_turbo'");
+    }
+
+    #[test]
+    fn optimized_png_is_no_larger_and_decodes_to_the_same_pixels() {
+        // 不压缩编码的渐变图，再压缩的空间很大
+        let mut img = Mat::new_rows_cols_with_default(128, 128, CV_8UC3, Scalar::all(0.0)).unwrap();
+        for row in 0..128 {
+            for col in 0..128 {
+                *img.at_2d_mut::<opencv::core::Vec3b>(row, col).unwrap() = opencv::core::Vec3b::from([row as u8 * 2, col as u8 * 2, 90]);
+            }
+        }
+        let mut buf = Vector::new();
+        assert!(imencode(".png", &img, &mut buf, &Vector::from_slice(&[16, 0])).unwrap());
+        let original = buf.to_vec();
+
+        let optimized = optimize_png(original.clone());
+        assert!(optimized.len() <= original.len(), "{} > {}", optimized.len(), original.len());
+        #[cfg(feature = "png-optimize")]
+        assert!(optimized.len() < original.len(), "{} >= {}", optimized.len(), original.len());
+        assert_eq!(detect_format(&optimized), Some(ImageFormat::Png));
+        let (before, after) = (decode(&original), decode(&optimized));
+        assert_eq!((after.cols(), after.rows(), after.channels()), (128, 128, 3));
+        assert_eq!(before.data_bytes().unwrap(), after.data_bytes().unwrap());
+    }
+
+    #[tokio::test]
+    async fn the_blurhash_is_a_valid_hash_of_the_output() {
+        let processor = processor(serde_json::json!({})).await;
+        let params = ProcessingParams { width: Some(32), blurhash: true, format: Some("jpg".to_string()), ..Default::default() };
+        // 纯红色原图
+        let image = processor.process_source(solid_png(64, 48, (0.0, 0.0, 255.0)), &params, false).await.unwrap();
+        let hash = image.blurhash.expect("no blurhash computed");
+        // 4x3 个分量：1 + 1 + 4 + 2 × 11 个字符
+        assert_eq!(hash.len(), 28, "{}", hash);
+        let pixels = blurhash::decode(&hash, 8, 8, 1.0).unwrap();
+        assert_eq!(pixels.len(), 8 * 8 * 4);
+        let (r, g, b) = (pixels[0], pixels[1], pixels[2]);
+        assert!(r > 200 && g < 40 && b < 40, "{:?}", (r, g, b));
+
+        // 未请求时不计算
+        let plain = ProcessingParams { blurhash: false, ..params };
+        assert!(processor.process_source(solid_png(64, 48, (0.0, 0.0, 255.0)), &plain, false).await.unwrap().blurhash.is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_variant_requests_fetch_the_original_once() {
+        let s3 = MockS3::start().await;
+        s3.put("photos/a.jpg", jpeg(400, 300));
+        let processor = processor_on(&s3, serde_json::json!({})).await;
+        let params = ProcessingParams { width: Some(200), ..Default::default() };
+        let forward = ["webp".to_string(), "jpg".to_string()];
+        let backward = ["jpg".to_string(), "webp".to_string()];
+
+        // 格式顺序相反的两个请求同时到达，不会互相等死，原图也只读取一次
+        let (a, b) = tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::join!(
+                processor.get_or_process_variants("photos/a.jpg".to_string(), params.clone(), &forward),
+                processor.get_or_process_variants("photos/a.jpg".to_string(), params.clone(), &backward),
+            )
+        })
+        .await
+        .expect("variant requests deadlocked");
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(s3.requests(), 1);
+        assert_eq!(a[0].data, b[1].data);
+        assert_eq!(a[1].data, b[0].data);
+
+        // 单个格式的请求与对应的 part 共用缓存条目
+        let single = ProcessingParams { format: Some("webp".to_string()), ..params };
+        let (image, source) = processor.get_or_process_image("photos/a.jpg".to_string(), single).await.unwrap();
+        assert_eq!(source, "cache");
+        assert_eq!(image.data, a[0].data);
+        assert_eq!(s3.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn variants_respect_the_derivative_limit() {
+        let s3 = MockS3::start().await;
+        s3.put("photos/a.jpg", jpeg(400, 300));
+        let processor = processor_on(&s3, serde_json::json!({ "max_derivatives_per_original": 1, "on_derivative_limit": "reject" })).await;
+        let formats = ["webp".to_string()];
+        let small = ProcessingParams { width: Some(100), ..Default::default() };
+        processor.get_or_process_variants("photos/a.jpg".to_string(), small.clone(), &formats).await.unwrap();
+
+        let large = ProcessingParams { width: Some(200), ..Default::default() };
+        let err = processor.get_or_process_variants("photos/a.jpg".to_string(), large, &formats).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().unwrap().status, 400);
+        // 已缓存的派生图不受影响
+        assert!(processor.get_or_process_variants("photos/a.jpg".to_string(), small, &formats).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn format_sizes_match_the_bytes_each_format_would_serve() {
+        let s3 = MockS3::start().await;
+        s3.put("photos/a.jpg", noisy_jpeg(200, 150));
+        let processor = processor_on(&s3, serde_json::json!({})).await;
+        let params = ProcessingParams { width: Some(100), ..Default::default() };
+
+        let sizes = processor.format_sizes("photos/a.jpg", params.clone()).await.unwrap();
+        for format in OUTPUT_FORMATS {
+            let request = ProcessingParams { format: Some(format.to_string()), ..params.clone() };
+            let (image, _) = processor.get_or_process_image("photos/a.jpg".to_string(), request).await.unwrap();
+            assert_eq!(sizes[*format], image.data.len(), "{}", format);
+        }
+        // 噪声图无损压缩后明显大于 JPEG
+        assert!(sizes["png"] > sizes["jpg"], "{:?}", sizes);
+
+        // 第二次比较从缓存读取，不再访问 S3
+        let requests = s3.requests();
+        assert_eq!(processor.format_sizes("photos/a.jpg", params).await.unwrap(), sizes);
+        assert_eq!(s3.requests(), requests);
+    }
+
+    #[tokio::test]
+    async fn the_dominant_colour_of_a_mostly_red_image_is_red() {
+        // 40x40：左边 10 列蓝色，其余红色
+        let mut img = Mat::new_rows_cols_with_default(40, 40, CV_8UC3, Scalar::new(0.0, 0.0, 255.0, 0.0)).unwrap();
+        for row in 0..40 {
+            for col in 0..10 {
+                *img.at_2d_mut::<opencv::core::Vec3b>(row, col).unwrap() = opencv::core::Vec3b::from([255, 0, 0]);
+            }
+        }
+        let mut buf = Vector::new();
+        assert!(imencode(".png", &img, &mut buf, &Vector::new()).unwrap());
+        let s3 = MockS3::start().await;
+        s3.put("photos/red.png", buf.to_vec());
+        let processor = processor_on(&s3, serde_json::json!({})).await;
+
+        let colors = processor.image_colors("photos/red.png").await.unwrap();
+        assert_eq!(colors.dominant, "#ff0000");
+        // 平均色：红 255 × 3/4，蓝 255 × 1/4
+        assert_eq!(colors.average, "#bf0040");
+
+        // 结果已缓存
+        let requests = s3.requests();
+        assert_eq!(processor.image_colors("photos/red.png").await.unwrap().dominant, "#ff0000");
+        assert_eq!(s3.requests(), requests);
+    }
+
+    #[tokio::test]
+    async fn the_histogram_of_a_gradient_is_flat() {
+        // 256x4 横向渐变：红从 0 到 255，绿从 255 到 0，蓝固定 128
+        let mut img = Mat::new_rows_cols_with_default(4, 256, CV_8UC3, Scalar::all(0.0)).unwrap();
+        for row in 0..4 {
+            for col in 0..256 {
+                *img.at_2d_mut::<opencv::core::Vec3b>(row, col).unwrap() = opencv::core::Vec3b::from([128, 255 - col as u8, col as u8]);
+            }
+        }
+        let mut buf = Vector::new();
+        assert!(imencode(".png", &img, &mut buf, &Vector::new()).unwrap());
+        let s3 = MockS3::start().await;
+        s3.put("charts/gradient.png", buf.to_vec());
+        let processor = processor_on(&s3, serde_json::json!({})).await;
+
+        let histogram = processor.image_histogram("charts/gradient.png", 4).await.unwrap();
+        assert_eq!((histogram.bins, histogram.pixels), (4, 1024));
+        assert_eq!(histogram.channels["red"], vec![256, 256, 256, 256]);
+        assert_eq!(histogram.channels["green"], vec![256, 256, 256, 256]);
+        assert_eq!(histogram.channels["blue"], vec![0, 0, 1024, 0]);
+        // 没有透明通道时不输出 alpha
+        assert!(!histogram.channels.contains_key("alpha"));
+
+        let histogram = processor.image_histogram("charts/gradient.png", 256).await.unwrap();
+        assert!(histogram.channels["red"].iter().all(|&count| count == 4));
+
+        let err = processor.image_histogram("charts/gradient.png", 0).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().unwrap().status, 400);
+    }
+
+    #[test]
+    fn watermark_text_keeps_only_printable_ascii() {
+        assert_eq!(sanitize_watermark_text("  (c) ACME 2026  ", 64).as_deref(), Some("(c) ACME 2026"));
+        assert_eq!(sanitize_watermark_text("caf\u{e9}\n\u{7}ok", 64).as_deref(), Some("cafok"));
+        assert_eq!(sanitize_watermark_text("abcdefgh", 4).as_deref(), Some("abcd"));
+        assert_eq!(sanitize_watermark_text("\u{4e2d}\u{6587}", 64), None);
+        assert_eq!(sanitize_watermark_text("   ", 64), None);
+    }
+
+    #[tokio::test]
+    async fn the_text_watermark_changes_only_pixels_in_its_corner() {
+        let processor = processor(serde_json::json!({
+            "text_watermark": { "position": "top-left", "color": "#FF0000", "opacity": 1.0 }
+        }))
+        .await;
+        let img = Mat::new_rows_cols_with_default(100, 200, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let marked = processor.draw_text_watermark(&img, "AC").unwrap();
+        assert_eq!((marked.cols(), marked.rows()), (200, 100));
+
+        let (mut inside, mut outside) = (0, 0);
+        for row in 0..100 {
+            for col in 0..200 {
+                if bgr_at(&marked, row, col) != [0, 0, 0] {
+                    if row < 50 && col < 100 { inside += 1 } else { outside += 1 }
+                }
+            }
+        }
+        assert!(inside > 50, "only {} pixels changed", inside);
+        assert_eq!(outside, 0);
+        // 不透明的红色文字
+        let reddest = (0..50).flat_map(|row| (0..100).map(move |col| (row, col))).map(|(row, col)| bgr_at(&marked, row, col)).max_by_key(|p| p[2]).unwrap();
+        assert_eq!(reddest, [0, 0, 255]);
+
+        // text 参数经过同样的处理流程
+        let params = ProcessingParams { text: Some("AC".to_string()), format: Some("png".to_string()), ..Default::default() };
+        let plain = ProcessingParams { text: None, ..params.clone() };
+        let source = solid_png(200, 100, (0.0, 0.0, 0.0));
+        let with_text = decode(&processor.process_source(source.clone(), &params, false).await.unwrap().data);
+        let without = decode(&processor.process_source(source, &plain, false).await.unwrap().data);
+        assert!((0..50).any(|row| (0..100).any(|col| bgr_at(&with_text, row, col) != bgr_at(&without, row, col))));
+    }
+
+    #[test]
+    fn a_codec_that_cannot_encode_fails_the_self_test() {
+        // 模拟运行时缺少 libwebp（imencode 返回 false）和 libpng（imencode 报错）
+        let probe = |format: &str| match format {
+            "webp" => Ok(false),
+            "png" => Err(anyhow::anyhow!("could not find a writer for the specified extension")),
+            _ => Ok(true),
+        };
+        assert_eq!(codec_failures(OUTPUT_FORMATS, probe), vec!["png".to_string(), "webp".to_string()]);
+        assert!(codec_failures(OUTPUT_FORMATS, |_| Ok(true)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn every_output_format_passes_the_self_test() {
+        assert!(processor(serde_json::json!({})).await.self_test().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failed_resize_encodes_at_the_original_size() {
+        let processor = processor(serde_json::json!({})).await;
+        // INTER_MAX 不是有效的插值方法，OpenCV 的 resize 会报错
+        let params = ProcessingParams {
+            width: Some(50),
+            interpolation: Some(InterpolationFlags::INTER_MAX),
+            format: Some("png".to_string()),
+            ..Default::default()
+        };
+        let image = processor.process_source(solid_png(200, 100, (0.0, 128.0, 255.0)), &params, false).await.unwrap();
+        assert_eq!((image.width, image.height), (Some(200), Some(100)));
+        let output = decode(&image.data);
+        assert_eq!((output.cols(), output.rows()), (200, 100));
+        assert_eq!(bgr_at(&output, 50, 100), [0, 128, 255]);
+
+        // 正常的插值方法仍然缩放
+        let params = ProcessingParams { interpolation: Some(InterpolationFlags::INTER_AREA), ..params };
+        let image = processor.process_source(solid_png(200, 100, (0.0, 128.0, 255.0)), &params, false).await.unwrap();
+        assert_eq!((image.width, image.height), (Some(50), Some(25)));
+    }
+
+    // 16x16 的 Adobe CMYK JPEG（APP14 transform=0，按 Adobe 惯例反相存储），颜色为 C=0 M=100% Y=100% K=0 的纯红
+    const CMYK_JPEG: &[u8] = &[
+        0xff, 0xd8, 0xff, 0xee, 0x00, 0x0e, 0x41, 0x64, 0x6f, 0x62, 0x65, 0x00, 0x64, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0xff, 0xdb, 0x00, 0x43, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+        0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0xff, 0xc0, 0x00, 0x14, 0x08, 0x00, 0x10, 0x00, 0x10,
+        0x04, 0x01, 0x11, 0x00, 0x02, 0x11, 0x00, 0x03, 0x11, 0x00, 0x04, 0x11, 0x00, 0xff, 0xc4, 0x00,
+        0x31, 0x00, 0x00, 0x01, 0x05, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x10, 0x01,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xff, 0xda, 0x00, 0x0e, 0x04, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x3f, 0x00,
+        0xfe, 0xfe, 0x1f, 0xe7, 0xfe, 0xff, 0x00, 0x3f, 0xf7, 0xf7, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x0f,
+        0xff, 0xd9,
+    ];
+
+    #[test]
+    fn a_cmyk_jpeg_decodes_to_bgr() {
+        let img = decode(CMYK_JPEG);
+        assert_eq!((img.cols(), img.rows(), img.channels()), (16, 16, 3));
+        let [b, g, r] = bgr_at(&img, 8, 8);
+        assert!(r > 200 && g < 60 && b < 60, "{:?}", (b, g, r));
+    }
+
+    #[test]
+    fn decoding_retries_the_next_mode_until_one_yields_pixels() {
+        // 第一种模式报错、第二种解出空图，第三种才成功
+        let tried = std::cell::RefCell::new(Vec::new());
+        let img = decode_with_fallback(|mode| {
+            tried.borrow_mut().push(mode);
+            match tried.borrow().len() {
+                1 => Err(opencv::Error::new(opencv::core::StsError, "Unsupported color conversion")),
+                2 => Ok(Mat::default()),
+                _ => Mat::new_rows_cols_with_default(2, 3, CV_8UC3, Scalar::all(7.0)),
+            }
+        })
+        .expect("the last mode decoded");
+        assert_eq!((img.cols(), img.rows()), (3, 2));
+        let modes: Vec<_> = DECODE_MODES.iter().map(|&(mode, _)| mode).collect();
+        assert_eq!(*tried.borrow(), modes);
+
+        // 全部失败时返回 None，由调用方按无法解码处理
+        assert!(decode_with_fallback(|_| Ok(Mat::default())).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_preview_of_a_progressive_jpeg_reads_only_the_first_bytes() {
+        let mut img = Mat::new_rows_cols_with_default(300, 400, CV_8UC3, Scalar::all(0.0)).unwrap();
+        randu(&mut img, &Scalar::all(0.0), &Scalar::all(255.0)).unwrap();
+        let mut progressive = Vector::new();
+        // IMWRITE_JPEG_QUALITY=95，IMWRITE_JPEG_PROGRESSIVE=1
+        assert!(imencode(".jpg", &img, &mut progressive, &Vector::from_slice(&[1, 95, 2, 1])).unwrap());
+        let progressive = progressive.to_vec();
+        assert!(is_progressive_jpeg(&progressive));
+        assert!(progressive.len() > 32 * 1024);
+        let s3 = MockS3::start().await;
+        s3.put("photos/progressive.jpg", progressive);
+        s3.put("photos/baseline.jpg", noisy_jpeg(400, 300));
+        let processor = processor_on(&s3, serde_json::json!({ "preview_bytes": 32 * 1024 })).await;
+        let params = ProcessingParams { width: Some(100), preview: true, ..Default::default() };
+
+        // 只有一次 Range 请求，结果按截断的数据解码
+        let (image, source) = processor.get_or_process_image("photos/progressive.jpg".to_string(), params.clone()).await.unwrap();
+        assert_eq!(source, "preview");
+        assert_eq!((image.width, image.height), (Some(100), Some(75)));
+        assert_eq!(s3.requests(), 1);
+
+        // 基线 JPEG 截断后无法预览，先读开头再读完整原图
+        let (image, source) = processor.get_or_process_image("photos/baseline.jpg".to_string(), params).await.unwrap();
+        assert_eq!(source, "newly_processed");
+        assert_eq!((image.width, image.height), (Some(100), Some(75)));
+        assert_eq!(s3.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn only_public_read_objects_are_served_when_required() {
+        use crate::mock_s3::MockObject;
+        let s3 = MockS3::start().await;
+        s3.put_object("photos/public.jpg", MockObject { data: jpeg(120, 80), public: true, ..MockObject::default() });
+        s3.put_object("photos/private.jpg", MockObject { data: jpeg(120, 80), public: false, ..MockObject::default() });
+        let processor = processor_on(&s3, serde_json::json!({})).await.with_public_object_check(Duration::from_secs(60));
+        let params = ProcessingParams { width: Some(60), ..Default::default() };
+
+        let err = processor.get_or_process_image("photos/private.jpg".to_string(), params.clone()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().unwrap().status, 403);
+        // 只查询了 ACL，没有读取对象
+        assert_eq!(s3.requests(), 1);
+
+        let (image, _) = processor.get_or_process_image("photos/public.jpg".to_string(), params.clone()).await.unwrap();
+        assert_eq!(image.width, Some(60));
+        // ACL 结果已缓存，缓存命中时不再访问 S3
+        let requests = s3.requests();
+        let (_, source) = processor.get_or_process_image("photos/public.jpg".to_string(), params.clone()).await.unwrap();
+        assert_eq!(source, "cache");
+        assert_eq!(s3.requests(), requests);
+
+        // 未开启时私有对象也照常处理
+        let open = processor_on(&s3, serde_json::json!({})).await;
+        assert!(open.get_or_process_image("photos/private.jpg".to_string(), params).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn image_info_reads_only_the_start_of_the_original() {
+        let mut img = Mat::new_rows_cols_with_default(300, 400, CV_8UC3, Scalar::all(0.0)).unwrap();
+        randu(&mut img, &Scalar::all(0.0), &Scalar::all(255.0)).unwrap();
+        let mut png = Vector::new();
+        assert!(imencode(".png", &img, &mut png, &Vector::new()).unwrap());
+        assert!(png.len() > 256 * 1024);
+        let s3 = MockS3::start().await;
+        s3.put("photos/large.png", png.to_vec());
+        s3.put("photos/small.jpg", jpeg(120, 80));
+        let processor = processor_on(&s3, serde_json::json!({})).await;
+
+        assert_eq!(processor.image_info("photos/large.png").await.unwrap(), (400, 300, "image/png"));
+        // 一次 64KB 的 Range 请求就足以读出尺寸
+        assert_eq!((s3.requests(), s3.bytes_served()), (1, 64 * 1024));
+
+        // 比区间短的对象整个返回
+        assert_eq!(processor.image_info("photos/small.jpg").await.unwrap(), (120, 80, "image/jpeg"));
+        assert_eq!(s3.requests(), 2);
+
+        // 读不出尺寸时返回 415
+        s3.put("photos/notes.txt", b"not an image".to_vec());
+        let err = processor.image_info("photos/notes.txt").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().unwrap().status, 415);
+    }
+
+    #[tokio::test]
+    async fn bench_processes_the_built_in_image_without_touching_s3() {
+        let s3 = MockS3::start().await;
+        let processor = processor_on(&s3, serde_json::json!({})).await;
+        let params = ProcessingParams { width: Some(320), format: Some("webp".to_string()), ..Default::default() };
+
+        let (image, timings) = processor.bench(&params, 3).await.unwrap();
+        assert_eq!(timings.len(), 3);
+        assert_eq!(image.content_type, "image/webp");
+        // 内置原图为 1920x1080
+        assert_eq!((image.width, image.height), (Some(320), Some(180)));
+        assert_eq!(decode(&image.data).cols(), 320);
+        assert_eq!(s3.requests(), 0);
+
+        // iterations=0 时至少跑一次
+        let (_, timings) = processor.bench(&params, 0).await.unwrap();
+        assert_eq!(timings.len(), 1);
+        assert_eq!(s3.requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_ttl_override_expires_the_entry_early() {
+        let s3 = MockS3::start().await;
+        s3.put("photos/a.jpg", jpeg(120, 80));
+        let processor = processor_on(&s3, serde_json::json!({})).await;
+        let short = ProcessingParams { width: Some(60), ttl_override: Some(Duration::from_secs(1)), ..Default::default() };
+
+        let (image, _) = processor.get_or_process_image("photos/a.jpg".to_string(), short.clone()).await.unwrap();
+        assert_eq!(image.ttl, Some(Duration::from_secs(1)));
+        let (cached, source) = processor.get_or_process_image("photos/a.jpg".to_string(), short.clone()).await.unwrap();
+        assert_eq!(source, "cache");
+        assert!(processor.cache_expires_in(&cached).unwrap() <= Duration::from_secs(1));
+
+        // 缓存本身的 TTL 为 60 秒，覆盖值到期后重新处理
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let (_, source) = processor.get_or_process_image("photos/a.jpg".to_string(), short).await.unwrap();
+        assert_eq!(source, "newly_processed");
+        assert_eq!(s3.requests(), 2);
+
+        // 其他派生图不受影响
+        let plain = ProcessingParams { width: Some(30), ..Default::default() };
+        processor.get_or_process_image("photos/a.jpg".to_string(), plain.clone()).await.unwrap();
+        let (cached, _) = processor.get_or_process_image("photos/a.jpg".to_string(), plain).await.unwrap();
+        assert!(processor.cache_expires_in(&cached).unwrap() > Duration::from_secs(50));
+    }
+
+    // 只有文件头的 64x48 GIF，按 format=original 请求时不需要解码
+    const GIF_HEADER: &[u8] = b"GIF89a\x40\x00\x30\x00\x00\x00\x00";
+
+    #[test]
+    fn undecodable_original_is_returned_when_no_downscale_is_needed() {
+        let request = |width, height| ProcessingParams {
+            width,
+            height,
+            format: Some("original".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(original_fits_request(GIF_HEADER, ImageFormat::Gif, &request(Some(64), None)), Some((64, 48)));
+        assert_eq!(original_fits_request(GIF_HEADER, ImageFormat::Gif, &request(Some(100), Some(48))), Some((64, 48)));
+        assert_eq!(original_fits_request(GIF_HEADER, ImageFormat::Gif, &request(None, None)), Some((64, 48)));
+        // 需要缩小或转换格式时不能原样返回
+        assert_eq!(original_fits_request(GIF_HEADER, ImageFormat::Gif, &request(Some(32), None)), None);
+        assert_eq!(original_fits_request(GIF_HEADER, ImageFormat::Gif, &request(None, Some(47))), None);
+        let jpg = ProcessingParams { width: Some(64), format: Some("jpg".to_string()), ..Default::default() };
+        assert_eq!(original_fits_request(GIF_HEADER, ImageFormat::Gif, &jpg), None);
+    }
+
+    #[test]
+    fn other_transforms_never_fit_the_original() {
+        let base = ProcessingParams { width: Some(100), format: Some("original".to_string()), ..Default::default() };
+        let transforms = [
+            ProcessingParams { orient: Some(Orientation::Portrait), ..base.clone() },
+            ProcessingParams { tile: Some(Tile { level: 0, x: 0, y: 0 }), ..base.clone() },
+            ProcessingParams { region: Some(Region::Square), ..base.clone() },
+            ProcessingParams { rotation: Some(Rotation { degrees: 90, mirror: false }), ..base.clone() },
+            ProcessingParams { grayscale: true, ..base.clone() },
+            ProcessingParams { scale_pct: Some(100.0), ..base.clone() },
+            ProcessingParams { square: Some(64), ..base.clone() },
+            ProcessingParams { max_pixels: Some(1000), ..base.clone() },
+            ProcessingParams { quality: Some(50), ..base.clone() },
+            ProcessingParams { text: Some("hi".to_string()), ..base.clone() },
+            ProcessingParams { aspect_ratio: Some((1, 1)), ..base.clone() },
+            ProcessingParams { pixel_art: true, ..base.clone() },
+            ProcessingParams { preview: true, ..base.clone() },
+            ProcessingParams { blurhash: true, ..base.clone() },
+        ];
+        assert!(original_fits_request(GIF_HEADER, ImageFormat::Gif, &base).is_some());
+        for params in transforms {
+            assert_eq!(original_fits_request(GIF_HEADER, ImageFormat::Gif, &params), None, "{:?}", params);
+        }
+    }
+
+    const SCRIPTED_SVG: &[u8] = br#"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="50" onload="alert(1)"><script>alert(2)</script><rect width="100" height="50" fill="red"/></svg>"#;
+
+    #[tokio::test]
+    async fn svg_sources_are_served_sanitized_without_opencv() {
+        let processor = processor(serde_json::json!({})).await;
+        let image = processor.process_source(SCRIPTED_SVG.to_vec(), &ProcessingParams::default(), false).await.unwrap();
+        assert_eq!(image.content_type, "image/svg+xml");
+        let text = String::from_utf8(image.data).unwrap();
+        assert!(!text.contains("alert") && text.contains("<rect"), "{}", text);
+
+        // 关闭 svg_sanitize 时原样返回
+        let processor = self::processor(serde_json::json!({ "svg_sanitize": false })).await;
+        let image = processor.process_source(SCRIPTED_SVG.to_vec(), &ProcessingParams::default(), false).await.unwrap();
+        assert_eq!(image.data, SCRIPTED_SVG);
+    }
+
+    #[tokio::test]
+    async fn passthrough_returns_the_original_bytes() {
+        let processor = processor(serde_json::json!({})).await;
+        let original = jpeg(64, 48);
+        let image = processor.passthrough_image(original.clone()).unwrap();
+        assert_eq!(image.data, original);
+        assert_eq!(image.content_type, "image/jpeg");
+        assert_eq!((image.width, image.height), (None, None));
+
+        // SVG 不能借 passthrough 绕过 svg_sanitize
+        let image = processor.passthrough_image(SCRIPTED_SVG.to_vec()).unwrap();
+        assert_eq!(image.content_type, "image/svg+xml");
+        assert!(!String::from_utf8(image.data).unwrap().contains("alert"));
+    }
+
+    #[cfg(feature = "svg")]
+    #[tokio::test]
+    async fn resized_svg_sources_are_rasterized_to_png() {
+        let processor = processor(serde_json::json!({})).await;
+        let params = ProcessingParams { width: Some(40), format: Some("png".to_string()), ..Default::default() };
+        let image = processor.process_source(SCRIPTED_SVG.to_vec(), &params, false).await.unwrap();
+        assert_eq!(image.content_type, "image/png");
+        assert_eq!((image.width, image.height), (Some(40), Some(20)));
+    }
+
+    #[tokio::test]
+    async fn quality_levels_are_cached_from_one_pass() {
+        let processor = processor(serde_json::json!({
+            "quality_levels": [40, 75],
+            "max_derivatives_per_original": 10,
+        }))
+        .await;
+        let key = "bucket/photo.jpg";
+        let params = ProcessingParams { width: Some(32), format: Some("jpg".to_string()), ..Default::default() };
+
+        let image = processor.process_with_quality_levels(key, jpeg(64, 48), &params, None, None, None).await.unwrap();
+        assert_eq!((image.width, image.height), (Some(32), Some(24)));
+        for quality in [40, 75] {
+            let sibling = ProcessingParams { quality: Some(quality), ..params.clone() };
+            assert!(processor.cache.contains(&processor.cache_key(key, &sibling)), "quality {} not cached", quality);
+        }
+        // 其他质量不计入派生图上限
+        assert!(processor.derivatives.lock().unwrap().get(key).is_none_or(|list| list.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn cached_derivatives_answer_if_modified_since_without_s3() {
+        let processor = processor(serde_json::json!({})).await;
+        let key = "bucket/photo.jpg";
+        let params = ProcessingParams { width: Some(32), ..Default::default() };
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let mut image = ProcessedImage::unprocessed(jpeg(32, 24));
+        image.last_modified = Some(modified);
+        processor.cache.insert(processor.cache_key(key, &normalize_params(params.clone())), image).await;
+        assert_eq!(processor.last_modified(key, &params).await, Some(modified));
+
+        // 未缓存的派生图需要 head 原图；测试中的 S3 不可达，因此没有结果
+        let other = ProcessingParams { width: Some(16), ..Default::default() };
+        assert_eq!(processor.last_modified(key, &other).await, None);
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::{
+    image_processor::{cache_key, parse_query_params_for_key, ImageProcessingConfig},
+    s3_client::with_key_prefix,
+};
+
+// 清单中的一条记录：原图 key + 处理参数 → 离线预生成的派生图对象
+#[derive(Debug, Deserialize)]
+struct ManifestEntry {
+    key: String,
+    #[serde(default)]
+    params: HashMap<String, serde_json::Value>,
+    object: String,
+}
+
+/// 预生成派生图清单，命中时直接从 S3 返回对应对象，不经过 OpenCV
+#[derive(Debug, Default)]
+pub struct Manifest {
+    // 缓存键 → 预生成对象的 bucket/key
+    objects: HashMap<String, String>,
+}
+
+impl Manifest {
+    // 读取 JSON 清单，按与请求相同的方式解析参数并计算缓存键（含 s3.key_prefix），保证匹配规则一致
+    pub fn load(path: &str, config: &ImageProcessingConfig, key_prefix: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read manifest '{}'", path))?;
+        let entries: Vec<ManifestEntry> = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse manifest '{}'", path))?;
+
+        let objects = entries
+            .into_iter()
+            .map(|entry| {
+                let raw: HashMap<String, String> = entry
+                    .params
+                    .into_iter()
+                    .map(|(k, v)| match v {
+                        serde_json::Value::String(s) => (k, s),
+                        other => (k, other.to_string()),
+                    })
+                    .collect();
+                let params = parse_query_params_for_key(&entry.key, raw, config);
+                (cache_key(&with_key_prefix(&entry.key, key_prefix), &params), entry.object)
+            })
+            .collect::<HashMap<_, _>>();
+
+        println!("Loaded manifest '{}' with {} pre-rendered derivatives", path, objects.len());
+        Ok(Self { objects })
+    }
+
+    pub fn lookup(&self, cache_key: &str) -> Option<&str> {
+        self.objects.get(cache_key).map(String::as_str)
+    }
+}
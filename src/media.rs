@@ -0,0 +1,15 @@
+// 按扩展名识别的视频类型，这些对象原样流式返回（支持 Range），不交给 OpenCV
+const VIDEO_TYPES: &[(&str, &str)] = &[
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mov", "video/quicktime"),
+];
+
+pub fn video_content_type(key: &str) -> Option<&'static str> {
+    let (_, extension) = key.rsplit_once('.')?;
+    VIDEO_TYPES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map(|(_, content_type)| *content_type)
+}
@@ -0,0 +1,193 @@
+// 测试用的内存 S3：按 path-style 处理 GetObject/HeadObject/PutObject/GetObjectAcl/ListObjectsV2，并记录收到的请求数和返回的字节数
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use warp::{http::Response, Filter};
+
+use crate::s3_client::S3Config;
+
+// 错误响应里的 x-amz-request-id
+pub const REQUEST_ID: &str = "MOCKREQUEST0001";
+
+#[derive(Clone, Default)]
+pub struct MockObject {
+    pub data: Vec<u8>,
+    // 是否对 AllUsers 授予 READ
+    pub public: bool,
+    // 用户元数据（不含 x-amz-meta- 前缀）
+    pub metadata: Vec<(String, String)>,
+}
+
+#[derive(Clone)]
+pub struct MockS3 {
+    pub endpoint: String,
+    objects: Arc<Mutex<HashMap<String, MockObject>>>,
+    requests: Arc<AtomicUsize>,
+    bytes_served: Arc<AtomicUsize>,
+}
+
+impl MockS3 {
+    pub async fn start() -> Self {
+        let objects: Arc<Mutex<HashMap<String, MockObject>>> = Arc::default();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let bytes_served = Arc::new(AtomicUsize::new(0));
+        let route = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::body::bytes())
+            .map({
+                let objects = objects.clone();
+                let requests = requests.clone();
+                let bytes_served = bytes_served.clone();
+                move |method: warp::http::Method, path: warp::path::FullPath, query: String, range: Option<String>, body: bytes::Bytes| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    let path = percent_encoding::percent_decode_str(path.as_str().trim_start_matches('/'))
+                        .decode_utf8_lossy()
+                        .into_owned();
+                    let response = handle(&objects, method, &path, &query, range.as_deref(), body.to_vec());
+                    bytes_served.fetch_add(response.body().len(), Ordering::SeqCst);
+                    response
+                }
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        MockS3 { endpoint: format!("http://{}", addr), objects, requests, bytes_served }
+    }
+
+    pub fn put(&self, key: &str, data: Vec<u8>) {
+        self.put_object(key, MockObject { data, ..MockObject::default() });
+    }
+
+    pub fn put_object(&self, key: &str, object: MockObject) {
+        self.objects.lock().unwrap().insert(key.to_string(), object);
+    }
+
+    // 当前存储的对象内容，用来检查 PUT 写入的数据
+    pub fn object(&self, key: &str) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(key).map(|object| object.data.clone())
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    // 所有响应体的字节数之和，用来确认只读取了对象的一部分
+    pub fn bytes_served(&self) -> usize {
+        self.bytes_served.load(Ordering::SeqCst)
+    }
+
+    pub fn config(&self) -> S3Config {
+        S3Config {
+            endpoint: self.endpoint.clone(),
+            access_key: "test".to_string(),
+            secret_key: "test".to_string(),
+            region: "us-east-1".to_string(),
+            use_path_style: true,
+            key_prefix: String::new(),
+            circuit_breaker: None,
+            throttle_backoff: None,
+        }
+    }
+}
+
+fn handle(
+    objects: &Mutex<HashMap<String, MockObject>>,
+    method: warp::http::Method,
+    path: &str,
+    query: &str,
+    range: Option<&str>,
+    body: Vec<u8>,
+) -> Response<Vec<u8>> {
+    let mut objects = objects.lock().unwrap();
+    let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+
+    if params.get("list-type").map(String::as_str) == Some("2") {
+        let prefix = format!("{}/{}", path.trim_end_matches('/'), params.get("prefix").map(String::as_str).unwrap_or(""));
+        let mut keys: Vec<_> = objects.iter().filter(|(key, _)| key.starts_with(&prefix)).collect();
+        keys.sort_by(|a, b| a.0.cmp(b.0));
+        let contents: String = keys
+            .iter()
+            .map(|(key, object)| {
+                let (_, key) = key.split_once('/').unwrap_or_default();
+                format!("<Contents><Key>{}</Key><Size>{}</Size></Contents>", key, object.data.len())
+            })
+            .collect();
+        let xml = format!(
+            "<ListBucketResult><Name>{}</Name><KeyCount>{}</KeyCount><MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+            path.trim_end_matches('/'),
+            keys.len(),
+            contents
+        );
+        return xml_response(200, xml);
+    }
+
+    if method == warp::http::Method::PUT {
+        objects.insert(path.to_string(), MockObject { data: body, ..MockObject::default() });
+        return Response::builder().status(200).header("ETag", "\"mock\"").body(Vec::new()).unwrap();
+    }
+
+    let Some(object) = objects.get(path) else {
+        if method == warp::http::Method::HEAD {
+            return Response::builder().status(404).header("x-amz-request-id", REQUEST_ID).body(Vec::new()).unwrap();
+        }
+        let xml = format!(
+            "<Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message><RequestId>{}</RequestId></Error>",
+            REQUEST_ID
+        );
+        return xml_response(404, xml);
+    };
+
+    if params.contains_key("acl") {
+        let grant = if object.public {
+            "<Grant><Grantee xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:type=\"Group\"><URI>http://acs.amazonaws.com/groups/global/AllUsers</URI></Grantee><Permission>READ</Permission></Grant>"
+        } else {
+            ""
+        };
+        return xml_response(
+            200,
+            format!("<AccessControlPolicy><Owner><ID>owner</ID></Owner><AccessControlList>{}</AccessControlList></AccessControlPolicy>", grant),
+        );
+    }
+
+    let total = object.data.len();
+    let mut builder = Response::builder()
+        .header("Last-Modified", "Tue, 14 Nov 2023 22:13:20 GMT")
+        .header("ETag", "\"mock\"")
+        .header("Accept-Ranges", "bytes");
+    for (name, value) in &object.metadata {
+        builder = builder.header(format!("x-amz-meta-{}", name), value);
+    }
+    // 只支持单个区间 "bytes=start-end" / "bytes=start-"
+    let span = range
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, end)| {
+            let start: usize = start.parse().ok()?;
+            let end = if end.is_empty() { total.checked_sub(1)? } else { end.parse::<usize>().ok()?.min(total.checked_sub(1)?) };
+            (start <= end).then_some((start, end))
+        });
+    let (status, data) = match span {
+        Some((start, end)) => {
+            builder = builder.header("Content-Range", format!("bytes {}-{}/{}", start, end, total));
+            (206, object.data[start..=end].to_vec())
+        }
+        None => (200, object.data.clone()),
+    };
+    builder = builder.status(status).header("Content-Length", data.len());
+    let body = if method == warp::http::Method::HEAD { Vec::new() } else { data };
+    builder.body(body).unwrap()
+}
+
+fn xml_response(status: u16, xml: String) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/xml")
+        .header("x-amz-request-id", REQUEST_ID)
+        .body(xml.into_bytes())
+        .unwrap()
+}
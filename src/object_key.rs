@@ -0,0 +1,127 @@
+use anyhow::Result;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+
+use crate::error::RequestError;
+
+/// 路径中 `%2F` 的处理方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EncodedSlashes {
+    // 解码为 "/"，与普通路径分隔符等价（IIIF 标识符中的 bucket%2Fkey 也按此解析）
+    #[default]
+    Decode,
+    // 保留字面的 "%2F"，用于 key 本身就包含 "%2F" 的对象
+    Preserve,
+    // 返回 400
+    Reject,
+}
+
+/// 对象 key 的规范化策略（key_normalization），在拆分 bucket/key 之前作用于请求路径
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct KeyNormalization {
+    // URL 解码（%20 → 空格）；warp 的路径 tail 是未解码的原始路径
+    pub decode: bool,
+    pub encoded_slashes: EncodedSlashes,
+    // 合并重复的 "/" 并去掉首尾的 "/"
+    pub collapse_slashes: bool,
+    // 整个 key 转为小写，用于上传时统一转了小写的桶
+    pub lowercase: bool,
+}
+
+impl Default for KeyNormalization {
+    fn default() -> Self {
+        Self {
+            decode: true,
+            encoded_slashes: EncodedSlashes::default(),
+            collapse_slashes: true,
+            lowercase: false,
+        }
+    }
+}
+
+// 按策略规范化请求路径中的 "bucket/key"；解码后不是合法 UTF-8 或包含控制字符时返回 400
+pub fn normalize_key(raw: &str, policy: &KeyNormalization) -> Result<String> {
+    let mut key = if policy.decode {
+        decode(raw, policy.encoded_slashes)?
+    } else {
+        raw.to_string()
+    };
+    if key.chars().any(char::is_control) {
+        return Err(RequestError::bad_request("Object key contains control characters").into());
+    }
+    if policy.collapse_slashes {
+        key = key.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>().join("/");
+    }
+    if policy.lowercase {
+        key = key.to_lowercase();
+    }
+    Ok(key)
+}
+
+fn decode(raw: &str, encoded_slashes: EncodedSlashes) -> Result<String> {
+    let invalid = || RequestError::bad_request("Object key is not valid UTF-8 after URL-decoding");
+    // %2F 的位置在大写后不变（只改变 ASCII 字母），据此拆分
+    let upper = raw.to_ascii_uppercase();
+    let has_encoded_slash = upper.contains("%2F");
+    match encoded_slashes {
+        EncodedSlashes::Reject if has_encoded_slash => {
+            Err(RequestError::bad_request("Encoded slashes (%2F) are not allowed in object keys").into())
+        }
+        EncodedSlashes::Preserve if has_encoded_slash => {
+            let mut parts = Vec::new();
+            let mut start = 0;
+            for (index, _) in upper.match_indices("%2F") {
+                parts.push(percent_decode_str(&raw[start..index]).decode_utf8().map_err(|_| invalid())?);
+                start = index + 3;
+            }
+            parts.push(percent_decode_str(&raw[start..]).decode_utf8().map_err(|_| invalid())?);
+            Ok(parts.join("%2F"))
+        }
+        _ => Ok(percent_decode_str(raw).decode_utf8().map_err(|_| invalid())?.into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(raw: &str, encoded_slashes: EncodedSlashes) -> Result<String> {
+        normalize_key(raw, &KeyNormalization { encoded_slashes, ..Default::default() })
+    }
+
+    fn status(result: Result<String>) -> u16 {
+        result.unwrap_err().downcast_ref::<RequestError>().unwrap().status
+    }
+
+    #[test]
+    fn encoded_characters_are_decoded() {
+        assert_eq!(normalize("bucket/my%20photo.jpg", EncodedSlashes::Decode).unwrap(), "bucket/my photo.jpg");
+        assert_eq!(normalize("bucket/%E5%9B%BE.jpg", EncodedSlashes::Decode).unwrap(), "bucket/图.jpg");
+        assert_eq!(status(normalize("bucket/%FF.jpg", EncodedSlashes::Decode)), 400);
+        assert_eq!(status(normalize("bucket/a%0Ab.jpg", EncodedSlashes::Decode)), 400);
+    }
+
+    #[test]
+    fn encoded_slashes_follow_the_policy() {
+        assert_eq!(normalize("bucket%2Fdir%2fphoto.jpg", EncodedSlashes::Decode).unwrap(), "bucket/dir/photo.jpg");
+        assert_eq!(normalize("bucket/dir%2fmy%20photo.jpg", EncodedSlashes::Preserve).unwrap(), "bucket/dir%2Fmy photo.jpg");
+        assert_eq!(status(normalize("bucket/dir%2Fphoto.jpg", EncodedSlashes::Reject)), 400);
+        // 没有 %2F 时各策略结果相同
+        assert_eq!(normalize("bucket/photo.jpg", EncodedSlashes::Reject).unwrap(), "bucket/photo.jpg");
+    }
+
+    #[test]
+    fn duplicate_slashes_are_collapsed() {
+        assert_eq!(normalize("/bucket//dir///photo.jpg/", EncodedSlashes::Decode).unwrap(), "bucket/dir/photo.jpg");
+        let keep = KeyNormalization { collapse_slashes: false, ..Default::default() };
+        assert_eq!(normalize_key("bucket//photo.jpg", &keep).unwrap(), "bucket//photo.jpg");
+    }
+
+    #[test]
+    fn decoding_and_case_can_be_configured() {
+        let raw = KeyNormalization { decode: false, lowercase: true, ..Default::default() };
+        assert_eq!(normalize_key("Bucket/My%20Photo.JPG", &raw).unwrap(), "bucket/my%20photo.jpg");
+    }
+}
@@ -0,0 +1,278 @@
+use serde_json::{json, Map, Value};
+
+use crate::image_processor::{
+    ParamKind, AUTO_CONTENT_FORMAT, INTERPOLATIONS, MAX_VARIANT_FORMATS, OPTIONAL_OUTPUT_FORMATS, ORIENTATIONS, OUTPUT_FORMATS, QUERY_PARAMS,
+};
+
+// 由 QUERY_PARAMS 和各取值表生成 OpenAPI 3 文档，与实际解析使用同一份定义
+pub fn openapi_document(base_path: &str) -> Value {
+    let key_param = json!({
+        "name": "key",
+        "in": "path",
+        "required": true,
+        "description": "Object key as {bucket}/{key}",
+        "schema": { "type": "string" },
+    });
+    let image_params: Vec<Value> = std::iter::once(key_param.clone())
+        .chain(QUERY_PARAMS.iter().map(|param| {
+            let mut value = json!({
+                "name": param.name,
+                "in": "query",
+                "required": false,
+                "description": param.description,
+                "schema": param_schema(&param.kind),
+            });
+            // 逗号分隔的列表，如 formats=webp,jpg
+            if matches!(param.kind, ParamKind::FormatList) {
+                value["explode"] = json!(false);
+            }
+            value
+        }))
+        .collect();
+
+    let mut paths = Map::new();
+    paths.insert(
+        "/{key}".to_string(),
+        json!({
+            "get": {
+                "summary": "Fetch an image, transformed by the query parameters",
+                "parameters": image_params,
+                "responses": {
+                    "200": { "description": "Processed image" },
+                    "206": { "description": "Byte range of a video (.mp4, .webm, ...) passthrough" },
+                    "304": { "description": "Not modified since If-Modified-Since" },
+                    "400": { "description": "Invalid parameters" },
+                    "403": { "description": "Key is not allowed" },
+                    "404": { "description": "Image not found" },
+                    "415": { "description": "Source is not a supported image" },
+                    "503": { "description": "Overloaded or S3 unavailable" },
+                    "504": { "description": "Request timed out" },
+                },
+            },
+            "put": {
+                "summary": "Upload an original image (admin)",
+                "parameters": [key_param.clone()],
+                "responses": { "201": { "description": "Uploaded" }, "401": { "description": "Unauthorized" } },
+            },
+        }),
+    );
+    paths.insert(
+        "/info/{key}".to_string(),
+        json!({
+            "head": {
+                "summary": "Read the original's dimensions and content type from its header bytes",
+                "parameters": [key_param.clone()],
+                "responses": { "200": { "description": "X-Image-Width, X-Image-Height and Content-Type headers" } },
+            },
+        }),
+    );
+    paths.insert(
+        "/color/{key}".to_string(),
+        json!({
+            "get": {
+                "summary": "Dominant (k-means) and average color of the original",
+                "parameters": [key_param.clone()],
+                "responses": { "200": { "description": "JSON with dominant and average as #rrggbb" } },
+            },
+        }),
+    );
+    paths.insert(
+        "/histogram/{key}".to_string(),
+        json!({
+            "get": {
+                "summary": "Per-channel histograms of the original",
+                "parameters": [
+                    key_param.clone(),
+                    {
+                        "name": "bins",
+                        "in": "query",
+                        "description": "Number of bins per channel",
+                        "schema": { "type": "integer", "minimum": 1, "maximum": 256, "default": 256 },
+                    },
+                ],
+                "responses": {
+                    "200": { "description": "JSON with bins, pixels and one count array per channel" },
+                    "401": { "description": "Missing or invalid X-API-Key" },
+                    "403": { "description": "Outside the API key's buckets or the policy token" },
+                    "429": { "description": "API key rate limit exceeded" },
+                },
+            },
+        }),
+    );
+    paths.insert(
+        "/sizes/{key}".to_string(),
+        json!({
+            "get": {
+                "summary": "Encode the processed image to every supported format and report the byte sizes",
+                "parameters": [key_param.clone()],
+                "responses": { "200": { "description": "JSON object mapping format to size in bytes" } },
+            },
+        }),
+    );
+    paths.insert(
+        "/tile/{key}/{level}/{x}/{y}".to_string(),
+        json!({
+            "get": {
+                "summary": "Fetch one 256px deep-zoom tile; format and quality apply as usual",
+                "parameters": [key_param.clone()],
+                "responses": {
+                    "200": { "description": "Tile image" },
+                    "400": { "description": "Malformed path or level above the image's maximum" },
+                    "404": { "description": "Tile outside the image" },
+                },
+            },
+        }),
+    );
+    paths.insert(
+        "/iiif/{version}/{key}/{region}/{size}/{rotation}/{quality}.{format}".to_string(),
+        json!({
+            "get": {
+                "summary": "IIIF Image API 2.1 (version 2) or 3.0 (version 3) image request",
+                "parameters": [key_param.clone()],
+                "responses": {
+                    "200": { "description": "Processed image" },
+                    "400": { "description": "Unsupported region, size, rotation or quality" },
+                },
+            },
+        }),
+    );
+    paths.insert(
+        "/iiif/{version}/{key}/info.json".to_string(),
+        json!({
+            "get": {
+                "summary": "IIIF image information document",
+                "parameters": [key_param.clone()],
+                "responses": { "200": { "description": "info.json" } },
+            },
+        }),
+    );
+    paths.insert(
+        "/srcset/{key}".to_string(),
+        json!({
+            "get": {
+                "summary": "Build a srcset for the configured widths",
+                "parameters": [key_param.clone()],
+                "responses": { "200": { "description": "srcset string, or JSON with output=json" } },
+            },
+        }),
+    );
+    paths.insert(
+        "/cache/{key}".to_string(),
+        json!({
+            "delete": {
+                "summary": "Evict one cached derivative (admin)",
+                "parameters": [key_param],
+                "responses": { "200": { "description": "Evicted" }, "404": { "description": "Not cached" } },
+            },
+        }),
+    );
+    for (path, method, summary) in [
+        ("/health", "get", "Liveness check"),
+        ("/ready", "get", "Readiness check (encoder self-test)"),
+        ("/stats", "get", "Cache statistics, JSON with format=json"),
+        ("/metrics", "get", "Prometheus metrics"),
+        ("/clear-cache", "post", "Clear the cache"),
+        ("/warm", "post", "Start a cache warming job (admin)"),
+        ("/warm/{job_id}", "get", "Cache warming job status (admin)"),
+        ("/bench", "get", "Process a synthetic image and report timings (admin)"),
+        ("/policy-token", "post", "Sign a transform policy token (admin)"),
+        ("/config", "get", "Effective configuration with secrets redacted (admin)"),
+        ("/openapi.json", "get", "This document"),
+    ] {
+        paths.insert(
+            path.to_string(),
+            json!({ method: { "summary": summary, "responses": { "200": { "description": "OK" } } } }),
+        );
+    }
+
+    let mut document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "S3 Image Transformer",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+    });
+    if !base_path.is_empty() {
+        document["servers"] = json!([{ "url": base_path }]);
+    }
+    document
+}
+
+fn param_schema(kind: &ParamKind) -> Value {
+    let formats = || OUTPUT_FORMATS.iter().chain(OPTIONAL_OUTPUT_FORMATS).copied();
+    match kind {
+        ParamKind::Integer { min, max } => {
+            let mut schema = json!({ "type": "integer", "minimum": min });
+            if let Some(max) = max {
+                schema["maximum"] = json!(max);
+            }
+            schema
+        }
+        ParamKind::Boolean => json!({ "type": "boolean" }),
+        ParamKind::Text => json!({ "type": "string" }),
+        ParamKind::Format => json!({
+            "type": "string",
+            "enum": formats().chain(["original", "auto", AUTO_CONTENT_FORMAT]).collect::<Vec<_>>(),
+        }),
+        ParamKind::FormatList => json!({
+            "type": "array",
+            "items": { "type": "string", "enum": formats().collect::<Vec<_>>() },
+            "maxItems": MAX_VARIANT_FORMATS,
+        }),
+        ParamKind::Interpolation => json!({
+            "type": "string",
+            "enum": INTERPOLATIONS.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+        }),
+        ParamKind::Orientation => json!({
+            "type": "string",
+            "enum": ORIENTATIONS.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+        }),
+        ParamKind::AspectRatio => json!({ "type": "string", "pattern": "^[0-9]+:[0-9]+$" }),
+        ParamKind::Choice(values) => json!({ "type": "string", "enum": values }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_param<'a>(document: &'a Value, name: &str) -> &'a Value {
+        document["paths"]["/{key}"]["get"]["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|param| param["name"] == name)
+            .unwrap_or_else(|| panic!("parameter '{}' is missing", name))
+    }
+
+    #[test]
+    fn image_route_lists_the_core_query_params() {
+        let document = openapi_document("");
+        for name in ["width", "height", "quality", "format"] {
+            assert_eq!(image_param(&document, name)["in"], "query", "{}", name);
+        }
+        assert_eq!(image_param(&document, "width")["schema"], json!({ "type": "integer", "minimum": 1 }));
+        assert_eq!(image_param(&document, "quality")["schema"], json!({ "type": "integer", "minimum": 1, "maximum": 100 }));
+        let formats = image_param(&document, "format")["schema"]["enum"].as_array().unwrap().clone();
+        for format in ["jpg", "png", "webp", "avif", "original", "auto"] {
+            assert!(formats.contains(&json!(format)), "{}", format);
+        }
+        assert_eq!(image_param(&document, "key")["in"], "path");
+    }
+
+    #[test]
+    fn every_query_param_is_documented() {
+        let document = openapi_document("");
+        for param in QUERY_PARAMS {
+            image_param(&document, param.name);
+        }
+        assert_eq!(image_param(&document, "formats")["explode"], false);
+    }
+
+    #[test]
+    fn base_path_becomes_the_server_url() {
+        assert!(openapi_document("").get("servers").is_none());
+        assert_eq!(openapi_document("/images")["servers"], json!([{ "url": "/images" }]));
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    error::RequestError,
+    image_processor::{ProcessingParams, TILE_SIZE},
+};
+
+/// 签名令牌中携带的变换策略：令牌允许的是一族变换，而不是某一个具体 URL
+///
+/// 令牌格式为 `base64url(JSON).base64url(HMAC-SHA256(secret, base64url(JSON)))`，通过 `?token=` 传递。
+/// 所有字段都是可选的，未给出的维度不受限制。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransformPolicy {
+    // 允许访问的 key 前缀（bucket/path），例如 "avatars/users/42/"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    // 请求的 width/height 上限；策略限制了尺寸时，请求必须显式给出尺寸
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_quality: Option<i32>,
+    // 允许的输出格式；不带 format 的请求按 "original" 计
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formats: Option<Vec<String>>,
+    // 过期时间（Unix 秒）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+}
+
+// 校验签名与过期时间，返回令牌中的策略；任何失败都按 403 处理
+pub fn verify_token(secret: &str, token: &str) -> Result<TransformPolicy> {
+    let forbidden = |message: &str| anyhow::Error::from(RequestError::forbidden(message.to_string()));
+    let (payload, signature) = token.split_once('.').ok_or_else(|| forbidden("Malformed policy token"))?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| forbidden("Malformed policy token"))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(payload.as_bytes());
+    // verify_slice 为常数时间比较
+    mac.verify_slice(&signature).map_err(|_| forbidden("Invalid policy token signature"))?;
+
+    let json = URL_SAFE_NO_PAD.decode(payload).map_err(|_| forbidden("Malformed policy token"))?;
+    let policy: TransformPolicy = serde_json::from_slice(&json).map_err(|_| forbidden("Malformed policy token"))?;
+    if let Some(exp) = policy.exp {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now >= exp {
+            return Err(forbidden("Policy token has expired"));
+        }
+    }
+    Ok(policy)
+}
+
+// 生成与 verify_token 对应的令牌，格式见 TransformPolicy
+pub fn sign_policy(secret: &str, policy: &TransformPolicy) -> Result<String> {
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(policy)?);
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(payload.as_bytes());
+    Ok(format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())))
+}
+
+impl TransformPolicy {
+    // 请求的 key 和处理参数是否落在策略范围内，超出时返回 403 并说明原因；也用于 API key 的变换限制
+    pub fn check(&self, image_key: &str, params: &ProcessingParams) -> Result<()> {
+        self.check_key(image_key)?;
+        for (name, requested, max) in [("width", params.width, self.max_width), ("height", params.height, self.max_height)] {
+            match (requested, max) {
+                (Some(requested), Some(max)) if requested > max => return outside(format!("{} {} exceeds {}", name, requested, max)),
+                // 瓦片的尺寸固定为 TILE_SIZE
+                (None, Some(max)) if params.tile.is_some() && TILE_SIZE > max => {
+                    return outside(format!("tile {} {} exceeds {}", name, TILE_SIZE, max))
+                }
+                (None, Some(_)) if params.tile.is_some() => {}
+                // 未给出的一边按原图宽高比推算，可能远超上限，因此受限的每一边都必须显式给出
+                (None, Some(max)) => return outside(format!("an explicit {} of at most {} is required", name, max)),
+                _ => {}
+            }
+        }
+        if let (Some(quality), Some(max)) = (params.quality, self.max_quality) {
+            if quality > max {
+                return outside(format!("quality {} exceeds {}", quality, max));
+            }
+        }
+        if let Some(ref formats) = self.formats {
+            let requested = if params.auto_format { "auto" } else { params.format.as_deref().unwrap_or("original") };
+            if !formats.iter().any(|f| f.eq_ignore_ascii_case(requested)) {
+                return outside(format!("format '{}' is not allowed", requested));
+            }
+        }
+        Ok(())
+    }
+
+    // 只读取原图信息的请求（/info、/color、/histogram、IIIF info.json、视频透传）没有变换参数，只检查 key 前缀
+    pub fn check_key(&self, image_key: &str) -> Result<()> {
+        if let Some(ref prefix) = self.prefix {
+            if !image_key.starts_with(prefix.as_str()) {
+                return outside(format!("key is not under '{}'", prefix));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn outside(reason: String) -> Result<()> {
+    Err(RequestError::forbidden(format!("Request is outside the allowed transform policy: {}", reason)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+
+    fn policy() -> TransformPolicy {
+        TransformPolicy {
+            prefix: Some("avatars/users/42/".to_string()),
+            max_width: Some(800),
+            max_height: Some(800),
+            max_quality: Some(85),
+            formats: Some(vec!["webp".to_string(), "jpg".to_string()]),
+            exp: None,
+        }
+    }
+
+    fn params(width: Option<i32>, height: Option<i32>) -> ProcessingParams {
+        ProcessingParams { width, height, format: Some("webp".to_string()), ..Default::default() }
+    }
+
+    fn status(result: Result<impl std::fmt::Debug>) -> u16 {
+        result.unwrap_err().downcast_ref::<RequestError>().unwrap().status
+    }
+
+    #[test]
+    fn signed_token_round_trips() {
+        let token = sign_policy(SECRET, &policy()).unwrap();
+        let verified = verify_token(SECRET, &token).unwrap();
+        assert_eq!(verified.prefix, policy().prefix);
+        assert_eq!(verified.max_width, Some(800));
+    }
+
+    #[test]
+    fn tampered_or_foreign_tokens_are_rejected() {
+        let token = sign_policy(SECRET, &policy()).unwrap();
+        assert_eq!(status(verify_token("other-secret", &token)), 403);
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(br#"{"prefix":"avatars/"}"#);
+        assert_eq!(status(verify_token(SECRET, &format!("{}.{}", forged, signature))), 403);
+        assert_eq!(status(verify_token(SECRET, "not-a-token")), 403);
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let token = sign_policy(SECRET, &TransformPolicy { exp: Some(1), ..policy() }).unwrap();
+        assert_eq!(status(verify_token(SECRET, &token)), 403);
+    }
+
+    #[test]
+    fn requests_within_the_policy_pass() {
+        let policy = policy();
+        assert!(policy.check("avatars/users/42/me.jpg", &params(Some(800), Some(600))).is_ok());
+        let tile = ProcessingParams { tile: Some(crate::image_processor::Tile { level: 3, x: 0, y: 0 }), ..params(None, None) };
+        assert!(policy.check("avatars/users/42/me.jpg", &tile).is_ok());
+        assert!(policy.check_key("avatars/users/42/me.jpg").is_ok());
+    }
+
+    #[test]
+    fn requests_outside_the_policy_are_forbidden() {
+        let policy = policy();
+        let key = "avatars/users/42/me.jpg";
+        assert_eq!(status(policy.check("avatars/users/7/me.jpg", &params(Some(100), Some(100)))), 403);
+        assert_eq!(status(policy.check(key, &params(Some(801), Some(100)))), 403);
+        assert_eq!(status(policy.check(key, &ProcessingParams { quality: Some(90), ..params(Some(100), Some(100)) })), 403);
+        assert_eq!(status(policy.check(key, &ProcessingParams { format: Some("png".to_string()), ..params(Some(100), Some(100)) })), 403);
+        assert_eq!(status(policy.check_key("avatars/users/7/me.jpg")), 403);
+    }
+
+    #[test]
+    fn every_capped_dimension_must_be_explicit() {
+        // 只给 width 时高度按原图推算，100x10000 的原图会得到 10000px 高的结果
+        let policy = TransformPolicy { max_height: Some(500), ..Default::default() };
+        assert_eq!(status(policy.check("a/b.jpg", &params(Some(100), None))), 403);
+        assert!(policy.check("a/b.jpg", &params(Some(100), Some(500))).is_ok());
+        let policy = TransformPolicy { max_width: Some(500), ..Default::default() };
+        assert_eq!(status(policy.check("a/b.jpg", &params(None, Some(100)))), 403);
+    }
+}
@@ -0,0 +1,124 @@
+// 相机 RAW 原图：多数是 TIFF 结构（NEF/ARW/DNG），按魔数会被识别为 TIFF，因此以扩展名为准
+const RAW_EXTENSIONS: &[&str] = &["cr2", "cr3", "nef", "nrw", "arw", "dng", "raf", "orf", "rw2", "pef", "srw"];
+
+pub fn is_raw_key(key: &str) -> bool {
+    key.rsplit_once('.')
+        .is_some_and(|(_, ext)| RAW_EXTENSIONS.iter().any(|raw| raw.eq_ignore_ascii_case(ext)))
+}
+
+// 用 rawloader 解码并经 imagepipe 去马赛克、白平衡和伽马校正，得到 BGR Mat 交给后续的缩放/编码流程；
+// 输出不超过 max_width × max_height，避免全尺寸 RAW 占用过多内存
+#[cfg(feature = "raw")]
+pub fn decode_raw(data: &[u8], max_width: i32, max_height: i32) -> anyhow::Result<opencv::core::Mat> {
+    use opencv::{
+        core::{Mat, Scalar, CV_8UC3},
+        imgproc::{cvt_color_def, COLOR_RGB2BGR},
+        prelude::*,
+    };
+
+    let raw = rawloader::decode(&mut std::io::Cursor::new(data))
+        .map_err(|e| anyhow::anyhow!("Failed to decode RAW image: {}", e))?;
+    let mut pipeline = imagepipe::Pipeline::new_from_source(imagepipe::ImageSource::Raw(raw))
+        .map_err(|e| anyhow::anyhow!("Failed to set up RAW pipeline: {}", e))?;
+    pipeline.globals.settings.maxwidth = max_width.max(0) as usize;
+    pipeline.globals.settings.maxheight = max_height.max(0) as usize;
+    let rgb = pipeline
+        .output_8bit(None)
+        .map_err(|e| anyhow::anyhow!("Failed to develop RAW image: {}", e))?;
+
+    let mut img = Mat::new_rows_cols_with_default(rgb.height as i32, rgb.width as i32, CV_8UC3, Scalar::all(0.0))?;
+    img.data_bytes_mut()?.copy_from_slice(&rgb.data);
+    let mut bgr = Mat::default();
+    cvt_color_def(&img, &mut bgr, COLOR_RGB2BGR)?;
+    Ok(bgr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_keys_are_recognized_by_extension() {
+        for key in ["shoot/IMG_0001.CR2", "shoot/DSC_0042.nef", "a/b.ARW", "x.dng", "x.cr3", "x.Rw2"] {
+            assert!(is_raw_key(key), "{}", key);
+        }
+        // TIFF 结构的 RAW 与普通 TIFF 魔数相同，只有扩展名能区分
+        for key in ["photo.jpg", "scan.tiff", "archive.dng.zip", "nef", "folder.nef/readme", ""] {
+            assert!(!is_raw_key(key), "{}", key);
+        }
+    }
+
+    // 最小的未压缩 DNG：16 位 RGGB 拜耳数据，每个 2x2 单元 R=3000 G=1500 B=600，白电平 4095
+    #[cfg(feature = "raw")]
+    fn dng(width: u32, height: u32) -> Vec<u8> {
+        // (tag, type, count, value)；type 1=BYTE 2=ASCII 3=SHORT 4=LONG，超过 4 字节的值放在 IFD 之后
+        let short = |v: &[u16]| v.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+        let long = |v: u32| v.to_le_bytes().to_vec();
+        let mut entries: Vec<(u16, u16, u32, Vec<u8>)> = vec![
+            (254, 4, 1, long(0)),
+            (256, 4, 1, long(width)),
+            (257, 4, 1, long(height)),
+            (258, 3, 1, short(&[16])),
+            (259, 3, 1, short(&[1])),
+            (262, 3, 1, short(&[32803])),
+            (271, 2, 10, b"Synthetic\0".to_vec()),
+            (272, 2, 9, b"Test DNG\0".to_vec()),
+            (273, 4, 1, long(0)),
+            (277, 3, 1, short(&[1])),
+            (278, 4, 1, long(height)),
+            (279, 4, 1, long(width * height * 2)),
+            (33421, 3, 2, short(&[2, 2])),
+            (33422, 1, 4, vec![0, 1, 1, 2]),
+            (50706, 1, 4, vec![1, 4, 0, 0]),
+            (50717, 3, 1, short(&[4095])),
+        ];
+        let extra_start = 8 + 2 + entries.len() * 12 + 4;
+        let mut extra = Vec::new();
+        let mut offsets = Vec::new();
+        for (_, _, _, value) in &entries {
+            offsets.push(extra_start + extra.len());
+            if value.len() > 4 {
+                extra.extend_from_slice(value);
+                extra.resize(extra.len() + extra.len() % 2, 0);
+            }
+        }
+        let pixels_at = (extra_start + extra.len()) as u32;
+        entries[8].3 = long(pixels_at);
+
+        let mut out = b"II*\0".to_vec();
+        out.extend_from_slice(&8u32.to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for ((tag, kind, count, value), offset) in entries.iter().zip(offsets) {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            let mut field = if value.len() > 4 { (offset as u32).to_le_bytes().to_vec() } else { value.clone() };
+            field.resize(4, 0);
+            out.extend_from_slice(&field);
+        }
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&extra);
+        for row in 0..height {
+            for col in 0..width {
+                let value: u16 = match (row % 2, col % 2) {
+                    (0, 0) => 3000,
+                    (1, 1) => 600,
+                    _ => 1500,
+                };
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    #[cfg(feature = "raw")]
+    #[test]
+    fn a_dng_is_developed_to_bgr_within_the_size_limit() {
+        use opencv::prelude::*;
+        let img = decode_raw(&dng(32, 32), 16, 16).unwrap();
+        assert_eq!((img.cols(), img.rows(), img.channels()), (16, 16, 3));
+
+        let err = decode_raw(b"plain text, not a raw file", 16, 16).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to decode RAW image"), "{}", err);
+    }
+}
@@ -0,0 +1,384 @@
+use serde::{Deserialize, Serialize};
+
+use crate::image_processor::ProcessedImage;
+
+/// 共享缓存层（cache.redis）：内存未命中时先查 Redis，再回源处理；多个实例共享处理结果
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub struct RedisConfig {
+    // 例如 "redis://10.0.0.5:6379/0"
+    pub url: String,
+    // 所有键的前缀，便于与其他应用共用一个 Redis
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    // 单次读写的超时时间，超时按未命中/写入失败处理，不拖慢请求
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_key_prefix() -> String {
+    "s3img:".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    200
+}
+
+#[cfg(feature = "redis")]
+pub use enabled::RedisCache;
+
+#[cfg(not(feature = "redis"))]
+pub use disabled::RedisCache;
+
+#[cfg(feature = "redis")]
+mod enabled {
+    use anyhow::Result;
+    use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{ProcessedImage, RedisConfig};
+    use crate::logging::redact_url_password;
+
+    /// 每个条目存为一个 hash：图片字节和元数据各占一个字段，过期时间与内存缓存的 TTL 一致
+    #[derive(Clone)]
+    pub struct RedisCache {
+        connection: ConnectionManager,
+        key_prefix: String,
+    }
+
+    impl RedisCache {
+        // 连接延迟到第一次读写时建立，Redis 暂时不可用不影响启动
+        pub fn new(config: &RedisConfig) -> Result<Self> {
+            let client = redis::Client::open(config.url.as_str())?;
+            let timeout = Some(Duration::from_millis(config.timeout_ms));
+            let manager_config = ConnectionManagerConfig::new()
+                .set_response_timeout(timeout)
+                .set_connection_timeout(timeout)
+                .set_number_of_retries(1);
+            let connection = ConnectionManager::new_lazy_with_config(client, manager_config)?;
+            println!("Shared Redis cache enabled at {}", redact_url_password(&config.url));
+            Ok(Self {
+                connection,
+                key_prefix: config.key_prefix.clone(),
+            })
+        }
+
+        fn key(&self, key: &str) -> String {
+            format!("{}{}", self.key_prefix, key)
+        }
+
+        pub async fn get(&self, key: &str) -> Option<ProcessedImage> {
+            let mut connection = self.connection.clone();
+            let fields: HashMap<String, Vec<u8>> = match redis::cmd("HGETALL").arg(self.key(key)).query_async(&mut connection).await {
+                Ok(fields) => fields,
+                Err(e) => {
+                    eprintln!("Warning: Redis cache read failed: {}", e);
+                    return None;
+                }
+            };
+            from_fields(fields)
+        }
+
+        pub async fn insert(&self, key: &str, value: &ProcessedImage, ttl: Duration) {
+            if ttl.is_zero() {
+                return;
+            }
+            let key = self.key(key);
+            let mut connection = self.connection.clone();
+            let result = redis::pipe()
+                .atomic()
+                .cmd("DEL").arg(&key).ignore()
+                .cmd("HSET").arg(&key).arg(to_fields(value)).ignore()
+                .cmd("PEXPIRE").arg(&key).arg(ttl.as_millis() as u64).ignore()
+                .query_async::<()>(&mut connection)
+                .await;
+            if let Err(e) = result {
+                eprintln!("Warning: Redis cache write failed: {}", e);
+            }
+        }
+
+        pub async fn remove(&self, key: &str) -> bool {
+            let mut connection = self.connection.clone();
+            match redis::cmd("DEL").arg(self.key(key)).query_async::<u64>(&mut connection).await {
+                Ok(removed) => removed > 0,
+                Err(e) => {
+                    eprintln!("Warning: Redis cache delete failed: {}", e);
+                    false
+                }
+            }
+        }
+    }
+
+    fn epoch_ms(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+
+    fn to_fields(value: &ProcessedImage) -> Vec<(&'static str, Vec<u8>)> {
+        let mut fields = vec![
+            ("data", value.data.clone()),
+            ("content_type", value.content_type.clone().into_bytes()),
+        ];
+        let numbers = [
+            ("width", value.width.map(|v| v as u64)),
+            ("height", value.height.map(|v| v as u64)),
+            ("ttl_ms", value.ttl.map(|ttl| ttl.as_millis() as u64)),
+            ("expires_at_ms", value.expires_at.map(epoch_ms)),
+            ("cached_at_ms", value.cached_at.map(epoch_ms)),
+            ("last_modified_ms", value.last_modified.map(epoch_ms)),
+        ];
+        for (name, number) in numbers {
+            if let Some(number) = number {
+                fields.push((name, number.to_string().into_bytes()));
+            }
+        }
+        if let Some(ref blurhash) = value.blurhash {
+            fields.push(("blurhash", blurhash.clone().into_bytes()));
+        }
+        if let Some(ref degraded) = value.degraded {
+            fields.push(("degraded", degraded.clone().into_bytes()));
+        }
+        fields
+    }
+
+    // 缺少图片字节（不存在或已过期）时为未命中
+    fn from_fields(mut fields: HashMap<String, Vec<u8>>) -> Option<ProcessedImage> {
+        let data = fields.remove("data")?;
+        let text = |fields: &HashMap<String, Vec<u8>>, name: &str| {
+            fields.get(name).and_then(|v| String::from_utf8(v.clone()).ok())
+        };
+        let number = |fields: &HashMap<String, Vec<u8>>, name: &str| text(fields, name).and_then(|v| v.parse::<u64>().ok());
+        let from_epoch = |ms: u64| UNIX_EPOCH + Duration::from_millis(ms);
+
+        let cached_at = number(&fields, "cached_at_ms").map(from_epoch);
+        let ttl = number(&fields, "ttl_ms").map(Duration::from_millis);
+        // 读入内存缓存后同样不能活过 Redis 中的原条目
+        let entry_deadline = cached_at.zip(ttl).map(|(cached_at, ttl)| cached_at + ttl);
+        let expires_at = match (number(&fields, "expires_at_ms").map(from_epoch), entry_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Some(ProcessedImage {
+            data,
+            content_type: text(&fields, "content_type").unwrap_or_else(|| "application/octet-stream".to_string()),
+            width: number(&fields, "width").map(|v| v as i32),
+            height: number(&fields, "height").map(|v| v as i32),
+            ttl,
+            expires_at,
+            blurhash: text(&fields, "blurhash"),
+            compressed: false,
+            cached_at,
+            degraded: text(&fields, "degraded"),
+            last_modified: number(&fields, "last_modified_ms").map(from_epoch),
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::cache::{CacheConfig, ImageCache};
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::{TcpListener, TcpStream};
+
+        type Store = Arc<Mutex<HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>>>;
+
+        // 只实现 RedisCache 用到的命令（HSET/HGETALL/DEL/PEXPIRE 与 MULTI/EXEC）的内存 Redis，多个连接共享数据
+        async fn mock_redis() -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("redis://{}", listener.local_addr().unwrap());
+            let store = Store::default();
+            tokio::spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    tokio::spawn(serve(socket, store.clone()));
+                }
+            });
+            url
+        }
+
+        async fn serve(socket: TcpStream, store: Store) {
+            let mut reader = BufReader::new(socket);
+            let mut queued: Option<Vec<Vec<u8>>> = None;
+            while let Some(command) = read_command(&mut reader).await {
+                let reply = match command[0].to_ascii_uppercase().as_slice() {
+                    b"MULTI" => {
+                        queued = Some(Vec::new());
+                        b"+OK\r\n".to_vec()
+                    }
+                    b"EXEC" => {
+                        let replies = queued.take().unwrap_or_default();
+                        let mut out = format!("*{}\r\n", replies.len()).into_bytes();
+                        replies.into_iter().for_each(|reply| out.extend(reply));
+                        out
+                    }
+                    _ => {
+                        let reply = execute(&command, &store);
+                        match queued {
+                            Some(ref mut queued) => {
+                                queued.push(reply);
+                                b"+QUEUED\r\n".to_vec()
+                            }
+                            None => reply,
+                        }
+                    }
+                };
+                if reader.get_mut().write_all(&reply).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        fn execute(command: &[Vec<u8>], store: &Store) -> Vec<u8> {
+            let mut store = store.lock().unwrap();
+            match command[0].to_ascii_uppercase().as_slice() {
+                b"HSET" => {
+                    let entry = store.entry(command[1].clone()).or_default();
+                    for pair in command[2..].chunks(2) {
+                        entry.insert(pair[0].clone(), pair[1].clone());
+                    }
+                    format!(":{}\r\n", (command.len() - 2) / 2).into_bytes()
+                }
+                b"HGETALL" => {
+                    let fields = store.get(&command[1]).cloned().unwrap_or_default();
+                    let mut out = format!("*{}\r\n", fields.len() * 2).into_bytes();
+                    for (name, value) in fields {
+                        for bulk in [name, value] {
+                            out.extend(format!("${}\r\n", bulk.len()).into_bytes());
+                            out.extend(bulk);
+                            out.extend(b"\r\n");
+                        }
+                    }
+                    out
+                }
+                b"DEL" => {
+                    let removed = command[1..].iter().filter(|key| store.remove(*key).is_some()).count();
+                    format!(":{}\r\n", removed).into_bytes()
+                }
+                b"PEXPIRE" => b":1\r\n".to_vec(),
+                // 连接建立时的 CLIENT SETINFO 等
+                _ => b"+OK\r\n".to_vec(),
+            }
+        }
+
+        async fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.ok()? == 0 {
+                return None;
+            }
+            let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+            let mut args = Vec::with_capacity(count);
+            for _ in 0..count {
+                line.clear();
+                reader.read_line(&mut line).await.ok()?;
+                let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+                let mut arg = vec![0; len + 2];
+                reader.read_exact(&mut arg).await.ok()?;
+                arg.truncate(len);
+                args.push(arg);
+            }
+            Some(args)
+        }
+
+        fn image() -> ProcessedImage {
+            ProcessedImage {
+                data: vec![1, 2, 3, 4],
+                content_type: "image/webp".to_string(),
+                width: Some(120),
+                height: Some(80),
+                ttl: Some(Duration::from_secs(600)),
+                expires_at: None,
+                blurhash: Some("LEHV6nWB2yk8".to_string()),
+                compressed: false,
+                cached_at: None,
+                degraded: None,
+                last_modified: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            }
+        }
+
+        fn cache(url: &str) -> ImageCache {
+            ImageCache::new(
+                serde_json::from_value::<CacheConfig>(serde_json::json!({
+                    "max_capacity_mb": 16,
+                    "time_to_live_sec": 60,
+                    "time_to_idle_sec": 60,
+                    "redis": { "url": url, "timeout_ms": 1000 },
+                }))
+                .unwrap(),
+            )
+        }
+
+        #[test]
+        fn fields_round_trip_and_never_outlive_the_redis_entry() {
+            let cached_at = SystemTime::now();
+            let value = ProcessedImage { cached_at: Some(cached_at), ..image() };
+            let fields = to_fields(&value).into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+            let read = from_fields(fields).unwrap();
+            assert_eq!((read.data, read.content_type.as_str()), (value.data.clone(), "image/webp"));
+            assert_eq!((read.width, read.height, read.ttl), (Some(120), Some(80), Some(Duration::from_secs(600))));
+            assert_eq!(read.blurhash.as_deref(), Some("LEHV6nWB2yk8"));
+            assert_eq!(read.last_modified, value.last_modified);
+            // 毫秒精度
+            let deadline = read.expires_at.unwrap().duration_since(cached_at).unwrap_or_default();
+            assert!(deadline.abs_diff(Duration::from_secs(600)) < Duration::from_millis(2), "{:?}", deadline);
+            assert!(from_fields(HashMap::new()).is_none());
+        }
+
+        #[tokio::test]
+        async fn an_entry_written_by_one_instance_is_served_by_another() {
+            let url = mock_redis().await;
+            let (first, second) = (cache(&url), cache(&url));
+            let key = "bucket/photo.jpg:w=120";
+            first.insert(key.to_string(), image()).await;
+            assert!(!second.contains(key));
+
+            // Redis 写入在后台进行
+            let mut shared = None;
+            for _ in 0..200 {
+                shared = second.get(key).await;
+                if shared.is_some() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let shared = shared.expect("the second instance did not see the entry");
+            assert_eq!((shared.data, shared.content_type.as_str(), shared.width), (vec![1, 2, 3, 4], "image/webp", Some(120)));
+            // 读出后放入第二个实例的内存缓存
+            assert!(second.contains(key));
+
+            // 失效同时删除 Redis 中的条目，新的实例不会再读到
+            assert!(second.remove(key).await);
+            assert!(cache(&url).get(key).await.is_none());
+        }
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+mod disabled {
+    use anyhow::Result;
+    use std::time::Duration;
+
+    use super::{ProcessedImage, RedisConfig};
+
+    #[derive(Clone)]
+    pub struct RedisCache;
+
+    impl RedisCache {
+        // 未启用 redis feature 时只使用内存缓存
+        pub fn new(_config: &RedisConfig) -> Result<Self> {
+            eprintln!("Warning: cache.redis is configured but the `redis` feature is not enabled, using the in-memory cache only");
+            Ok(Self)
+        }
+
+        pub async fn get(&self, _key: &str) -> Option<ProcessedImage> {
+            None
+        }
+
+        pub async fn insert(&self, _key: &str, _value: &ProcessedImage, _ttl: Duration) {}
+
+        pub async fn remove(&self, _key: &str) -> bool {
+            false
+        }
+    }
+}
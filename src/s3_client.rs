@@ -1,21 +1,79 @@
 use anyhow::Result;
-use aws_sdk_s3::{Client, primitives::ByteStream};
-use serde::Deserialize;
-use std::sync::Arc;
+use aws_sdk_s3::{
+    Client,
+    error::{ProvideErrorMetadata, SdkError},
+    operation::{RequestId, RequestIdExt},
+    primitives::{ByteStream, DateTime},
+    types::Permission,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::{
+    circuit_breaker::{CircuitBreaker, CircuitBreakerConfig},
+    error::RequestError,
+    logging::{keys_redacted, redact_key},
+    throttle::{ThrottleBackoff, ThrottleBackoffConfig},
+};
+
+/// get_object_stream 返回的对象，body 在发送响应时才从 S3 读取
+pub struct S3Stream {
+    pub body: ByteStream,
+    pub content_length: u64,
+    // 按 Range 读取时 S3 返回的 "bytes start-end/total"
+    pub content_range: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct S3Config {
     pub endpoint: String,
     pub access_key: String,
     pub secret_key: String,
     pub region: String,
     pub use_path_style: bool,
+    // 加在每个对象 key 前面的前缀（如 "images/"），请求 /bucket/foo.jpg 读取 bucket 中的 images/foo.jpg
+    #[serde(default)]
+    pub key_prefix: String,
+    // get_object 的熔断配置，未配置时不启用
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    // S3 限流（503 SlowDown）后按 bucket 退避，退避期内的请求直接返回 503 + Retry-After；未配置时不启用
+    #[serde(default)]
+    pub throttle_backoff: Option<ThrottleBackoffConfig>,
+}
+
+/// get_object 返回的对象内容及元数据
+#[derive(Debug, Clone)]
+pub struct S3Object {
+    pub data: Vec<u8>,
+    // 用户元数据（x-amz-meta-*），键名不含前缀且为小写
+    pub metadata: HashMap<String, String>,
+    // 对象的过期时间：Expires 头与生命周期规则（x-amz-expiration 的 expiry-date）中较早者
+    pub expires_at: Option<SystemTime>,
+    // 对象的最后修改时间（秒级精度），随派生图一起缓存，用于 Last-Modified / If-Modified-Since
+    pub last_modified: Option<SystemTime>,
+}
+
+impl S3Object {
+    // 源站通过 `x-amz-meta-cache-ttl: <秒>` 指定该对象派生图的缓存时间
+    pub fn cache_ttl(&self) -> Option<Duration> {
+        self.metadata
+            .get("cache-ttl")
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct S3Client {
     pub client: Arc<Client>,
     pub config: S3Config,
+    breaker: Option<Arc<CircuitBreaker>>,
+    throttle: Option<Arc<ThrottleBackoff>>,
 }
 
 impl S3Client {
@@ -50,100 +108,462 @@ impl S3Client {
         let s3_config = builder.build();
         let client = Client::from_conf(s3_config);
 
+        let breaker = config
+            .circuit_breaker
+            .clone()
+            .map(|c| Arc::new(CircuitBreaker::new(c)));
+        let throttle = config
+            .throttle_backoff
+            .clone()
+            .map(|c| Arc::new(ThrottleBackoff::new(c)));
+
+        if !config.key_prefix.is_empty() {
+            println!("Prepending '{}' to every object key", config.key_prefix);
+        }
+
         Ok(Self {
             client: Arc::new(client),
             config,
+            breaker,
+            throttle,
         })
     }
 
-    pub async fn get_object(&self, key: &str) -> Result<Vec<u8>> {
-        // Parse the key to extract bucket and object key
-        // Expected format: bucket_name/object_key
-        let parts: Vec<&str> = key.splitn(2, '/').collect();
-        if parts.len() != 2 {
-            return Err(anyhow::anyhow!("Invalid key format. Expected 'bucket_name/object_key', got '{}'", key));
+    pub async fn get_object(&self, key: &str) -> Result<S3Object> {
+        let (bucket, object_key) = self.split_key(key)?;
+
+        // 熔断器断开期间直接失败，不再访问 S3
+        if let Some(ref breaker) = self.breaker {
+            if !breaker.allow() {
+                return Err(RequestError::service_unavailable("S3 backend unavailable (circuit breaker open)").into());
+            }
         }
+        self.check_throttle(bucket)?;
         
-        let bucket = parts[0];
-        let object_key = parts[1];
-        
-        println!("Attempting to fetch object with key: '{}' from bucket: '{}'", object_key, bucket);
+        println!("Attempting to fetch object '{}'", log_key(bucket, &object_key));
         
         let response = self.client
             .get_object()
             .bucket(bucket)
-            .key(object_key)
+            .key(&object_key)
             .send()
             .await;
 
         match response {
-            Ok(resp) => {
-                let data = resp.body.collect().await?;
-                let data_vec = data.into_bytes().to_vec();
-                println!("Successfully fetched object '{}/{}', size: {} bytes", bucket, object_key, data_vec.len());
-                Ok(data_vec)
+            Ok(mut resp) => {
+                let metadata = resp.metadata.take().unwrap_or_default();
+                let ids = request_ids(&resp);
+                let last_modified = resp.last_modified().and_then(http_seconds);
+                let expires_at = [
+                    resp.expires().and_then(http_seconds),
+                    resp.expiration().and_then(parse_expiration_date),
+                ]
+                .into_iter()
+                .flatten()
+                .min();
+                match resp.body.collect().await {
+                    Ok(data) => {
+                        self.record_outcome(true);
+                        self.record_throttle(bucket, false);
+                        let data_vec = data.into_bytes().to_vec();
+                        println!("Successfully fetched object '{}', size: {} bytes", log_key(bucket, &object_key), data_vec.len());
+                        Ok(S3Object { data: data_vec, metadata, expires_at, last_modified })
+                    }
+                    Err(e) => {
+                        self.record_outcome(false);
+                        Err(anyhow::anyhow!("S3 get_object body read failed for key '{}' ({}): {}", log_key(bucket, &object_key), ids, e))
+                    }
+                }
             }
             Err(e) => {
-                eprintln!("Failed to fetch object '{}/{}': {}", bucket, object_key, e);
+                // 对象不存在说明后端工作正常，不计入熔断失败
+                let missing = matches!(&e, SdkError::ServiceError(se) if se.err().is_no_such_key());
+                self.record_outcome(missing);
+                self.record_throttle(bucket, is_throttled(&e));
+                // 本次请求本身被限流时同样返回 503 + Retry-After
+                self.check_throttle(bucket)?;
+                eprintln!("Failed to fetch object '{}' ({}): {}", log_key(bucket, &object_key), request_ids(&e), e);
                 // Let's also log the specific type of error
-                eprintln!("Error type: {:?}", e);
-                Err(anyhow::anyhow!("S3 get_object failed for key '{}/{}': {}", bucket, object_key, e))
+                if !keys_redacted() {
+                    eprintln!("Error type: {:?}", e);
+                }
+                if missing {
+                    return Err(RequestError::not_found("Image not found").into());
+                }
+                Err(anyhow::anyhow!("S3 get_object failed for key '{}' ({}): {}", log_key(bucket, &object_key), request_ids(&e), e))
             }
         }
     }
 
-    pub async fn put_object(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<()> {
-        // Parse the key to extract bucket and object key
-        // Expected format: bucket_name/object_key
-        let parts: Vec<&str> = key.splitn(2, '/').collect();
-        if parts.len() != 2 {
-            return Err(anyhow::anyhow!("Invalid key format. Expected 'bucket_name/object_key', got '{}'", key));
+    // 流式读取对象；range 为客户端的 Range 头，原样转发给 S3（S3 只支持单个区间，多个区间时返回整个对象）
+    pub async fn get_object_stream(&self, key: &str, range: Option<&str>) -> Result<S3Stream> {
+        let (bucket, object_key) = self.split_key(key)?;
+
+        if let Some(ref breaker) = self.breaker {
+            if !breaker.allow() {
+                return Err(RequestError::service_unavailable("S3 backend unavailable (circuit breaker open)").into());
+            }
         }
-        
-        let bucket = parts[0];
-        let object_key = parts[1];
+        self.check_throttle(bucket)?;
+
+        let response = self.client
+            .get_object()
+            .bucket(bucket)
+            .key(&object_key)
+            .set_range(range.map(str::to_string))
+            .send()
+            .await;
+
+        match response {
+            Ok(resp) => {
+                self.record_outcome(true);
+                self.record_throttle(bucket, false);
+                Ok(S3Stream {
+                    content_length: u64::try_from(resp.content_length()).unwrap_or_default(),
+                    content_range: resp.content_range().map(str::to_string),
+                    body: resp.body,
+                })
+            }
+            Err(e) => {
+                // 对象不存在、区间无效都说明后端工作正常
+                let (missing, invalid_range) = match &e {
+                    SdkError::ServiceError(se) => (se.err().is_no_such_key(), se.err().code() == Some("InvalidRange")),
+                    _ => (false, false),
+                };
+                self.record_outcome(missing || invalid_range);
+                self.record_throttle(bucket, is_throttled(&e));
+                // 本次请求本身被限流时同样返回 503 + Retry-After
+                self.check_throttle(bucket)?;
+                if missing {
+                    return Err(RequestError::not_found("Object not found").into());
+                }
+                if invalid_range {
+                    return Err(RequestError::new(416, "Requested range not satisfiable").into());
+                }
+                Err(anyhow::anyhow!("S3 streaming get_object failed for key '{}' ({}): {}", log_key(bucket, &object_key), request_ids(&e), e))
+            }
+        }
+    }
+
+    // 通过 get_object_acl 判断对象是否对匿名用户（AllUsers）可读
+    pub async fn is_public_read(&self, key: &str) -> Result<bool> {
+        const ALL_USERS: &str = "http://acs.amazonaws.com/groups/global/AllUsers";
+        let (bucket, object_key) = self.split_key(key)?;
+
+        let response = self.client
+            .get_object_acl()
+            .bucket(bucket)
+            .key(&object_key)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("S3 get_object_acl failed for key '{}' ({}): {}", log_key(bucket, &object_key), request_ids(&e), e))?;
+
+        Ok(response.grants().unwrap_or_default().iter().any(|grant| {
+            grant.grantee().and_then(|g| g.uri()) == Some(ALL_USERS)
+                && matches!(grant.permission(), Some(Permission::Read | Permission::FullControl))
+        }))
+    }
+
+    // 读取对象的 [start, end] 字节区间（含两端），对象比区间短时返回实际内容
+    pub async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+        let (bucket, object_key) = self.split_key(key)?;
+        self.check_throttle(bucket)?;
+
+        let response = self.client
+            .get_object()
+            .bucket(bucket)
+            .key(&object_key)
+            .range(format!("bytes={}-{}", start, end))
+            .send()
+            .await;
+
+        match response {
+            Ok(resp) => {
+                self.record_throttle(bucket, false);
+                let data = resp.body.collect().await
+                    .map_err(|e| anyhow::anyhow!("S3 ranged get_object body read failed for key '{}': {}", log_key(bucket, &object_key), e))?;
+                Ok(data.into_bytes().to_vec())
+            }
+            Err(e) => {
+                self.record_throttle(bucket, is_throttled(&e));
+                // 本次请求本身被限流时同样返回 503 + Retry-After
+                self.check_throttle(bucket)?;
+                if matches!(&e, SdkError::ServiceError(se) if se.err().is_no_such_key()) {
+                    return Err(RequestError::not_found("Image not found").into());
+                }
+                Err(anyhow::anyhow!("S3 ranged get_object failed for key '{}' ({}): {}", log_key(bucket, &object_key), request_ids(&e), e))
+            }
+        }
+    }
+
+    // 拆分出 bucket 和实际的对象 key（已加上 key_prefix）
+    fn split_key<'a>(&self, key: &'a str) -> Result<(&'a str, String)> {
+        let (bucket, object_key) = split_key(key)?;
+        Ok((bucket, format!("{}{}", self.config.key_prefix, object_key)))
+    }
+
+    // 实际请求 S3 的 "bucket/key"，用于缓存键，使不同 key_prefix 的部署不会共用条目
+    pub fn effective_key(&self, key: &str) -> String {
+        with_key_prefix(key, &self.config.key_prefix)
+    }
+
+    // bucket 处于限流退避期时直接返回 503，Retry-After 为剩余时间（向上取整）
+    fn check_throttle(&self, bucket: &str) -> Result<()> {
+        let Some(remaining) = self.throttle.as_ref().and_then(|t| t.remaining(bucket)) else {
+            return Ok(());
+        };
+        let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        Err(RequestError::service_unavailable(format!("S3 is throttling bucket '{}', try again later", bucket))
+            .with_retry_after(secs)
+            .into())
+    }
+
+    fn record_throttle(&self, bucket: &str, throttled: bool) {
+        if let Some(ref throttle) = self.throttle {
+            if throttled {
+                throttle.record_throttle(bucket);
+            } else {
+                throttle.record_success(bucket);
+            }
+        }
+    }
+
+    fn record_outcome(&self, success: bool) {
+        if let Some(ref breaker) = self.breaker {
+            if success {
+                breaker.record_success();
+            } else {
+                breaker.record_failure();
+            }
+        }
+    }
+
+    pub async fn put_object(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<()> {
+        let (bucket, object_key) = self.split_key(key)?;
         
         let byte_stream = ByteStream::from(data);
         
         self.client
             .put_object()
             .bucket(bucket)
-            .key(object_key)
+            .key(&object_key)
             .body(byte_stream)
             .content_type(content_type)
             .send()
-            .await?;
+            .await
+            .map_err(|e| anyhow::anyhow!("S3 put_object failed for key '{}' ({}): {}", log_key(bucket, &object_key), request_ids(&e), e))?;
 
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub async fn object_exists(&self, key: &str) -> bool {
-        // Parse the key to extract bucket and object key
-        // Expected format: bucket_name/object_key
-        let parts: Vec<&str> = key.splitn(2, '/').collect();
-        if parts.len() != 2 {
+        let Ok((bucket, object_key)) = self.split_key(key) else {
             return false;
-        }
-        
-        let bucket = parts[0];
-        let object_key = parts[1];
+        };
         
         self.client
             .head_object()
             .bucket(bucket)
-            .key(object_key)
+            .key(&object_key)
             .send()
             .await
             .is_ok()
     }
 
+    pub async fn last_modified(&self, key: &str) -> Result<Option<SystemTime>> {
+        let (bucket, object_key) = self.split_key(key)?;
+
+        let response = self.client
+            .head_object()
+            .bucket(bucket)
+            .key(&object_key)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("S3 head_object failed for key '{}' ({}): {}", log_key(bucket, &object_key), request_ids(&e), e))?;
+
+        Ok(response.last_modified().and_then(http_seconds))
+    }
+
+    #[allow(dead_code)]
     pub async fn ensure_bucket_exists(&self) -> Result<()> {
         // This function is no longer applicable since we don't have a fixed bucket
         Ok(())
     }
     
-    pub async fn list_objects(&self, _prefix: &str) -> Result<Vec<String>> {
-        // This function is no longer applicable since we don't have a fixed bucket
-        Ok(Vec::new())
+    // 列出 "bucket/prefix" 下的对象，返回 "bucket/key" 形式的完整 key，最多 limit 个
+    pub async fn list_objects(&self, prefix: &str, limit: usize) -> Result<Vec<String>> {
+        let (bucket, object_prefix) = self.split_key(prefix)?;
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let response = self.client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(&object_prefix)
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("S3 list_objects_v2 failed for prefix '{}' ({}): {}", log_key(bucket, &object_prefix), request_ids(&e), e))?;
+
+            for object in response.contents().unwrap_or_default() {
+                // 以 "/" 结尾的是目录占位对象；返回的 key 去掉 key_prefix，与请求中的 key 对应
+                let key = object.key().map(|k| k.strip_prefix(self.config.key_prefix.as_str()).unwrap_or(k));
+                if let Some(key) = key.filter(|k| !k.ends_with('/')) {
+                    keys.push(format!("{}/{}", bucket, key));
+                    if keys.len() >= limit {
+                        return Ok(keys);
+                    }
+                }
+            }
+
+            match response.next_continuation_token() {
+                Some(token) if response.is_truncated() => continuation_token = Some(token.to_string()),
+                _ => return Ok(keys),
+            }
+        }
+    }
+}
+
+// 错误响应中的 x-amz-request-id 和 x-amz-id-2，向 S3 服务方报障时需要提供
+// 日志和错误信息中的 "bucket/key"，按 logging.keys 脱敏
+fn log_key(bucket: &str, object_key: &str) -> String {
+    redact_key(&format!("{}/{}", bucket, object_key))
+}
+
+// S3 的限流响应：503 SlowDown，以及兼容实现返回的其他限流错误码
+fn is_throttled<E: ProvideErrorMetadata, R>(e: &SdkError<E, R>) -> bool {
+    matches!(e, SdkError::ServiceError(se) if matches!(se.err().code(), Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestLimitExceeded" | "TooManyRequests")))
+}
+
+fn request_ids(e: &(impl RequestId + RequestIdExt)) -> String {
+    format!(
+        "request_id={}, extended_request_id={}",
+        e.request_id().unwrap_or("-"),
+        e.extended_request_id().unwrap_or("-")
+    )
+}
+
+// HTTP 日期只有秒级精度，这里同样截断到秒，便于与 If-Modified-Since 比较
+fn http_seconds(time: &DateTime) -> Option<SystemTime> {
+    u64::try_from(time.secs()).ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// 解析 x-amz-expiration 头，例如 `expiry-date="Fri, 23 Dec 2012 00:00:00 GMT", rule-id="rule"`
+fn parse_expiration_date(header: &str) -> Option<SystemTime> {
+    let (_, rest) = header.split_once("expiry-date=\"")?;
+    let (date, _) = rest.split_once('"')?;
+    httpdate::parse_http_date(date).ok()
+}
+
+// Parse the key to extract bucket and object key
+// Expected format: bucket_name/object_key
+fn split_key(key: &str) -> Result<(&str, &str)> {
+    key.split_once('/')
+        .ok_or_else(|| anyhow::anyhow!("Invalid key format. Expected 'bucket_name/object_key', got '{}'", redact_key(key)))
+}
+
+// "bucket/key" → "bucket/{prefix}key"，即实际请求 S3 的 key；没有 bucket 部分时原样返回
+pub fn with_key_prefix(key: &str, prefix: &str) -> String {
+    match key.split_once('/') {
+        Some((bucket, object_key)) if !prefix.is_empty() => format!("{}/{}{}", bucket, prefix, object_key),
+        _ => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn open_breaker_fails_fast_without_calling_s3() {
+        // 没有服务监听的端点：第一次请求真实失败并断开熔断器
+        let client = S3Client::new(S3Config {
+            endpoint: "http://127.0.0.1:9".to_string(),
+            access_key: "test".to_string(),
+            secret_key: "test".to_string(),
+            region: String::new(),
+            use_path_style: true,
+            key_prefix: String::new(),
+            circuit_breaker: Some(CircuitBreakerConfig { failure_threshold: 1, cooldown_sec: 60 }),
+            throttle_backoff: None,
+        })
+        .await
+        .unwrap();
+
+        let first = client.get_object("bucket/photo.jpg").await.unwrap_err();
+        assert!(first.downcast_ref::<RequestError>().is_none());
+
+        let second = client.get_object("bucket/photo.jpg").await.unwrap_err();
+        let re = second.downcast_ref::<RequestError>().unwrap();
+        assert_eq!(re.status, 503);
+        assert!(re.message.contains("circuit breaker open"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn a_range_request_streams_only_the_requested_bytes() {
+        let s3 = crate::mock_s3::MockS3::start().await;
+        let clip: Vec<u8> = (0..=255).collect();
+        s3.put("videos/clip.mp4", clip.clone());
+        let client = S3Client::new(s3.config()).await.unwrap();
+
+        let partial = client.get_object_stream("videos/clip.mp4", Some("bytes=16-31")).await.unwrap();
+        assert_eq!(partial.content_range.as_deref(), Some("bytes 16-31/256"));
+        assert_eq!(partial.content_length, 16);
+        assert_eq!(partial.body.collect().await.unwrap().into_bytes().as_ref(), &clip[16..32]);
+
+        let whole = client.get_object_stream("videos/clip.mp4", None).await.unwrap();
+        assert_eq!((whole.content_range, whole.content_length), (None, 256));
+    }
+
+    #[test]
+    fn cache_ttl_comes_from_the_object_metadata() {
+        let object = |ttl: Option<&str>| S3Object {
+            data: Vec::new(),
+            metadata: ttl.map(|ttl| HashMap::from([("cache-ttl".to_string(), ttl.to_string())])).unwrap_or_default(),
+            expires_at: None,
+            last_modified: None,
+        };
+        assert_eq!(object(Some("600")).cache_ttl(), Some(Duration::from_secs(600)));
+        assert_eq!(object(Some(" 30 ")).cache_ttl(), Some(Duration::from_secs(30)));
+        assert_eq!(object(Some("soon")).cache_ttl(), None);
+        assert_eq!(object(Some("-5")).cache_ttl(), None);
+        assert_eq!(object(None).cache_ttl(), None);
+    }
+
+    #[tokio::test]
+    async fn s3_errors_carry_the_request_id() {
+        let s3 = crate::mock_s3::MockS3::start().await;
+        let client = S3Client::new(s3.config()).await.unwrap();
+        let expected = format!("request_id={}", crate::mock_s3::REQUEST_ID);
+
+        // HEAD 的 404 只有响应头里的 x-amz-request-id，GET 的错误还带有 XML 错误体
+        let head = client.last_modified("photos/missing.jpg").await.unwrap_err().to_string();
+        assert!(head.contains(&expected), "{}", head);
+        let acl = client.is_public_read("photos/missing.jpg").await.unwrap_err().to_string();
+        assert!(acl.contains(&expected), "{}", acl);
+        assert!(acl.contains("'photos/missing.jpg'"), "{}", acl);
+    }
+
+    #[test]
+    fn the_key_prefix_goes_between_bucket_and_key() {
+        assert_eq!(with_key_prefix("media/foo.jpg", "images/"), "media/images/foo.jpg");
+        assert_eq!(with_key_prefix("media/a/b.png", "images/"), "media/images/a/b.png");
+        assert_eq!(with_key_prefix("media/foo.jpg", ""), "media/foo.jpg");
+        // 没有 bucket 部分时原样返回，由 split_key 报错
+        assert_eq!(with_key_prefix("foo.jpg", "images/"), "foo.jpg");
+    }
+
+    #[tokio::test]
+    async fn objects_are_read_from_under_the_key_prefix() {
+        let s3 = crate::mock_s3::MockS3::start().await;
+        s3.put("media/images/foo.jpg", b"prefixed".to_vec());
+        s3.put("media/foo.jpg", b"unprefixed".to_vec());
+        let client = S3Client::new(S3Config { key_prefix: "images/".to_string(), ..s3.config() }).await.unwrap();
+
+        assert_eq!(client.effective_key("media/foo.jpg"), "media/images/foo.jpg");
+        assert_eq!(client.get_object("media/foo.jpg").await.unwrap().data, b"prefixed");
+        assert_eq!(client.get_range("media/foo.jpg", 0, 3).await.unwrap(), b"pref");
+    }
+}
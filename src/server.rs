@@ -0,0 +1,396 @@
+use anyhow::Result;
+use hyper::{server::conn::Http, service::service_fn, Body, Request};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpListener,
+    sync::{mpsc, watch, Semaphore},
+    time::{Instant, Sleep},
+};
+use warp::{Filter, Reply};
+
+/// 连接的对端地址，作为请求扩展传给 warp filter（取代 `warp::addr::remote()`）
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// 传输层的连接限制
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    // 同时处理的最大连接数，None 表示不限制
+    pub max_connections: Option<usize>,
+    // 连接数已满时新连接最多排队等待的时间，超时后返回 503 并关闭
+    pub queue_timeout: Duration,
+}
+
+/// 连接超时配置（server.timeouts），防止慢速客户端长期占用连接
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    // 读取完整请求头的最长时间
+    pub header_read_ms: Option<u64>,
+    // 连接上连续多久没有任何读写就关闭，覆盖请求体读到一半停住和空闲的 keep-alive 连接
+    pub idle_ms: Option<u64>,
+    // 单个图片请求（访问检查 + S3 + 处理 + 缓存）的总耗时上限，超过返回 504
+    pub request_ms: Option<u64>,
+    // 收到退出信号后等待在途连接处理完的最长时间，超时后强制关闭；默认 DEFAULT_DRAIN_MS
+    pub shutdown_drain_ms: Option<u64>,
+}
+
+const DEFAULT_DRAIN_MS: u64 = 30_000;
+
+const SHED_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 20\r\nConnection: close\r\nRetry-After: 1\r\n\r\nToo many connections";
+
+/// 自行接受 TCP 连接并交给 hyper 处理，以便在接受连接时施加并发上限
+pub async fn serve<F>(filter: F, addr: SocketAddr, limits: ConnectionLimits, timeouts: TimeoutConfig) -> Result<()>
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let listener = TcpListener::bind(addr).await?;
+    serve_on(listener, filter, limits, timeouts, shutdown_signal()).await;
+    Ok(())
+}
+
+// 在已绑定的 listener 上接受连接，直到 shutdown 完成后排空退出
+async fn serve_on<F>(listener: TcpListener, filter: F, limits: ConnectionLimits, timeouts: TimeoutConfig, shutdown: impl Future<Output = ()>)
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let service = warp::service(filter);
+    let semaphore = limits.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+    let mut http = Http::new();
+    if let Some(ms) = timeouts.header_read_ms {
+        http.http1_header_read_timeout(Duration::from_millis(ms));
+    }
+    let http = Arc::new(http);
+    let idle_timeout = timeouts.idle_ms.map(Duration::from_millis);
+
+    let drain_timeout = Duration::from_millis(timeouts.shutdown_drain_ms.unwrap_or(DEFAULT_DRAIN_MS));
+
+    if let Some(max) = limits.max_connections {
+        println!("Connection limit: {} concurrent, queue timeout {:?}", max, limits.queue_timeout);
+    }
+
+    // 收到退出信号后通知每个连接优雅关闭；每个连接持有一个 drain_tx，全部释放即表示排空
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let (mut stream, peer) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                // 例如文件描述符耗尽，稍后重试而不是退出
+                eprintln!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let service = service.clone();
+        let semaphore = semaphore.clone();
+        let http = http.clone();
+        let queue_timeout = limits.queue_timeout;
+        let mut shutdown_rx = shutdown_rx.clone();
+        let drain_tx = drain_tx.clone();
+        tokio::spawn(async move {
+            let _drain = drain_tx;
+            // 连接数已满时排队等待许可，超时则直接返回 503
+            let _permit = match semaphore {
+                Some(semaphore) => match tokio::time::timeout(queue_timeout, semaphore.acquire_owned()).await {
+                    Ok(Ok(permit)) => Some(permit),
+                    _ => {
+                        eprintln!("Connection limit reached, shedding connection from {}", peer);
+                        let _ = stream.write_all(SHED_RESPONSE).await;
+                        let _ = stream.shutdown().await;
+                        return;
+                    }
+                },
+                None => None,
+            };
+
+            let svc = service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(PeerAddr(peer));
+                let mut service = service.clone();
+                async move {
+                    let response = hyper::service::Service::call(&mut service, req).await?;
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            let stream = IdleTimeoutStream::new(stream, idle_timeout);
+            let connection = http.serve_connection(stream, svc);
+            tokio::pin!(connection);
+            // 退出时处理完当前请求再关闭，keep-alive 连接不再接受新请求
+            let result = tokio::select! {
+                result = &mut connection => result,
+                _ = shutdown_rx.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                eprintln!("Connection error from {}: {}", peer, e);
+            }
+        });
+    }
+
+    // 停止接受新连接，等待在途连接排空，超过 drain 时间仍未结束的连接随进程退出被强制关闭
+    drop(listener);
+    println!("Shutdown signal received, draining connections for up to {:?}", drain_timeout);
+    let _ = shutdown_tx.send(true);
+    drop(drain_tx);
+    match tokio::time::timeout(drain_timeout, drain_rx.recv()).await {
+        Ok(_) => println!("All connections drained"),
+        Err(_) => eprintln!("Drain timeout of {:?} reached, closing remaining connections", drain_timeout),
+    }
+}
+
+// Ctrl-C 或 SIGTERM；无法注册信号处理时只是不响应该信号
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                eprintln!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+// 包装 TCP 连接：每次读写有进展时重置计时，超过 idle 时间仍无进展则让读写返回 TimedOut，hyper 随即关闭连接
+struct IdleTimeoutStream<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> IdleTimeoutStream<S> {
+    fn new(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: timeout.map(|t| Box::pin(tokio::time::sleep(t))),
+        }
+    }
+
+    fn reset(&mut self) {
+        if let (Some(sleep), Some(timeout)) = (self.sleep.as_mut(), self.timeout) {
+            sleep.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    // 读写挂起时检查是否已超时
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let expired = self.sleep.as_mut().is_some_and(|sleep| sleep.as_mut().poll(cx).is_ready());
+        if expired {
+            Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "connection idle timeout")))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeoutStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.reset();
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_expired(cx),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeoutStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                this.reset();
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_expired(cx).map_ok(|()| 0),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpStream, sync::oneshot, task::JoinHandle};
+
+    // 在随机端口上启动服务，发送 oneshot 即触发退出
+    async fn start<F>(filter: F, limits: ConnectionLimits, timeouts: TimeoutConfig) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<()>)
+    where
+        F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+        F::Extract: Reply,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_on(listener, filter, limits, timeouts, async {
+            let _ = stop_rx.await;
+        }));
+        (addr, stop_tx, server)
+    }
+
+    // 处理 /slow 需要 300ms
+    fn slow_route() -> impl Filter<Extract = (&'static str,), Error = warp::Rejection> + Clone + Send + Sync + 'static {
+        warp::path!("slow").and_then(|| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok::<_, warp::Rejection>("done")
+        })
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        response
+    }
+
+    fn limits(max: usize, queue_timeout_ms: u64) -> ConnectionLimits {
+        ConnectionLimits { max_connections: Some(max), queue_timeout: Duration::from_millis(queue_timeout_ms) }
+    }
+
+    #[tokio::test]
+    async fn a_connection_over_the_limit_is_shed_after_the_queue_timeout() {
+        let (addr, _stop, _server) = start(slow_route(), limits(1, 50), TimeoutConfig::default()).await;
+        let first = tokio::spawn(get(addr, "/slow"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let second = get(addr, "/slow").await;
+        assert!(second.starts_with("HTTP/1.1 503"), "{}", second);
+        assert!(second.ends_with("Too many connections"), "{}", second);
+
+        let first = first.await.unwrap();
+        assert!(first.starts_with("HTTP/1.1 200") && first.ends_with("done"), "{}", first);
+    }
+
+    #[tokio::test]
+    async fn a_connection_over_the_limit_waits_for_a_free_slot() {
+        let (addr, _stop, _server) = start(slow_route(), limits(1, 2_000), TimeoutConfig::default()).await;
+        let first = tokio::spawn(get(addr, "/slow"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // 排队到第一个连接结束后才开始处理
+        let started = Instant::now();
+        let second = get(addr, "/slow").await;
+        assert!(second.starts_with("HTTP/1.1 200") && second.ends_with("done"), "{}", second);
+        assert!(started.elapsed() >= Duration::from_millis(500), "{:?}", started.elapsed());
+        assert!(first.await.unwrap().starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn a_stream_with_no_progress_times_out() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut stream = IdleTimeoutStream::new(server, Some(Duration::from_millis(50)));
+
+        // 有数据时照常读取并重置计时
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let started = Instant::now();
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn a_client_that_stalls_mid_request_is_dropped() {
+        let upload = warp::path!("upload").and(warp::body::bytes()).map(|body: bytes::Bytes| format!("{} bytes", body.len()));
+        let timeouts = TimeoutConfig { idle_ms: Some(100), ..TimeoutConfig::default() };
+        let (addr, _stop, _server) = start(upload, ConnectionLimits { max_connections: None, queue_timeout: Duration::ZERO }, timeouts).await;
+
+        // 声明 10 字节的请求体只发送 3 字节后停住
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"POST /upload HTTP/1.1\r\nHost: test\r\nContent-Length: 10\r\n\r\nabc").await.unwrap();
+        let started = Instant::now();
+        let mut response = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response)).await;
+        assert!(closed.is_ok(), "stalled connection was not closed");
+        assert!(!String::from_utf8_lossy(&response).contains("200"), "{}", String::from_utf8_lossy(&response));
+        assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+
+        // 正常发送的请求不受影响
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"POST /upload HTTP/1.1\r\nHost: test\r\nConnection: close\r\nContent-Length: 3\r\n\r\nabc").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("3 bytes"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn a_stuck_request_does_not_hold_shutdown_past_the_drain_timeout() {
+        let stuck = warp::path!("stuck").and_then(|| async {
+            std::future::pending::<()>().await;
+            Ok::<_, warp::Rejection>("never")
+        });
+        let timeouts = TimeoutConfig { shutdown_drain_ms: Some(200), ..TimeoutConfig::default() };
+        let (addr, stop, server) = start(stuck, ConnectionLimits { max_connections: None, queue_timeout: Duration::ZERO }, timeouts).await;
+        let request = tokio::spawn(get(addr, "/stuck"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let started = Instant::now();
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), server).await.expect("shutdown hung on the stuck request").unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(1), "{:?}", elapsed);
+        request.abort();
+    }
+
+    #[tokio::test]
+    async fn shutdown_without_connections_does_not_wait_for_the_drain_timeout() {
+        let timeouts = TimeoutConfig { shutdown_drain_ms: Some(5_000), ..TimeoutConfig::default() };
+        let (_addr, stop, server) = start(slow_route(), ConnectionLimits { max_connections: None, queue_timeout: Duration::ZERO }, timeouts).await;
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), server).await.expect("idle server did not shut down").unwrap();
+    }
+}
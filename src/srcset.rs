@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SrcsetConfig {
+    // 生成 srcset 时使用的宽度列表，由服务端统一控制
+    pub widths: Vec<i32>,
+    // 生成的 URL 前缀，例如 "https://img.example.com"；为空时生成相对路径
+    pub base_url: String,
+}
+
+impl Default for SrcsetConfig {
+    fn default() -> Self {
+        Self {
+            widths: vec![320, 640, 960, 1280, 1920],
+            base_url: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SrcsetCandidate {
+    pub width: i32,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Srcset {
+    pub srcset: String,
+    pub candidates: Vec<SrcsetCandidate>,
+}
+
+// 为每个配置的宽度生成变换 URL；请求中的其余参数（quality、format 等）原样附加到每个 URL 上
+pub fn build_srcset(config: &SrcsetConfig, image_key: &str, params: &HashMap<String, String>) -> Srcset {
+    // 排序后输出，保证同样的请求得到同样的 URL（便于 CDN 缓存）
+    let extra: BTreeMap<&str, &str> = params
+        .iter()
+        .filter(|(k, _)| !matches!(k.as_str(), "width" | "output"))
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+
+    let base_url = config.base_url.trim_end_matches('/');
+    let candidates: Vec<SrcsetCandidate> = config
+        .widths
+        .iter()
+        .map(|&width| {
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            query.append_pair("width", &width.to_string());
+            for (k, v) in &extra {
+                query.append_pair(k, v);
+            }
+            SrcsetCandidate {
+                width,
+                url: format!("{}/{}?{}", base_url, image_key, query.finish()),
+            }
+        })
+        .collect();
+
+    let srcset = candidates
+        .iter()
+        .map(|c| format!("{} {}w", c.url, c.width))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Srcset { srcset, candidates }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(base_url: &str) -> SrcsetConfig {
+        SrcsetConfig { widths: vec![320, 640], base_url: base_url.to_string() }
+    }
+
+    #[test]
+    fn srcset_lists_every_configured_width() {
+        let result = build_srcset(&config("https://img.example.com/"), "bucket/photo.jpg", &HashMap::new());
+        assert_eq!(
+            result.srcset,
+            "https://img.example.com/bucket/photo.jpg?width=320 320w, https://img.example.com/bucket/photo.jpg?width=640 640w"
+        );
+        assert_eq!(result.candidates.iter().map(|c| c.width).collect::<Vec<_>>(), [320, 640]);
+    }
+
+    #[test]
+    fn other_params_are_carried_over_in_a_stable_order() {
+        let params: HashMap<String, String> = [("width", "9999"), ("output", "json"), ("quality", "70"), ("format", "webp")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let result = build_srcset(&config(""), "bucket/photo.jpg", &params);
+        // 请求中的 width 和 output 由 srcset 自身决定，不附加到 URL 上
+        assert_eq!(result.candidates[0].url, "/bucket/photo.jpg?width=320&format=webp&quality=70");
+        assert_eq!(result.candidates[1].url, "/bucket/photo.jpg?width=640&format=webp&quality=70");
+    }
+}
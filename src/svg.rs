@@ -0,0 +1,257 @@
+use anyhow::Result;
+use roxmltree::{Document, Node, NodeType, ParsingOptions};
+
+use crate::error::RequestError;
+
+// SVG 是文本格式，infer 无法识别；检查开头（跳过 BOM/空白）是否为标签且包含 <svg 根元素
+pub fn is_svg(bytes: &[u8]) -> bool {
+    // 只看前 4KB，XML 声明、注释和 doctype 都在这范围内
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(4096)]);
+    let text = head.trim_start_matches('\u{feff}').trim_start();
+    text.starts_with('<') && text.contains("<svg")
+}
+
+// 可执行脚本或嵌入任意 HTML 的元素，连同其内容一起去掉
+const DROPPED_ELEMENTS: &[&str] = &["script", "foreignobject", "iframe", "embed", "object"];
+// 可通过 attributeName 改写其他属性的动画元素
+const ANIMATION_ELEMENTS: &[&str] = &["set", "animate", "animatemotion", "animatetransform", "animatecolor"];
+
+/// 去掉 SVG 中可执行的内容（脚本、事件属性、javascript: 链接、改写链接或事件属性的动画），其余原样保留。
+/// 按 XML 解析后重新输出，实体（含 DTD 中定义的）在检查前已展开；无法解析时返回 415
+pub fn sanitize_svg(data: &[u8]) -> Result<Vec<u8>> {
+    let unparsable = || RequestError::unsupported_media_type("SVG source could not be parsed for sanitizing");
+    let text = std::str::from_utf8(data).map_err(|_| unparsable())?;
+    let options = ParsingOptions { allow_dtd: true, ..Default::default() };
+    let doc = Document::parse_with_options(text.trim_start_matches('\u{feff}'), options).map_err(|_| unparsable())?;
+    let mut out = String::with_capacity(data.len());
+    write_element(doc.root_element(), &mut out);
+    Ok(out.into_bytes())
+}
+
+fn write_element(node: Node, out: &mut String) {
+    let local = node.tag_name().name().to_ascii_lowercase();
+    if DROPPED_ELEMENTS.contains(&local.as_str()) {
+        return;
+    }
+    if ANIMATION_ELEMENTS.contains(&local.as_str()) && node.attribute("attributeName").is_some_and(rewrites_unsafe_attribute) {
+        return;
+    }
+
+    let name = qualified_name(node, node.tag_name().namespace(), node.tag_name().name(), true);
+    out.push('<');
+    out.push_str(&name);
+    // 只输出父元素中还没有的命名空间声明
+    let inherited: Vec<(Option<&str>, &str)> = node
+        .parent_element()
+        .map(|parent| parent.namespaces().map(|ns| (ns.name(), ns.uri())).collect())
+        .unwrap_or_default();
+    for ns in node.namespaces() {
+        if ns.name() == Some("xml") || inherited.contains(&(ns.name(), ns.uri())) {
+            continue;
+        }
+        match ns.name() {
+            Some(prefix) => out.push_str(&format!(" xmlns:{}=\"{}\"", prefix, escape(ns.uri()))),
+            None => out.push_str(&format!(" xmlns=\"{}\"", escape(ns.uri()))),
+        }
+    }
+    for attr in node.attributes() {
+        let attr_name = attr.name().to_ascii_lowercase();
+        if attr_name.starts_with("on") {
+            continue;
+        }
+        let value = if matches!(attr_name.as_str(), "href" | "src") && is_unsafe_url(attr.value()) {
+            "#"
+        } else {
+            attr.value()
+        };
+        let attr_name = qualified_name(node, attr.namespace(), attr.name(), false);
+        out.push_str(&format!(" {}=\"{}\"", attr_name, escape(value)));
+    }
+
+    if !node.has_children() {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+    for child in node.children() {
+        match child.node_type() {
+            NodeType::Element => write_element(child, out),
+            NodeType::Text => out.push_str(&escape(child.text().unwrap_or_default())),
+            NodeType::Comment => out.push_str(&format!("<!--{}-->", child.text().unwrap_or_default())),
+            // 处理指令（如 xml-stylesheet 引用外部样式）不保留
+            NodeType::PI | NodeType::Root => {}
+        }
+    }
+    out.push_str(&format!("</{}>", name));
+}
+
+// 按元素上生效的命名空间声明还原前缀；属性不使用默认命名空间
+fn qualified_name(node: Node, namespace: Option<&str>, local: &str, element: bool) -> String {
+    let Some(uri) = namespace else {
+        return local.to_string();
+    };
+    if element && node.default_namespace() == Some(uri) {
+        return local.to_string();
+    }
+    let prefix = if uri == roxmltree::NS_XML_URI {
+        Some("xml")
+    } else {
+        node.namespaces().find(|ns| ns.uri() == uri && ns.name().is_some()).and_then(|ns| ns.name())
+    };
+    match prefix {
+        Some(prefix) => format!("{}:{}", prefix, local),
+        None => local.to_string(),
+    }
+}
+
+// 动画的 attributeName 为 href/xlink:href 或事件属性时可以在运行时写入 javascript: 链接或脚本
+fn rewrites_unsafe_attribute(attribute: &str) -> bool {
+    let attribute = attribute.trim().to_ascii_lowercase();
+    let local = attribute.rsplit(':').next().unwrap_or_default();
+    local == "href" || local == "src" || local.starts_with("on")
+}
+
+// 浏览器解析 URL 时忽略其中的空白和控制字符（java\tscript:），比较前同样去掉
+fn is_unsafe_url(value: &str) -> bool {
+    let url: String = value.chars().filter(|c| !c.is_whitespace() && !c.is_control()).collect::<String>().to_ascii_lowercase();
+    url.starts_with("javascript:") || url.starts_with("vbscript:") || (url.starts_with("data:") && !url.starts_with("data:image/"))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// 将 SVG 栅格化为 PNG：给定宽/高时按比例缩放到该尺寸（不超过 max），否则使用 SVG 自身尺寸
+#[cfg(feature = "svg")]
+pub fn rasterize_svg(data: &[u8], width: Option<i32>, height: Option<i32>, max_width: i32, max_height: i32) -> anyhow::Result<Vec<u8>> {
+    use resvg::{tiny_skia, usvg};
+
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())
+        .map_err(|e| anyhow::anyhow!("Failed to parse SVG: {}", e))?;
+    let size = tree.size();
+    let (src_w, src_h) = (size.width(), size.height());
+
+    let scale = match (width, height) {
+        (Some(w), Some(h)) => (w as f32 / src_w).min(h as f32 / src_h),
+        (Some(w), None) => w as f32 / src_w,
+        (None, Some(h)) => h as f32 / src_h,
+        (None, None) => 1.0,
+    };
+    // 限制在配置的最大尺寸内
+    let scale = scale
+        .min(max_width as f32 / src_w)
+        .min(max_height as f32 / src_h);
+    let out_w = ((src_w * scale).round() as u32).max(1);
+    let out_h = ((src_h * scale).round() as u32).max(1);
+
+    let mut pixmap = tiny_skia::Pixmap::new(out_w, out_h)
+        .ok_or_else(|| anyhow::anyhow!("Invalid SVG raster size {}x{}", out_w, out_h))?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+    pixmap
+        .encode_png()
+        .map_err(|e| anyhow::anyhow!("Failed to encode rasterized SVG: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SVG_NS: &str = r#"xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink""#;
+
+    fn sanitize(svg: &str) -> String {
+        String::from_utf8(sanitize_svg(svg.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn scripts_and_foreign_objects_are_removed() {
+        let out = sanitize(&format!(
+            r#"<svg {}><script><![CDATA[alert(1)]]></script><foreignObject><div>x</div></foreignObject><circle r="5"/></svg>"#,
+            SVG_NS
+        ));
+        assert!(!out.contains("script") && !out.contains("alert") && !out.contains("foreignObject"));
+        assert!(out.contains(r#"<circle r="5"/>"#));
+        assert!(is_svg(out.as_bytes()));
+    }
+
+    #[test]
+    fn event_attributes_are_removed_regardless_of_spacing_or_case() {
+        let out = sanitize(&format!(r#"<svg {} onload="alert(1)"><g ONCLICK='alert(2)'/><rect
+onmouseover="alert(3)" width="1"/></svg>"#, SVG_NS));
+        assert!(!out.to_ascii_lowercase().contains("alert"), "{}", out);
+        assert!(out.contains(r#"width="1""#));
+    }
+
+    #[test]
+    fn malformed_markup_is_rejected_instead_of_passed_through() {
+        // 按 HTML 宽松解析时 / 可以代替空白分隔属性
+        for svg in ["<svg/onload=alert(1)>", "<svg><g/onload=alert(1)></g></svg>", "not svg"] {
+            assert!(sanitize_svg(svg.as_bytes()).is_err(), "{}", svg);
+        }
+        assert!(sanitize_svg(&[0xff, 0xfe, b'<']).is_err());
+    }
+
+    #[test]
+    fn javascript_urls_are_neutralized_after_entity_decoding() {
+        let payloads = [
+            r#"<a href="javascript:alert(1)"><circle r="1"/></a>"#,
+            r#"<a xlink:href="&#106;avascript:alert(1)"><circle r="1"/></a>"#,
+            r#"<a href="&#x6A;&#x61;vascript&#58;alert(1)"><circle r="1"/></a>"#,
+            r#"<a href=" java&#9;script:alert(1)"><circle r="1"/></a>"#,
+            r#"<a href="JaVaScRiPt:alert(1)"><circle r="1"/></a>"#,
+            r#"<a href="data:text/html,&lt;script&gt;alert(1)&lt;/script&gt;"><circle r="1"/></a>"#,
+        ];
+        for payload in payloads {
+            let out = sanitize(&format!("<svg {}>{}</svg>", SVG_NS, payload));
+            assert!(!out.contains("alert"), "{} -> {}", payload, out);
+            assert!(out.contains(r##"href="#""##), "{}", out);
+        }
+        // 普通链接保持不变
+        let out = sanitize(&format!(r##"<svg {}><use xlink:href="#icon"/><a href="https://example.com/">x</a></svg>"##, SVG_NS));
+        assert!(out.contains(r##"<use xlink:href="#icon"/>"##) && out.contains(r#"href="https://example.com/""#));
+    }
+
+    #[test]
+    fn dtd_entities_are_expanded_before_checking() {
+        let svg = format!(
+            r#"<!DOCTYPE svg [<!ENTITY js "javascript:alert(1)">]><svg {}><a href="&js;">x</a></svg>"#,
+            SVG_NS
+        );
+        let out = sanitize(&svg);
+        assert!(!out.contains("alert") && !out.contains("DOCTYPE"), "{}", out);
+    }
+
+    #[test]
+    fn animations_that_write_links_or_handlers_are_removed() {
+        let out = sanitize(&format!(
+            r#"<svg {}><a><set attributeName="href" to="javascript:alert(1)"/><animate attributeName="xlink:href" values="javascript:alert(2)"/><set attributeName="onmouseover" to="alert(3)"/><animate attributeName="opacity" from="0" to="1" dur="1s"/>x</a></svg>"#,
+            SVG_NS
+        ));
+        assert!(!out.contains("alert"), "{}", out);
+        assert!(out.contains(r#"attributeName="opacity""#));
+    }
+
+    #[test]
+    fn namespaces_and_text_survive_rewriting() {
+        let out = sanitize(&format!(
+            r##"<?xml version="1.0"?><?xml-stylesheet href="https://evil.example/x.css"?><svg {} viewBox="0 0 10 10"><!-- logo --><text x="1">a &amp; b &lt; c</text><use xlink:href="#t"/></svg>"##,
+            SVG_NS
+        ));
+        assert_eq!(
+            out,
+            format!(r##"<svg {} viewBox="0 0 10 10"><!-- logo --><text x="1">a &amp; b &lt; c</text><use xlink:href="#t"/></svg>"##, SVG_NS)
+        );
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn rasterized_svg_is_a_png_at_the_requested_size() {
+        let svg = format!(r#"<svg {} width="100" height="50"><rect width="100" height="50" fill="red"/></svg>"#, SVG_NS);
+        let png = rasterize_svg(svg.as_bytes(), Some(40), None, 1920, 1080).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+        let size = imagesize::blob_size(&png).unwrap();
+        assert_eq!((size.width, size.height), (40, 20));
+        // 不超过配置的最大尺寸
+        let png = rasterize_svg(svg.as_bytes(), Some(400), None, 200, 1080).unwrap();
+        assert_eq!(imagesize::blob_size(&png).unwrap().width, 200);
+    }
+}
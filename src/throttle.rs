@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThrottleBackoffConfig {
+    // 收到限流响应后该 bucket 暂停访问的时间（秒）
+    #[serde(default = "default_backoff_sec")]
+    pub backoff_sec: u64,
+    // 退避期结束后立即再次被限流时时间翻倍，最长不超过该值（秒）
+    #[serde(default = "default_max_backoff_sec")]
+    pub max_backoff_sec: u64,
+}
+
+fn default_backoff_sec() -> u64 {
+    1
+}
+
+fn default_max_backoff_sec() -> u64 {
+    30
+}
+
+#[derive(Debug)]
+struct Backoff {
+    until: Instant,
+    window: Duration,
+}
+
+/// 按 bucket 记录的限流退避：S3 返回 503 SlowDown 后，退避期内对该 bucket 的新请求直接失败，不再加重后端负担。
+///
+/// 与熔断器不同，只对限流响应生效，且各 bucket 互不影响。
+#[derive(Debug)]
+pub struct ThrottleBackoff {
+    config: ThrottleBackoffConfig,
+    // bucket 来自请求路径，成功访问后即移除，表中只保留正在退避或刚退避过的 bucket
+    buckets: Mutex<HashMap<String, Backoff>>,
+}
+
+impl ThrottleBackoff {
+    pub fn new(config: ThrottleBackoffConfig) -> Self {
+        Self { config, buckets: Mutex::default() }
+    }
+
+    // 该 bucket 仍在退避期内时返回剩余时间
+    pub fn remaining(&self, bucket: &str) -> Option<Duration> {
+        let buckets = self.buckets.lock().unwrap();
+        let remaining = buckets.get(bucket)?.until.checked_duration_since(Instant::now())?;
+        Some(remaining).filter(|d| !d.is_zero())
+    }
+
+    pub fn record_throttle(&self, bucket: &str) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let initial = Duration::from_secs(self.config.backoff_sec.max(1));
+        let max = Duration::from_secs(self.config.max_backoff_sec).max(initial);
+        let window = match buckets.get(bucket) {
+            // 退避开始前已发出的并发请求陆续返回限流，属于同一次限流，保持当前退避
+            Some(previous) if previous.until > now => return,
+            // 上一次退避结束后仍被限流，加倍退避
+            Some(previous) => (previous.window * 2).min(max),
+            None => initial,
+        };
+        eprintln!("S3 throttled requests to bucket '{}', backing off for {}s", bucket, window.as_secs());
+        buckets.insert(bucket.to_string(), Backoff { until: now + window, window });
+    }
+
+    pub fn record_success(&self, bucket: &str) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.remove(bucket).is_some() {
+            println!("S3 bucket '{}' is no longer throttled", bucket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> ThrottleBackoff {
+        ThrottleBackoff::new(ThrottleBackoffConfig { backoff_sec: 2, max_backoff_sec: 30 })
+    }
+
+    #[test]
+    fn throttled_bucket_backs_off_until_success() {
+        let throttle = backoff();
+        assert!(throttle.remaining("photos").is_none());
+        throttle.record_throttle("photos");
+        let remaining = throttle.remaining("photos").unwrap();
+        assert!(remaining > Duration::from_secs(1) && remaining <= Duration::from_secs(2));
+        // 其他 bucket 不受影响
+        assert!(throttle.remaining("avatars").is_none());
+        throttle.record_success("photos");
+        assert!(throttle.remaining("photos").is_none());
+    }
+
+    #[test]
+    fn concurrent_throttles_do_not_compound() {
+        let throttle = backoff();
+        for _ in 0..10 {
+            throttle.record_throttle("photos");
+        }
+        assert!(throttle.remaining("photos").unwrap() <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn throttling_after_a_window_doubles_it() {
+        let throttle = backoff();
+        throttle.record_throttle("photos");
+        // 模拟退避期已结束
+        throttle.buckets.lock().unwrap().get_mut("photos").unwrap().until = Instant::now();
+        throttle.record_throttle("photos");
+        let remaining = throttle.remaining("photos").unwrap();
+        assert!(remaining > Duration::from_secs(3) && remaining <= Duration::from_secs(4));
+    }
+}
@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+// 区分的租户数上限，超出后的新租户计入 "other"，防止伪造的租户头让计数表无限增长
+const MAX_TENANTS: usize = 1000;
+const OVERFLOW_TENANT: &str = "other";
+
+/// 按租户统计用量（usage），用于计费/配额
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct UsageConfig {
+    pub enabled: bool,
+    // 标识租户的请求头（如 "X-Api-Key"）；未配置或请求未携带时按 bucket 归属
+    pub tenant_header: Option<String>,
+}
+
+impl UsageConfig {
+    pub fn tenant_for(&self, image_key: &str, headers: &warp::http::HeaderMap) -> String {
+        self.tenant_header
+            .as_deref()
+            .and_then(|name| headers.get(name))
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| image_key.split('/').next().unwrap_or_default())
+            .to_string()
+    }
+}
+
+// 请求路径中 {bucket}/{key} 的部分：/tile/ 和 /iiif/{2|3}/ 路由先去掉前缀，其余图片/视频路由的路径本身就是 key
+pub fn image_path(path: &str) -> &str {
+    let path = path.trim_start_matches('/');
+    if let Some(rest) = path.strip_prefix("tile/") {
+        return rest;
+    }
+    match path.strip_prefix("iiif/") {
+        Some(rest) => rest.split_once('/').map(|(_, rest)| rest).unwrap_or_default(),
+        None => path,
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TenantUsage {
+    pub requests: u64,
+    pub bytes_served: u64,
+    // 实际经过处理（未命中缓存）的图片数
+    pub images_processed: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UsageTracker {
+    tenants: Arc<Mutex<HashMap<String, TenantUsage>>>,
+}
+
+impl UsageTracker {
+    pub fn record(&self, tenant: &str, bytes: u64, processed: bool) {
+        let mut tenants = self.tenants.lock().unwrap();
+        let tenant = if tenants.contains_key(tenant) || tenants.len() < MAX_TENANTS {
+            tenant
+        } else {
+            OVERFLOW_TENANT
+        };
+        let usage = tenants.entry(tenant.to_string()).or_default();
+        usage.requests += 1;
+        usage.bytes_served += bytes;
+        if processed {
+            usage.images_processed += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, TenantUsage> {
+        self.tenants
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tenant, usage)| (tenant.clone(), usage.clone()))
+            .collect()
+    }
+}
+
+pub fn to_prometheus(tenants: &BTreeMap<String, TenantUsage>) -> String {
+    let mut out = String::new();
+    out.push_str("# TYPE image_tenant_requests_total counter\n");
+    for (tenant, usage) in tenants {
+        out.push_str(&format!("image_tenant_requests_total{{tenant=\"{}\"}} {}\n", escape_label(tenant), usage.requests));
+    }
+    out.push_str("# TYPE image_tenant_bytes_served_total counter\n");
+    for (tenant, usage) in tenants {
+        out.push_str(&format!("image_tenant_bytes_served_total{{tenant=\"{}\"}} {}\n", escape_label(tenant), usage.bytes_served));
+    }
+    out.push_str("# TYPE image_tenant_images_processed_total counter\n");
+    for (tenant, usage) in tenants {
+        out.push_str(&format!(
+            "image_tenant_images_processed_total{{tenant=\"{}\"}} {}\n",
+            escape_label(tenant),
+            usage.images_processed
+        ));
+    }
+    out
+}
+
+// 租户名来自请求头，输出为 Prometheus 标签值前转义
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenants_accrue_separate_bytes_and_processing_counts() {
+        let tracker = UsageTracker::default();
+        tracker.record("acme", 1000, true);
+        tracker.record("acme", 500, false);
+        tracker.record("globex", 20, true);
+
+        let tenants = tracker.snapshot();
+        let acme = &tenants["acme"];
+        assert_eq!((acme.requests, acme.bytes_served, acme.images_processed), (2, 1500, 1));
+        let globex = &tenants["globex"];
+        assert_eq!((globex.requests, globex.bytes_served, globex.images_processed), (1, 20, 1));
+    }
+
+    #[test]
+    fn tenants_beyond_the_limit_are_counted_as_other() {
+        let tracker = UsageTracker::default();
+        for i in 0..MAX_TENANTS {
+            tracker.record(&format!("tenant-{}", i), 1, false);
+        }
+        tracker.record("late", 1, false);
+        tracker.record("tenant-0", 1, false);
+
+        let tenants = tracker.snapshot();
+        assert_eq!(tenants[OVERFLOW_TENANT].requests, 1);
+        assert_eq!(tenants["tenant-0"].requests, 2);
+        assert!(!tenants.contains_key("late"));
+    }
+
+    #[test]
+    fn tenant_comes_from_the_header_or_the_bucket() {
+        let config = UsageConfig { enabled: true, tenant_header: Some("X-Tenant".to_string()) };
+        let mut headers = warp::http::HeaderMap::new();
+        assert_eq!(config.tenant_for("photos/a.jpg", &headers), "photos");
+        headers.insert("x-tenant", " acme ".parse().unwrap());
+        assert_eq!(config.tenant_for("photos/a.jpg", &headers), "acme");
+    }
+
+    #[test]
+    fn image_path_strips_tile_and_iiif_prefixes() {
+        assert_eq!(image_path("photos/a.jpg"), "photos/a.jpg");
+        assert_eq!(image_path("photos/clip.mp4"), "photos/clip.mp4");
+        assert_eq!(image_path("tile/photos/a.jpg/3/1/2"), "photos/a.jpg/3/1/2");
+        assert_eq!(image_path("iiif/3/photos/a.jpg/full/max/0/default.jpg"), "photos/a.jpg/full/max/0/default.jpg");
+        assert_eq!(image_path("/iiif/2/photos/a.jpg/info.json"), "photos/a.jpg/info.json");
+    }
+}
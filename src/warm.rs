@@ -0,0 +1,212 @@
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    image_processor::{parse_query_params_for_key, ImageProcessingConfig, ImageProcessor},
+    logging::redact_key,
+};
+
+// 单个预热任务最多处理的对象数
+const MAX_WARM_OBJECTS: usize = 10_000;
+const MAX_WARM_CONCURRENCY: usize = 16;
+// 超过该数量时清理已结束的任务记录
+const MAX_TRACKED_JOBS: usize = 100;
+
+/// POST /warm 的请求体
+#[derive(Debug, Deserialize)]
+pub struct WarmRequest {
+    // "bucket/prefix"，例如 "gallery/2024/"
+    pub prefix: String,
+    // 与图片请求相同的查询参数，例如 { "width": "300", "format": "webp" }
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_limit() -> usize {
+    1000
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmState {
+    Listing,
+    Running,
+    Done,
+    Failed,
+}
+
+/// 预热任务的进度，供 GET /warm/{job_id} 查询
+#[derive(Debug, Clone, Serialize)]
+pub struct WarmStatus {
+    pub job_id: String,
+    pub prefix: String,
+    pub state: WarmState,
+    pub total: usize,
+    pub processed: usize,
+    pub failed: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// 后台预热任务表
+#[derive(Debug, Clone, Default)]
+pub struct WarmJobs {
+    jobs: Arc<Mutex<HashMap<String, WarmStatus>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl WarmJobs {
+    pub fn status(&self, job_id: &str) -> Option<WarmStatus> {
+        self.jobs.lock().unwrap().get(job_id).cloned()
+    }
+
+    fn update(&self, job_id: &str, f: impl FnOnce(&mut WarmStatus)) {
+        if let Some(status) = self.jobs.lock().unwrap().get_mut(job_id) {
+            f(status);
+        }
+    }
+
+    // 列出前缀下的对象并在后台按给定并发处理、写入缓存，立即返回任务 id
+    pub fn start(&self, processor: ImageProcessor, config: &ImageProcessingConfig, request: WarmRequest) -> String {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let job_id = format!("{}-{}", secs, self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= MAX_TRACKED_JOBS {
+            jobs.retain(|_, s| matches!(s.state, WarmState::Listing | WarmState::Running));
+        }
+        jobs.insert(
+            job_id.clone(),
+            WarmStatus {
+                job_id: job_id.clone(),
+                prefix: request.prefix.clone(),
+                state: WarmState::Listing,
+                total: 0,
+                processed: 0,
+                failed: 0,
+                error: None,
+            },
+        );
+        drop(jobs);
+
+        let jobs = self.clone();
+        let id = job_id.clone();
+        let params = parse_query_params_for_key(&request.prefix, request.params, config);
+        let concurrency = request.concurrency.clamp(1, MAX_WARM_CONCURRENCY);
+        let limit = request.limit.clamp(1, MAX_WARM_OBJECTS);
+        tokio::spawn(async move {
+            let keys = match processor.list_originals(&request.prefix, limit).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    eprintln!("Warm job {} failed to list '{}': {}", id, redact_key(&request.prefix), e);
+                    jobs.update(&id, |s| {
+                        s.state = WarmState::Failed;
+                        s.error = Some(e.to_string());
+                    });
+                    return;
+                }
+            };
+            println!("Warm job {}: {} objects under '{}'", id, keys.len(), redact_key(&request.prefix));
+            jobs.update(&id, |s| {
+                s.state = WarmState::Running;
+                s.total = keys.len();
+            });
+
+            futures::stream::iter(keys)
+                .for_each_concurrent(concurrency, |key| {
+                    let processor = processor.clone();
+                    let params = params.clone();
+                    let jobs = jobs.clone();
+                    let id = id.clone();
+                    async move {
+                        let result = processor.get_or_process_image(key.clone(), params).await;
+                        if let Err(ref e) = result {
+                            eprintln!("Warm job {}: failed to process '{}': {}", id, redact_key(&key), e);
+                        }
+                        jobs.update(&id, |s| {
+                            s.processed += 1;
+                            if result.is_err() {
+                                s.failed += 1;
+                            }
+                        });
+                    }
+                })
+                .await;
+
+            jobs.update(&id, |s| s.state = WarmState::Done);
+            println!("Warm job {} finished", id);
+        });
+
+        job_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::ImageCache, mock_s3::MockS3, s3_client::S3Client};
+    use opencv::{
+        core::{Mat, Scalar, Vector, CV_8UC3},
+        imgcodecs::imencode,
+        prelude::*,
+    };
+    use std::time::Duration;
+
+    fn jpeg(width: i32, height: i32) -> Vec<u8> {
+        let img = Mat::new_rows_cols_with_default(height, width, CV_8UC3, Scalar::all(128.0)).unwrap();
+        let mut buf = Vector::new();
+        assert!(imencode(".jpg", &img, &mut buf, &Vector::new()).unwrap());
+        buf.to_vec()
+    }
+
+    #[tokio::test]
+    async fn warming_a_prefix_caches_every_object_under_it() {
+        let s3 = MockS3::start().await;
+        s3.put("gallery/2024/a.jpg", jpeg(200, 100));
+        s3.put("gallery/2024/b.jpg", jpeg(120, 160));
+        s3.put("gallery/2023/c.jpg", jpeg(80, 80));
+        let config: ImageProcessingConfig =
+            serde_json::from_value(serde_json::json!({ "default_quality": 80, "max_width": 1920, "max_height": 1080 })).unwrap();
+        let cache = ImageCache::new(
+            serde_json::from_value(serde_json::json!({ "max_capacity_mb": 16, "time_to_live_sec": 60, "time_to_idle_sec": 60 })).unwrap(),
+        );
+        let processor = ImageProcessor::new(S3Client::new(s3.config()).await.unwrap(), cache, config.clone()).unwrap();
+
+        let jobs = WarmJobs::default();
+        let params = HashMap::from([("width".to_string(), "50".to_string())]);
+        let request = WarmRequest { prefix: "gallery/2024/".to_string(), params: params.clone(), concurrency: 2, limit: 100 };
+        let job_id = jobs.start(processor.clone(), &config, request);
+
+        let mut status = jobs.status(&job_id).unwrap();
+        for _ in 0..500 {
+            if matches!(status.state, WarmState::Done | WarmState::Failed) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            status = jobs.status(&job_id).unwrap();
+        }
+        assert!(matches!(status.state, WarmState::Done), "{:?}", status);
+        assert_eq!((status.total, status.processed, status.failed), (2, 2, 0));
+
+        // 与请求相同的缓存键下已有派生图；前缀之外的对象没有被处理
+        for (key, cached) in [("gallery/2024/a.jpg", true), ("gallery/2024/b.jpg", true), ("gallery/2023/c.jpg", false)] {
+            let params = parse_query_params_for_key(key, params.clone(), &config);
+            assert_eq!(processor.evict_derivative(key, params).await.1, cached, "{}", key);
+        }
+    }
+}